@@ -0,0 +1,305 @@
+use crate::{HitRecord, Ray, Vec3};
+
+// Relative cost of visiting an interior node compared to testing one object,
+// used by the surface area heuristic when deciding whether to split a node
+const TRAVERSAL_COST: f32 = 0.125;
+const SAH_BINS: usize = 16;
+const MAX_LEAF_SIZE: usize = 4;
+// The traversal stack is a fixed array, so the tree depth is capped to fit in it
+const STACK_SIZE: usize = 64;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn surrounding_box(box0: Aabb, box1: Aabb) -> Aabb {
+        Aabb {
+            min: Vec3 {
+                x: box0.min.x.min(box1.min.x),
+                y: box0.min.y.min(box1.min.y),
+                z: box0.min.z.min(box1.min.z),
+            },
+            max: Vec3 {
+                x: box0.max.x.max(box1.max.x),
+                y: box0.max.y.max(box1.max.y),
+                z: box0.max.z.max(box1.max.z),
+            },
+        }
+    }
+
+    // Slab test, taking the inverse of the ray direction so it is computed once per ray
+    pub fn hit(&self, origin: Vec3, inv_direction: Vec3, mut t_min: f32, mut t_max: f32) -> bool {
+        for axis in 0..3 {
+            let inv_d = axis_of(inv_direction, axis);
+            let mut t0 = (axis_of(self.min, axis) - axis_of(origin, axis)) * inv_d;
+            let mut t1 = (axis_of(self.max, axis) - axis_of(origin, axis)) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            if t_max < t_min {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+}
+
+fn axis_of(v: Vec3, axis: usize) -> f32 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+enum BvhNode {
+    Leaf {
+        bbox: Aabb,
+        start: usize,
+        end: usize,
+    },
+    Interior {
+        bbox: Aabb,
+        left: usize,
+        right: usize,
+        axis: usize,
+    },
+}
+
+impl BvhNode {
+    fn bbox(&self) -> &Aabb {
+        match self {
+            BvhNode::Leaf { bbox, .. } => bbox,
+            BvhNode::Interior { bbox, .. } => bbox,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    bbox: Option<Aabb>,
+    count: usize,
+}
+
+fn grow(bbox: Option<Aabb>, other: Aabb) -> Aabb {
+    match bbox {
+        Some(bbox) => Aabb::surrounding_box(bbox, other),
+        None => other,
+    }
+}
+
+// Bounding volume hierarchy over a list of objects, stored as a flat array of nodes.
+// Leaves reference a range of `indices`, which point back into the object list
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new(boxes: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * boxes.len()),
+            indices: (0..boxes.len()).collect(),
+        };
+        if !boxes.is_empty() {
+            let centroids: Vec<Vec3> = boxes.iter().map(|b| b.centroid()).collect();
+            bvh.build(boxes, &centroids, 0, boxes.len(), 0);
+        }
+        bvh
+    }
+
+    fn build(
+        &mut self,
+        boxes: &[Aabb],
+        centroids: &[Vec3],
+        start: usize,
+        end: usize,
+        depth: usize,
+    ) -> usize {
+        let node_index = self.nodes.len();
+        let slice = &self.indices[start..end];
+        let bbox = slice[1..].iter().fold(boxes[slice[0]], |acc, &i| {
+            Aabb::surrounding_box(acc, boxes[i])
+        });
+        let centroid_bounds = slice[1..].iter().fold(
+            Aabb {
+                min: centroids[slice[0]],
+                max: centroids[slice[0]],
+            },
+            |acc, &i| {
+                Aabb::surrounding_box(
+                    acc,
+                    Aabb {
+                        min: centroids[i],
+                        max: centroids[i],
+                    },
+                )
+            },
+        );
+
+        let count = end - start;
+        self.nodes.push(BvhNode::Leaf { bbox, start, end });
+        if count == 1 || depth + 1 >= STACK_SIZE {
+            return node_index;
+        }
+
+        // Binned surface area heuristic : for each axis, objects are sorted into bins by
+        // centroid and every boundary between two bins is evaluated as a split candidate
+        let mut best: Option<(f32, usize, usize)> = None;
+        for axis in 0..3 {
+            let axis_min = axis_of(centroid_bounds.min, axis);
+            let extent = axis_of(centroid_bounds.max, axis) - axis_min;
+            if extent <= 0.0 {
+                continue;
+            }
+
+            let mut bins = [Bin {
+                bbox: None,
+                count: 0,
+            }; SAH_BINS];
+            for &i in &self.indices[start..end] {
+                let b = bin_index(axis_of(centroids[i], axis), axis_min, extent);
+                bins[b].bbox = Some(grow(bins[b].bbox, boxes[i]));
+                bins[b].count += 1;
+            }
+
+            let mut right_area = [0.0; SAH_BINS];
+            let mut right_count = [0; SAH_BINS];
+            let mut right_box: Option<Aabb> = None;
+            let mut n = 0;
+            for b in (1..SAH_BINS).rev() {
+                if let Some(bin_box) = bins[b].bbox {
+                    right_box = Some(grow(right_box, bin_box));
+                }
+                n += bins[b].count;
+                right_count[b] = n;
+                right_area[b] = right_box.map_or(0.0, |bbox| bbox.surface_area());
+            }
+
+            let mut left_box: Option<Aabb> = None;
+            let mut left_count = 0;
+            for b in 0..SAH_BINS - 1 {
+                if let Some(bin_box) = bins[b].bbox {
+                    left_box = Some(grow(left_box, bin_box));
+                }
+                left_count += bins[b].count;
+                if left_count == 0 || right_count[b + 1] == 0 {
+                    continue;
+                }
+                let left_area = left_box.map_or(0.0, |bbox| bbox.surface_area());
+                let cost = TRAVERSAL_COST
+                    + (left_area * left_count as f32
+                        + right_area[b + 1] * right_count[b + 1] as f32)
+                        / bbox.surface_area();
+                if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, axis, b));
+                }
+            }
+        }
+
+        let (cost, axis, split_bin) = match best {
+            Some(best) => best,
+            // Every centroid is at the same point, there is nothing to split on
+            None => return node_index,
+        };
+        if cost >= count as f32 && count <= MAX_LEAF_SIZE {
+            return node_index;
+        }
+
+        let axis_min = axis_of(centroid_bounds.min, axis);
+        let extent = axis_of(centroid_bounds.max, axis) - axis_min;
+        let mut mid = start;
+        for i in start..end {
+            let object = self.indices[i];
+            if bin_index(axis_of(centroids[object], axis), axis_min, extent) <= split_bin {
+                self.indices.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        let left = self.build(boxes, centroids, start, mid, depth + 1);
+        let right = self.build(boxes, centroids, mid, end, depth + 1);
+        self.nodes[node_index] = BvhNode::Interior {
+            bbox,
+            left,
+            right,
+            axis,
+        };
+        node_index
+    }
+
+    // Returns the closest hit along the ray, `hit_object` being called with the index of each
+    // object whose leaf is reached, and the current [t_min, closest hit] interval
+    pub fn hit<F>(&self, r: &Ray, t_min: f32, t_max: f32, hit_object: F) -> Option<HitRecord>
+    where
+        F: Fn(usize, f32, f32) -> Option<HitRecord>,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inv_direction = Vec3 {
+            x: 1.0 / r.direction.x,
+            y: 1.0 / r.direction.y,
+            z: 1.0 / r.direction.z,
+        };
+
+        let mut closest_so_far = t_max;
+        let mut return_record = None;
+        let mut stack = [0; STACK_SIZE];
+        let mut stack_size = 1;
+        while stack_size > 0 {
+            stack_size -= 1;
+            let node = &self.nodes[stack[stack_size]];
+            if !node
+                .bbox()
+                .hit(r.origin, inv_direction, t_min, closest_so_far)
+            {
+                continue;
+            }
+            match node {
+                BvhNode::Leaf { start, end, .. } => {
+                    for &object in &self.indices[*start..*end] {
+                        if let Some(hitten) = hit_object(object, t_min, closest_so_far) {
+                            closest_so_far = hitten.t;
+                            return_record = Some(hitten);
+                        }
+                    }
+                }
+                BvhNode::Interior {
+                    left, right, axis, ..
+                } => {
+                    // Push the far child first so the near one is visited first and
+                    // shrinks closest_so_far early
+                    let (near, far) = if axis_of(inv_direction, *axis) < 0.0 {
+                        (*right, *left)
+                    } else {
+                        (*left, *right)
+                    };
+                    stack[stack_size] = far;
+                    stack[stack_size + 1] = near;
+                    stack_size += 2;
+                }
+            }
+        }
+        return_record
+    }
+}
+
+fn bin_index(centroid: f32, axis_min: f32, extent: f32) -> usize {
+    let b = ((centroid - axis_min) / extent * SAH_BINS as f32) as usize;
+    b.min(SAH_BINS - 1)
+}
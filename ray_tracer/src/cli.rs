@@ -0,0 +1,140 @@
+use std::path::PathBuf;
+
+use crate::image::ImageFormat;
+
+pub const USAGE: &str = "Usage: ray_tracer [OPTIONS]
+
+Renders a scene and writes the image to the standard output or to a file.
+
+Options:
+  -s, --scene <SCENE>          Scene to render : \"random\" or \"three_spheres\" [default: random]
+  -w, --width <PIXELS>         Image width [default: 1200]
+  -a, --aspect-ratio <RATIO>   Image width / height, as a number or as W:H [default: 3:2]
+  -n, --samples <N>            Samples per pixel [default: 500]
+  -d, --max-depth <N>          Maximum number of ray bounces [default: 50]
+  -j, --threads <N>            Number of render threads [default: number of CPUs]
+      --seed <N>               Seed of the random scene layout and of the samples
+                               [default: random]
+  -o, --output <FILE>          Output file [default: standard output]
+  -f, --format <FORMAT>        Output format : p3 (ASCII PPM), p6 (binary PPM), png, or the linear
+                               hdr (Radiance), pfm, exr (ZIP compressed) and exr-none (uncompressed)
+                               [default: from the output extension (.ppm, .png, .hdr, .pfm, .exr),
+                               p3 on the standard output]
+  -h, --help                   Print this help";
+
+pub struct Options {
+    pub help: bool,
+    pub scene: String,
+    pub image_width: Option<i32>,
+    pub aspect_ratio: Option<f32>,
+    pub sample_per_pixel: Option<i32>,
+    pub max_depth: Option<i32>,
+    pub threads: Option<usize>,
+    pub seed: Option<u64>,
+    pub output: Option<PathBuf>,
+    pub format: Option<ImageFormat>,
+}
+
+impl Options {
+    // An explicit --format wins, otherwise the format follows the extension of the output file
+    pub fn image_format(&self) -> Result<ImageFormat, String> {
+        match (self.format, &self.output) {
+            (Some(format), _) => Ok(format),
+            (None, Some(path)) => ImageFormat::from_extension(path).ok_or_else(|| {
+                format!(
+                    "cannot guess the image format of {}, use --format",
+                    path.display()
+                )
+            }),
+            (None, None) => Ok(ImageFormat::PpmAscii),
+        }
+    }
+}
+
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        help: false,
+        scene: "random".to_string(),
+        image_width: None,
+        aspect_ratio: None,
+        sample_per_pixel: None,
+        max_depth: None,
+        threads: None,
+        seed: None,
+        output: None,
+        format: None,
+    };
+
+    while let Some(arg) = args.next() {
+        // Both `--option value` and `--option=value` are accepted
+        let (name, mut inline_value) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => {
+                (arg[..i].to_string(), Some(arg[i + 1..].to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline_value
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+        match name.as_str() {
+            "-h" | "--help" => options.help = true,
+            "-s" | "--scene" => options.scene = value()?,
+            "-w" | "--width" => options.image_width = Some(parse_positive(&name, &value()?)?),
+            "-a" | "--aspect-ratio" => options.aspect_ratio = Some(parse_aspect_ratio(&value()?)?),
+            "-n" | "--samples" => {
+                options.sample_per_pixel = Some(parse_positive(&name, &value()?)?)
+            }
+            "-d" | "--max-depth" => options.max_depth = Some(parse_positive(&name, &value()?)?),
+            "-j" | "--threads" => {
+                options.threads = Some(parse_positive(&name, &value()?)? as usize)
+            }
+            "--seed" => {
+                let seed = value()?;
+                options.seed = Some(
+                    seed.parse()
+                        .map_err(|_| format!("invalid value {:?} for {}", seed, name))?,
+                )
+            }
+            "-o" | "--output" => options.output = Some(PathBuf::from(value()?)),
+            "-f" | "--format" => {
+                let format = value()?;
+                options.format = Some(
+                    ImageFormat::from_name(&format)
+                        .ok_or_else(|| format!("unknown output format {:?}", format))?,
+                )
+            }
+            _ => return Err(format!("unknown option {}", name)),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_positive(name: &str, value: &str) -> Result<i32, String> {
+    match value.parse::<i32>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!(
+            "invalid value {:?} for {}, expected a positive integer",
+            value, name
+        )),
+    }
+}
+
+fn parse_aspect_ratio(value: &str) -> Result<f32, String> {
+    let error = || format!("invalid aspect ratio {:?}, expected a number or W:H", value);
+    let ratio = match value.find(':') {
+        Some(i) => {
+            let width: f32 = value[..i].parse().map_err(|_| error())?;
+            let height: f32 = value[i + 1..].parse().map_err(|_| error())?;
+            width / height
+        }
+        None => value.parse().map_err(|_| error())?,
+    };
+    if ratio.is_finite() && ratio > 0.0 {
+        Ok(ratio)
+    } else {
+        Err(error())
+    }
+}
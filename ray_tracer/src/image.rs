@@ -0,0 +1,412 @@
+use std::io::{self, Write};
+use std::path::Path;
+
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+
+#[derive(Debug, Copy, Clone)]
+pub enum ImageFormat {
+    // Plain text P3 PPM, one pixel per line
+    PpmAscii,
+    // Binary P6 PPM
+    PpmBinary,
+    Png,
+    // Linear floating point formats, written without tone mapping
+    RadianceHdr,
+    Pfm,
+    ExrUncompressed,
+    ExrZip,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "p3" => Some(ImageFormat::PpmAscii),
+            "p6" | "ppm" => Some(ImageFormat::PpmBinary),
+            "png" => Some(ImageFormat::Png),
+            "hdr" => Some(ImageFormat::RadianceHdr),
+            "pfm" => Some(ImageFormat::Pfm),
+            "exr" | "exr-zip" => Some(ImageFormat::ExrZip),
+            "exr-none" => Some(ImageFormat::ExrUncompressed),
+            _ => None,
+        }
+    }
+
+    pub fn from_extension(path: &Path) -> Option<ImageFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "ppm" => Some(ImageFormat::PpmBinary),
+            "png" => Some(ImageFormat::Png),
+            "hdr" => Some(ImageFormat::RadianceHdr),
+            "pfm" => Some(ImageFormat::Pfm),
+            "exr" => Some(ImageFormat::ExrZip),
+            _ => None,
+        }
+    }
+
+    pub fn is_high_dynamic_range(self) -> bool {
+        match self {
+            ImageFormat::PpmAscii | ImageFormat::PpmBinary | ImageFormat::Png => false,
+            ImageFormat::RadianceHdr
+            | ImageFormat::Pfm
+            | ImageFormat::ExrUncompressed
+            | ImageFormat::ExrZip => true,
+        }
+    }
+}
+
+// Writes an 8 bits per channel RGB image, `rgb` holding the rows from top to bottom
+pub fn write(
+    output: &mut dyn Write,
+    format: ImageFormat,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+) -> io::Result<()> {
+    match format {
+        ImageFormat::PpmAscii => {
+            writeln!(output, "P3")?;
+            writeln!(output, "{:?} {:?}", width, height)?;
+            writeln!(output, "{:?}", 255)?;
+            for pixel in rgb.chunks(3) {
+                writeln!(output, "{:?} {:?} {:?}", pixel[0], pixel[1], pixel[2])?;
+            }
+        }
+        ImageFormat::PpmBinary => {
+            write!(output, "P6\n{} {}\n255\n", width, height)?;
+            output.write_all(rgb)?;
+        }
+        ImageFormat::Png => write_png(output, width, height, rgb)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not an 8 bits format", format),
+            ))
+        }
+    }
+    output.flush()
+}
+
+// Writes a linear floating point RGB image, `rgb` holding the rows from top to bottom
+pub fn write_hdr(
+    output: &mut dyn Write,
+    format: ImageFormat,
+    width: usize,
+    height: usize,
+    rgb: &[f32],
+) -> io::Result<()> {
+    match format {
+        ImageFormat::RadianceHdr => write_radiance(output, width, height, rgb)?,
+        ImageFormat::Pfm => {
+            // Negative scale for little endian data, rows are stored from bottom to top
+            write!(output, "PF\n{} {}\n-1.0\n", width, height)?;
+            for row in rgb.chunks(width * 3).rev() {
+                for value in row {
+                    output.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        ImageFormat::ExrUncompressed => write_exr(output, width, height, rgb, false)?,
+        ImageFormat::ExrZip => write_exr(output, width, height, rgb, true)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a floating point format", format),
+            ))
+        }
+    }
+    output.flush()
+}
+
+fn write_png(output: &mut dyn Write, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    output.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, truecolour, deflate compression, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(output, b"IHDR", &header)?;
+
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    let stride = width * 3;
+    let mut previous = vec![0; stride];
+    let mut filtered = vec![0; stride];
+    for row in rgb.chunks(stride.max(1)).take(height) {
+        let filter = filter_row(row, &previous, &mut filtered);
+        encoder.write_all(&[filter])?;
+        encoder.write_all(&filtered)?;
+        previous.copy_from_slice(row);
+    }
+    write_chunk(output, b"IDAT", &encoder.finish()?)?;
+
+    write_chunk(output, b"IEND", &[])
+}
+
+fn write_chunk(output: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    output.write_all(&(data.len() as u32).to_be_bytes())?;
+    output.write_all(kind)?;
+    output.write_all(data)?;
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    output.write_all(&crc.sum().to_be_bytes())
+}
+
+// Applies every PNG filter to the row and keeps the one with the smallest sum of
+// absolute values, the usual heuristic to get the most compressible output
+fn filter_row(row: &[u8], previous: &[u8], filtered: &mut [u8]) -> u8 {
+    let mut best_filter = 0;
+    let mut best_sum = u64::MAX;
+    let mut candidate = vec![0; row.len()];
+    for filter in 0..5 {
+        for i in 0..row.len() {
+            let left = if i >= 3 { row[i - 3] } else { 0 };
+            let up = previous[i];
+            let up_left = if i >= 3 { previous[i - 3] } else { 0 };
+            let predicted = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                _ => paeth(left, up, up_left),
+            };
+            candidate[i] = row[i].wrapping_sub(predicted);
+        }
+        let sum: u64 = candidate
+            .iter()
+            .map(|&b| (b as i8).unsigned_abs() as u64)
+            .sum();
+        if sum < best_sum {
+            best_sum = sum;
+            best_filter = filter;
+            filtered.copy_from_slice(&candidate);
+        }
+    }
+    best_filter
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn write_radiance(
+    output: &mut dyn Write,
+    width: usize,
+    height: usize,
+    rgb: &[f32],
+) -> io::Result<()> {
+    write!(
+        output,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+    for row in rgb.chunks(width * 3).take(height) {
+        let pixels: Vec<[u8; 4]> = row.chunks(3).map(rgbe).collect();
+        // Run length encoding is only defined for these widths, other
+        // images are written as flat RGBE values
+        if !(8..0x8000).contains(&width) {
+            for pixel in pixels {
+                output.write_all(&pixel)?;
+            }
+            continue;
+        }
+        let mut line = vec![2, 2, (width >> 8) as u8, (width & 0xff) as u8];
+        for component in 0..4 {
+            let values: Vec<u8> = pixels.iter().map(|pixel| pixel[component]).collect();
+            run_length_encode(&values, &mut line);
+        }
+        output.write_all(&line)?;
+    }
+    Ok(())
+}
+
+// Shared exponent encoding of a colour, the mantissas being scaled by the largest component
+fn rgbe(color: &[f32]) -> [u8; 4] {
+    let max = color[0].max(color[1]).max(color[2]);
+    if !max.is_finite() || max < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // Up to 2^127, the largest f32 values filling the mantissas
+    let exponent = (max.log2().floor() as i32 + 1).min(127);
+    let scale = 256.0 / 2f32.powi(exponent);
+    let mantissa = |c: f32| (c.max(0.0) * scale).min(255.0) as u8;
+    [
+        mantissa(color[0]),
+        mantissa(color[1]),
+        mantissa(color[2]),
+        (exponent + 128) as u8,
+    ]
+}
+
+// Encodes one component of a scanline as runs of identical bytes (count + 128, value)
+// and literal sequences (count, values...)
+fn run_length_encode(values: &[u8], line: &mut Vec<u8>) {
+    const MIN_RUN: usize = 4;
+    let mut i = 0;
+    while i < values.len() {
+        let mut run_start = i;
+        let mut run_length = 0;
+        while run_start < values.len() {
+            run_length = 1;
+            while run_start + run_length < values.len()
+                && run_length < 127
+                && values[run_start + run_length] == values[run_start]
+            {
+                run_length += 1;
+            }
+            if run_length >= MIN_RUN {
+                break;
+            }
+            run_start += run_length;
+        }
+        while i < run_start {
+            let count = (run_start - i).min(128);
+            line.push(count as u8);
+            line.extend_from_slice(&values[i..i + count]);
+            i += count;
+        }
+        if run_length >= MIN_RUN {
+            line.push(128 + run_length as u8);
+            line.push(values[run_start]);
+            i = run_start + run_length;
+        }
+    }
+}
+
+// Scanline OpenEXR file with 32 bits float B, G and R channels. ZIP compression
+// works on blocks of 16 scanlines, uncompressed files store one scanline per block
+fn write_exr(
+    output: &mut dyn Write,
+    width: usize,
+    height: usize,
+    rgb: &[f32],
+    zip: bool,
+) -> io::Result<()> {
+    let lines_per_block = if zip { 16 } else { 1 };
+
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    let mut channels = vec![];
+    // Channels are stored in alphabetical order
+    for name in &[b"B", b"G", b"R"] {
+        channels.extend_from_slice(*name);
+        channels.push(0);
+        // FLOAT pixel type, not perceptually linear, x and y sampling of 1
+        channels.extend_from_slice(&2i32.to_le_bytes());
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    let mut window = vec![];
+    for value in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+    exr_attribute(&mut header, "channels", "chlist", &channels);
+    exr_attribute(
+        &mut header,
+        "compression",
+        "compression",
+        &[if zip { 3 } else { 0 }],
+    );
+    exr_attribute(&mut header, "dataWindow", "box2i", &window);
+    exr_attribute(&mut header, "displayWindow", "box2i", &window);
+    exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    exr_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    let mut blocks = vec![];
+    for first_line in (0..height).step_by(lines_per_block) {
+        let mut data = vec![];
+        for y in first_line..(first_line + lines_per_block).min(height) {
+            let row = &rgb[y * width * 3..(y + 1) * width * 3];
+            for channel in &[2, 1, 0] {
+                for pixel in row.chunks(3) {
+                    data.extend_from_slice(&pixel[*channel].to_le_bytes());
+                }
+            }
+        }
+        if zip {
+            let compressed = exr_zip(&data)?;
+            // Blocks which do not shrink are stored uncompressed
+            if compressed.len() < data.len() {
+                data = compressed;
+            }
+        }
+        blocks.push((first_line, data));
+    }
+
+    // The offset table gives the position of each block from the start of the file
+    let mut offset = header.len() + blocks.len() * 8;
+    for (_, data) in &blocks {
+        header.extend_from_slice(&(offset as u64).to_le_bytes());
+        offset += 8 + data.len();
+    }
+    output.write_all(&header)?;
+    for (first_line, data) in blocks {
+        output.write_all(&(first_line as i32).to_le_bytes())?;
+        output.write_all(&(data.len() as i32).to_le_bytes())?;
+        output.write_all(&data)?;
+    }
+    Ok(())
+}
+
+fn exr_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+// Bytes are split between even and odd positions, then delta encoded, before zlib compression
+fn exr_zip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reordered: Vec<u8> = data.iter().step_by(2).copied().collect();
+    reordered.extend(data.iter().skip(1).step_by(2));
+    let mut previous = reordered.first().copied().unwrap_or(0);
+    for byte in reordered.iter_mut().skip(1) {
+        let current = *byte;
+        *byte = current.wrapping_sub(previous).wrapping_add(128);
+        previous = current;
+    }
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(&reordered)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgbe_scales_the_mantissas_by_the_largest_component() {
+        assert_eq!(rgbe(&[1.0, 0.5, 0.25]), [128, 64, 32, 129]);
+        assert_eq!(rgbe(&[0.0, 0.0, 0.0]), [0, 0, 0, 0]);
+        assert_eq!(rgbe(&[f32::MAX, 1.0, 0.0]), [255, 0, 0, 255]);
+        assert_eq!(rgbe(&[f32::INFINITY, 1.0, 0.0]), [0, 0, 0, 0]);
+        assert_eq!(rgbe(&[f32::NAN, f32::NAN, f32::NAN]), [0, 0, 0, 0]);
+    }
+}
@@ -1,7 +1,15 @@
-use rand::Rng;
+use rand::{Rng, SeedableRng, StdRng};
 use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
 use std::time::SystemTime;
 
+mod bvh;
+mod cli;
+mod image;
+use bvh::{Aabb, Bvh};
+use image::ImageFormat;
+
 // #[derive(Debug, Copy, Clone)]
 #[derive(Debug, Copy, Clone)]
 struct Vec3 {
@@ -55,45 +63,37 @@ impl Vec3 {
         }
     }
 
-    fn write_color(mut self, sample_per_pixel: i32) {
-        self /= sample_per_pixel as f32;
-        let ir = (256.0 * clamp(self.x.sqrt(), 0.0, 0.999)) as i32;
-        let ig = (256.0 * clamp(self.y.sqrt(), 0.0, 0.999)) as i32;
-        let ib = (256.0 * clamp(self.z.sqrt(), 0.0, 0.999)) as i32;
-        println!("{:?} {:?} {:?}", ir, ig, ib);
-    }
-
-    fn random(min: f32, max: f32) -> Vec3 {
+    fn random<R: Rng>(rng: &mut R, min: f32, max: f32) -> Vec3 {
         Vec3 {
-            x: min + (max - min) * rand::random::<f32>(),
-            y: min + (max - min) * rand::random::<f32>(),
-            z: min + (max - min) * rand::random::<f32>(),
+            x: min + (max - min) * rng.gen::<f32>(),
+            y: min + (max - min) * rng.gen::<f32>(),
+            z: min + (max - min) * rng.gen::<f32>(),
         }
     }
 
-    fn random_in_unit_sphere() -> Vec3 {
+    fn random_in_unit_sphere<R: Rng>(rng: &mut R) -> Vec3 {
         loop {
-            let p = Vec3::random(-1.0, 1.0);
+            let p = Vec3::random(rng, -1.0, 1.0);
             if p.length() < 1.0 {
                 return p;
             }
         }
     }
-    fn random_unit_vector() -> Vec3 {
-        let a: f32 = 2.0 * std::f32::consts::PI * rand::random::<f32>();
-        let z: f32 = -1.0 + 2.0 * rand::random::<f32>();
+    fn random_unit_vector<R: Rng>(rng: &mut R) -> Vec3 {
+        let a: f32 = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+        let z: f32 = -1.0 + 2.0 * rng.gen::<f32>();
         let r: f32 = (1.0 - z * z).sqrt();
         Vec3 {
             x: r * a.cos(),
             y: r * a.sin(),
-            z: z,
+            z,
         }
     }
-    fn random_in_unit_disk() -> Vec3 {
+    fn random_in_unit_disk<R: Rng>(rng: &mut R) -> Vec3 {
         loop {
             let p = Vec3 {
-                x: -1.0 + 2.0 * rand::random::<f32>(),
-                y: -1.0 + 2.0 * rand::random::<f32>(),
+                x: -1.0 + 2.0 * rng.gen::<f32>(),
+                y: -1.0 + 2.0 * rng.gen::<f32>(),
                 z: 0.0,
             };
             if p.length() < 1.0 {
@@ -196,7 +196,7 @@ impl std::ops::Neg for Vec3 {
 }
 
 // todo : implement sur ray ?
-fn ray_color(r: &Ray, world: &World, depth: i32) -> Vec3 {
+fn ray_color<R: Rng>(r: &Ray, world: &World, depth: i32, rng: &mut R) -> Vec3 {
     // param : nb_ray: &mut i32
     // *nb_ray += 1;
     if depth <= 0 {
@@ -208,8 +208,8 @@ fn ray_color(r: &Ray, world: &World, depth: i32) -> Vec3 {
     }
 
     if let Some(ray_hitten) = world.hit(r, 0.001, 1000000.0) {
-        if let Some((scattered, attenuation)) = ray_hitten.material.scatter(r, &ray_hitten) {
-            return ray_color(&scattered, world, depth - 1) * attenuation;
+        if let Some((scattered, attenuation)) = ray_hitten.material.scatter(r, &ray_hitten, rng) {
+            return ray_color(&scattered, world, depth - 1, rng) * attenuation;
         }
         return Vec3 {
             x: 0.0,
@@ -259,21 +259,22 @@ struct HitRecord {
 
 struct World {
     objects: Vec<Sphere>,
+    bvh: Bvh,
 }
 
 impl World {
-    // todo add fn
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let mut closest_so_far = t_max;
-        let mut return_record = None;
-
-        for object in &self.objects {
-            if let Some(hitten) = object.hit(&r, t_min, closest_so_far) {
-                closest_so_far = hitten.t;
-                return_record = Some(hitten);
-            }
+    fn new(objects: Vec<Sphere>) -> World {
+        let boxes: Vec<Aabb> = objects.iter().map(|object| object.bounding_box()).collect();
+        World {
+            bvh: Bvh::new(&boxes),
+            objects,
         }
-        return return_record;
+    }
+
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        self.bvh.hit(r, t_min, t_max, |i, t_min, closest_so_far| {
+            self.objects[i].hit(r, t_min, closest_so_far)
+        })
     }
 }
 
@@ -295,9 +296,9 @@ impl Sphere {
                 let front_face = r.direction.dot(normal) < 0.0;
                 return Some(HitRecord {
                     t: temp,
-                    p: p,
+                    p,
                     normal: if front_face { normal } else { -normal },
-                    front_face: front_face,
+                    front_face,
                     material: self.material,
                 });
             }
@@ -308,15 +309,27 @@ impl Sphere {
                 let front_face = r.direction.dot(normal) < 0.0;
                 return Some(HitRecord {
                     t: temp,
-                    p: p,
+                    p,
                     normal: if front_face { normal } else { -normal },
-                    front_face: front_face,
+                    front_face,
                     material: self.material,
                 });
             }
         }
         None
     }
+
+    fn bounding_box(&self) -> Aabb {
+        let r = Vec3 {
+            x: self.radius,
+            y: self.radius,
+            z: self.radius,
+        };
+        Aabb {
+            min: self.center - r,
+            max: self.center + r,
+        }
+    }
 }
 
 struct Camera {
@@ -354,18 +367,18 @@ impl Camera {
         let lower_left_corner = origin - horizontal / 2.0 - vertical / 2.0 - w * focus_dist;
         let lens_radius = aperture / 2.0;
         Camera {
-            origin: origin,
-            horizontal: horizontal,
-            vertical: vertical,
-            lower_left_corner: lower_left_corner,
-            u: u,
-            v: v,
-            lens_radius: lens_radius,
+            origin,
+            horizontal,
+            vertical,
+            lower_left_corner,
+            u,
+            v,
+            lens_radius,
         }
     }
 
-    fn get_ray(&self, u: f32, v: f32) -> Ray {
-        let rd = Vec3::random_in_unit_disk() * self.lens_radius;
+    fn get_ray<R: Rng>(&self, u: f32, v: f32, rng: &mut R) -> Ray {
+        let rd = Vec3::random_in_unit_disk(rng) * self.lens_radius;
         let offset = self.u * rd.x + self.v * rd.y;
         Ray {
             origin: self.origin + offset,
@@ -384,10 +397,10 @@ enum Material {
 }
 
 impl Material {
-    fn scatter(&self, r: &Ray, rec: &HitRecord) -> Option<(Ray, Vec3)> {
+    fn scatter<R: Rng>(&self, r: &Ray, rec: &HitRecord, rng: &mut R) -> Option<(Ray, Vec3)> {
         match self {
             Material::Lambertian { albedo } => {
-                let scatter_direction = rec.normal + Vec3::random_unit_vector();
+                let scatter_direction = rec.normal + Vec3::random_unit_vector(rng);
                 let scattered = Ray {
                     origin: rec.p,
                     direction: scatter_direction,
@@ -398,7 +411,7 @@ impl Material {
                 let reflected = Vec3::reflect(r.direction.unit_vector(), rec.normal);
                 let scattered = Ray {
                     origin: rec.p,
-                    direction: reflected + Vec3::random_in_unit_sphere() * *fuzz,
+                    direction: reflected + Vec3::random_in_unit_sphere(rng) * *fuzz,
                 };
                 if scattered.direction.dot(rec.normal) > 0.0 {
                     Some((scattered, *albedo))
@@ -434,7 +447,7 @@ impl Material {
                 }
 
                 let reflect_prob = schlick(cos_theta, etai_over_etat);
-                if rand::random::<f32>() < reflect_prob {
+                if rng.gen::<f32>() < reflect_prob {
                     let reflected = Vec3::reflect(unit_direction, rec.normal);
                     let scattered = Ray {
                         origin: rec.p,
@@ -468,7 +481,9 @@ impl Material {
     }
 }
 
-fn random_scene() -> World {
+// The layout of the small spheres only depends on the seed
+fn random_spheres(seed: u64) -> Vec<Sphere> {
+    let mut rng = StdRng::from_seed(&[seed as usize][..]);
     let mut spheres = vec![];
     spheres.push(Sphere {
         center: Vec3 {
@@ -488,11 +503,11 @@ fn random_scene() -> World {
 
     for a in -11..11 {
         for b in -11..11 {
-            let choose_mat = rand::random::<f32>();
+            let choose_mat = rng.gen::<f32>();
             let center = Vec3 {
-                x: a as f32 + 0.9 * rand::random::<f32>(),
+                x: a as f32 + 0.9 * rng.gen::<f32>(),
                 y: 0.2,
-                z: b as f32 + 0.9 * rand::random::<f32>(),
+                z: b as f32 + 0.9 * rng.gen::<f32>(),
             };
 
             if (center
@@ -505,26 +520,24 @@ fn random_scene() -> World {
                 > 0.9
             {
                 if choose_mat < 0.8 {
-                    let albedo = Vec3::random(0.0, 1.0) * Vec3::random(0.0, 1.0);
+                    let albedo =
+                        Vec3::random(&mut rng, 0.0, 1.0) * Vec3::random(&mut rng, 0.0, 1.0);
                     spheres.push(Sphere {
-                        center: center,
+                        center,
                         radius: 0.2,
-                        material: Material::Lambertian { albedo: albedo },
+                        material: Material::Lambertian { albedo },
                     })
                 } else if choose_mat < 0.95 {
-                    let albedo = Vec3::random(0.0, 0.5);
-                    let fuzz = rand::random::<f32>() * 0.5;
+                    let albedo = Vec3::random(&mut rng, 0.0, 0.5);
+                    let fuzz = rng.gen::<f32>() * 0.5;
                     spheres.push(Sphere {
-                        center: center,
+                        center,
                         radius: 0.2,
-                        material: Material::Metal {
-                            albedo: albedo,
-                            fuzz: fuzz,
-                        },
+                        material: Material::Metal { albedo, fuzz },
                     })
                 } else {
                     spheres.push(Sphere {
-                        center: center,
+                        center,
                         radius: 0.2,
                         material: Material::Dielectric { ref_idx: 1.5 },
                     })
@@ -574,23 +587,75 @@ fn random_scene() -> World {
         },
     });
 
-    World { objects: spheres }
+    spheres
+}
+
+fn random_scene(seed: u64) -> World {
+    World::new(random_spheres(seed))
+}
+
+// The ground and the three big spheres of the random scene, without the small ones
+fn three_spheres_scene() -> World {
+    let spheres = random_spheres(0)
+        .into_iter()
+        .filter(|sphere| sphere.radius >= 1.0)
+        .collect();
+    World::new(spheres)
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
 }
 
 fn main() {
     let now = SystemTime::now();
 
-    // todo : voir const si on met en maj
-    // todo voir trait object pas juste sphere
-    let aspect_ratio: f32 = 3.0 / 2.0;
-    let sample_per_pixel = 500;
-    let image_width: i32 = 1200;
+    let options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n\nFor more information, try --help", e);
+            std::process::exit(2);
+        }
+    };
+    if options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    if let Some(threads) = options.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
+    let format = options.image_format().unwrap_or_else(|e| exit_with_error(&e));
+
+    let aspect_ratio: f32 = options.aspect_ratio.unwrap_or(3.0 / 2.0);
+    let sample_per_pixel = options.sample_per_pixel.unwrap_or(500);
+    let image_width: i32 = options.image_width.unwrap_or(1200);
     let image_height: i32 = (image_width as f32 / aspect_ratio) as i32;
-    let max_depth = 50;
+    let max_depth = options.max_depth.unwrap_or(50);
+
+    let seed = options.seed.unwrap_or_else(rand::random);
+    eprintln!("seed : {}", seed);
+
+    let world = match options.scene.as_str() {
+        "random" => random_scene(seed),
+        "three_spheres" => three_spheres_scene(),
+        scene => exit_with_error(&format!(
+            "unknown scene {:?}, expected \"random\" or \"three_spheres\"",
+            scene
+        )),
+    };
 
-    println!("P3");
-    println!("{:?} {:?}", image_width, image_height);
-    println!("{:?}", 255);
+    // The output is opened before rendering so that a wrong path does not waste a render
+    let mut output: Box<dyn Write> = match &options.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(e) => exit_with_error(&format!("{}: {}", path.display(), e)),
+        },
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
 
     let lookfrom = Vec3 {
         x: 13.0,
@@ -619,8 +684,7 @@ fn main() {
         dist_to_focus,
     );
 
-    let world = random_scene();
-    let mut nb_ray = &mut 0;
+    // let mut nb_ray = &mut 0;
 
     // for height in (0..image_height).rev() {
     //     // eprintln!("{:?}", height);
@@ -636,43 +700,174 @@ fn main() {
     //     }
     // }
 
-    let v: Vec<Vec<Vec3>> = (0..image_height)
+    let v = render(
+        &world,
+        &camera,
+        image_width,
+        image_height,
+        sample_per_pixel,
+        max_depth,
+        seed,
+    );
+    if let Err(e) = write_image(&mut output, v, sample_per_pixel, format) {
+        exit_with_error(&format!("could not write the image : {}", e));
+    }
+
+    eprintln!("{:?}", now.elapsed());
+}
+
+// Sums of the samples of each pixel, from the top row. Each pixel draws its
+// numbers from its own generator, seeded by the seed and the pixel position,
+// so that the image only depends on the seed and not on the threads
+fn render(
+    world: &World,
+    camera: &Camera,
+    image_width: i32,
+    image_height: i32,
+    sample_per_pixel: i32,
+    max_depth: i32,
+    seed: u64,
+) -> Vec<Vec<Vec3>> {
+    (0..image_height)
         .into_par_iter()
         .rev()
         .map(|height| {
             (0..image_width)
                 .map(|width| {
+                    let mut rng =
+                        StdRng::from_seed(&[seed as usize, width as usize, height as usize][..]);
                     let mut pixel_color = Vec3 {
                         x: 0.0,
                         y: 0.0,
                         z: 0.0,
                     };
                     for _ in 0..sample_per_pixel {
-                        let u: f32 =
-                            (width as f32 + rand::random::<f32>()) / (image_width as f32 - 1.);
+                        let u: f32 = (width as f32 + rng.gen::<f32>()) / (image_width as f32 - 1.);
                         let v: f32 =
-                            (height as f32 + rand::random::<f32>()) / (image_height as f32 - 1.);
-                        let r: Ray = camera.get_ray(u, v);
-                        pixel_color += ray_color(&r, &world, max_depth);
+                            (height as f32 + rng.gen::<f32>()) / (image_height as f32 - 1.);
+                        let r: Ray = camera.get_ray(u, v, &mut rng);
+                        pixel_color += ray_color(&r, world, max_depth, &mut rng);
                     }
                     pixel_color
                 })
                 .collect()
         })
-        .collect();
-    write_image(v, sample_per_pixel);
-
-    eprintln!("{:?}", now.elapsed());
+        .collect()
 }
 
-fn write_image(image: Vec<Vec<Vec3>>, sample_per_pixel: i32) {
-    for col in image {
+fn write_image(
+    output: &mut dyn Write,
+    pixels: Vec<Vec<Vec3>>,
+    sample_per_pixel: i32,
+    format: ImageFormat,
+) -> io::Result<()> {
+    let width = pixels.first().map_or(0, |row| row.len());
+    let height = pixels.len();
+    if format.is_high_dynamic_range() {
+        let radiance: Vec<f32> = pixels
+            .iter()
+            .flatten()
+            .flat_map(|&pixel| {
+                let pixel = pixel / sample_per_pixel as f32;
+                [pixel.x, pixel.y, pixel.z]
+            })
+            .collect();
+        return image::write_hdr(output, format, width, height, &radiance);
+    }
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for col in pixels {
         for mut pixel in col {
             pixel /= sample_per_pixel as f32;
-            let ir = (256.0 * clamp(pixel.x.sqrt(), 0.0, 0.999)) as i32;
-            let ig = (256.0 * clamp(pixel.y.sqrt(), 0.0, 0.999)) as i32;
-            let ib = (256.0 * clamp(pixel.z.sqrt(), 0.0, 0.999)) as i32;
-            println!("{:?} {:?} {:?}", ir, ig, ib);
+            rgb.push((256.0 * clamp(pixel.x.sqrt(), 0.0, 0.999)) as u8);
+            rgb.push((256.0 * clamp(pixel.y.sqrt(), 0.0, 0.999)) as u8);
+            rgb.push((256.0 * clamp(pixel.z.sqrt(), 0.0, 0.999)) as u8);
+        }
+    }
+    image::write(output, format, width, height, &rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Closest hit found by testing every sphere, as before the hierarchy
+    fn linear_hit(world: &World, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut closest = None;
+        let mut closest_so_far = t_max;
+        for object in &world.objects {
+            if let Some(rec) = object.hit(r, t_min, closest_so_far) {
+                closest_so_far = rec.t;
+                closest = Some(rec);
+            }
         }
+        closest
+    }
+
+    #[test]
+    fn the_hierarchy_finds_the_closest_hit() {
+        let world = random_scene(7);
+        let mut rng = StdRng::from_seed(&[11][..]);
+        let mut hits = 0;
+        for _ in 0..20000 {
+            let r = Ray {
+                origin: Vec3 {
+                    x: rng.gen_range(-13.0, 13.0),
+                    y: rng.gen_range(0.0, 4.0),
+                    z: rng.gen_range(-13.0, 13.0),
+                },
+                direction: Vec3 {
+                    x: rng.gen_range(-1.0, 1.0),
+                    y: rng.gen_range(-1.0, 1.0),
+                    z: rng.gen_range(-1.0, 1.0),
+                },
+            };
+            let expected = linear_hit(&world, &r, 0.001, 1000000.0).map(|rec| rec.t);
+            assert_eq!(world.hit(&r, 0.001, 1000000.0).map(|rec| rec.t), expected);
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 5000);
+    }
+
+    fn test_camera() -> Camera {
+        Camera::new(
+            Vec3 {
+                x: 13.0,
+                y: 2.0,
+                z: 3.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vec3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            20.0,
+            3.0 / 2.0,
+            0.1,
+            10.0,
+        )
+    }
+
+    fn pixels(image: &[Vec<Vec3>]) -> Vec<[f32; 3]> {
+        image
+            .iter()
+            .flatten()
+            .map(|pixel| [pixel.x, pixel.y, pixel.z])
+            .collect()
+    }
+
+    #[test]
+    fn the_seed_gives_the_image() {
+        let world = random_scene(3);
+        let camera = test_camera();
+        let first = render(&world, &camera, 24, 16, 4, 10, 3);
+        let second = render(&world, &camera, 24, 16, 4, 10, 3);
+        assert_eq!(pixels(&first), pixels(&second));
+        let other = render(&world, &camera, 24, 16, 4, 10, 4);
+        assert_ne!(pixels(&first), pixels(&other));
     }
 }
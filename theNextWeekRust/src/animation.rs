@@ -0,0 +1,369 @@
+use std::cmp::Ordering;
+
+use serde::Deserialize;
+
+use crate::transform::Matrix4;
+use crate::Vec3;
+
+// How the values between two keyframes are found
+#[derive(Debug, Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    // Cubic Hermite spline whose tangents are the slopes between the neighbouring
+    // keyframes (Catmull-Rom), so the motion has no sudden change of speed
+    Spline,
+}
+
+// Values that can be interpolated by weighted sums
+pub trait Animated: Copy {
+    fn add(self, other: Self) -> Self;
+    fn scale(self, factor: f32) -> Self;
+    // Brings a weighted sum back to a valid value
+    fn normalize(self) -> Self {
+        self
+    }
+}
+
+impl Animated for Vec3 {
+    fn add(self, other: Vec3) -> Vec3 {
+        self + other
+    }
+
+    fn scale(self, factor: f32) -> Vec3 {
+        self * factor
+    }
+}
+
+// Unit quaternion w + xi + yj + zk, representing a rotation
+#[derive(Debug, Copy, Clone)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Quaternion {
+    pub fn from_axis_angle(axis: Vec3, degrees: f32) -> Quaternion {
+        let axis = axis.unit_vector();
+        let (sin, cos) = (degrees.to_radians() / 2.0).sin_cos();
+        Quaternion {
+            w: cos,
+            x: axis.x * sin,
+            y: axis.y * sin,
+            z: axis.z * sin,
+        }
+    }
+
+    fn dot(&self, other: &Quaternion) -> f32 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn to_matrix(self) -> Matrix4 {
+        let Quaternion { w, x, y, z } = self;
+        let mut matrix = Matrix4::identity();
+        matrix.m[0][0] = 1.0 - 2.0 * (y * y + z * z);
+        matrix.m[0][1] = 2.0 * (x * y - w * z);
+        matrix.m[0][2] = 2.0 * (x * z + w * y);
+        matrix.m[1][0] = 2.0 * (x * y + w * z);
+        matrix.m[1][1] = 1.0 - 2.0 * (x * x + z * z);
+        matrix.m[1][2] = 2.0 * (y * z - w * x);
+        matrix.m[2][0] = 2.0 * (x * z - w * y);
+        matrix.m[2][1] = 2.0 * (y * z + w * x);
+        matrix.m[2][2] = 1.0 - 2.0 * (x * x + y * y);
+        matrix
+    }
+}
+
+impl Animated for Quaternion {
+    fn add(self, other: Quaternion) -> Quaternion {
+        Quaternion {
+            w: self.w + other.w,
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+
+    fn scale(self, factor: f32) -> Quaternion {
+        Quaternion {
+            w: self.w * factor,
+            x: self.x * factor,
+            y: self.y * factor,
+            z: self.z * factor,
+        }
+    }
+
+    fn normalize(self) -> Quaternion {
+        self.scale(1.0 / self.dot(&self).sqrt())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Track<T> {
+    // Times and values, by increasing times
+    keys: Vec<(f32, T)>,
+    interpolation: Interpolation,
+}
+
+impl<T: Animated> Track<T> {
+    pub fn new(keys: Vec<(f32, T)>, interpolation: Interpolation) -> Result<Track<T>, String> {
+        if keys.is_empty() {
+            return Err("a track needs at least one keyframe".to_string());
+        }
+        let increasing =
+            |pair: &[(f32, T)]| pair[0].0.partial_cmp(&pair[1].0) == Some(Ordering::Less);
+        if !keys.windows(2).all(increasing) {
+            return Err("the times of the keyframes must increase".to_string());
+        }
+        Ok(Track {
+            keys,
+            interpolation,
+        })
+    }
+
+    // Rate of change of the track at keyframe `i`, per unit of time
+    fn tangent(&self, i: usize) -> T {
+        let before = i.saturating_sub(1);
+        let after = (i + 1).min(self.keys.len() - 1);
+        let (t0, v0) = self.keys[before];
+        let (t1, v1) = self.keys[after];
+        v1.add(v0.scale(-1.0)).scale(1.0 / (t1 - t0))
+    }
+
+    // The value is held before the first keyframe and after the last one
+    pub fn value(&self, time: f32) -> T {
+        let last = self.keys.len() - 1;
+        let i = self.keys.partition_point(|&(t, _)| t <= time);
+        if i == 0 {
+            return self.keys[0].1;
+        }
+        if i > last {
+            return self.keys[last].1;
+        }
+        let (t0, v0) = self.keys[i - 1];
+        let (t1, v1) = self.keys[i];
+        let duration = t1 - t0;
+        let s = (time - t0) / duration;
+        match self.interpolation {
+            Interpolation::Linear => v0.scale(1.0 - s).add(v1.scale(s)).normalize(),
+            Interpolation::Spline => {
+                let s2 = s * s;
+                let s3 = s2 * s;
+                v0.scale(2.0 * s3 - 3.0 * s2 + 1.0)
+                    .add(self.tangent(i - 1).scale((s3 - 2.0 * s2 + s) * duration))
+                    .add(v1.scale(-2.0 * s3 + 3.0 * s2))
+                    .add(self.tangent(i).scale((s3 - s2) * duration))
+                    .normalize()
+            }
+        }
+    }
+
+    // Times of the keyframes
+    pub fn times(&self) -> impl Iterator<Item = f32> + '_ {
+        self.keys.iter().map(|&(t, _)| t)
+    }
+}
+
+impl Track<Quaternion> {
+    // q and -q are the same rotation : the keys are flipped so that each one is
+    // in the same hemisphere as the previous one, so that the rotation between
+    // them takes the short way
+    pub fn rotations(
+        keys: Vec<(f32, Quaternion)>,
+        interpolation: Interpolation,
+    ) -> Result<Track<Quaternion>, String> {
+        let mut keys = keys;
+        for i in 1..keys.len() {
+            if keys[i].1.dot(&keys[i - 1].1) < 0.0 {
+                keys[i].1 = keys[i].1.scale(-1.0);
+            }
+        }
+        Track::new(keys, interpolation)
+    }
+}
+
+// Scale, then rotation, then translation, each one optional, changing over time.
+// The rotation and the scale are around the origin of the animated object
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub position: Option<Track<Vec3>>,
+    pub rotation: Option<Track<Quaternion>>,
+    pub scale: Option<Track<Vec3>>,
+}
+
+// Smallest factor of the interpolated scales, as splines can overshoot below zero
+const MIN_SCALE: f32 = 1e-4;
+
+impl Animation {
+    fn scale_at(&self, time: f32) -> Option<Vec3> {
+        self.scale.as_ref().map(|track| {
+            let scale = track.value(time);
+            Vec3 {
+                x: scale.x.max(MIN_SCALE),
+                y: scale.y.max(MIN_SCALE),
+                z: scale.z.max(MIN_SCALE),
+            }
+        })
+    }
+
+    pub fn matrix(&self, time: f32) -> Matrix4 {
+        let mut matrix = Matrix4::identity();
+        if let Some(scale) = self.scale_at(time) {
+            matrix = Matrix4::scaling(scale);
+        }
+        if let Some(rotation) = &self.rotation {
+            matrix = rotation.value(time).to_matrix().mul(&matrix);
+        }
+        if let Some(position) = &self.position {
+            matrix = Matrix4::translation(position.value(time)).mul(&matrix);
+        }
+        matrix
+    }
+
+    // Inverse of `matrix`, from the inverse of each step
+    pub fn inverse_matrix(&self, time: f32) -> Matrix4 {
+        let mut matrix = Matrix4::identity();
+        if let Some(position) = &self.position {
+            matrix = Matrix4::translation(-position.value(time));
+        }
+        if let Some(rotation) = &self.rotation {
+            let q = rotation.value(time);
+            let conjugate = Quaternion {
+                w: q.w,
+                x: -q.x,
+                y: -q.y,
+                z: -q.z,
+            };
+            matrix = conjugate.to_matrix().mul(&matrix);
+        }
+        if let Some(scale) = self.scale_at(time) {
+            let inverse = Vec3 {
+                x: 1.0 / scale.x,
+                y: 1.0 / scale.y,
+                z: 1.0 / scale.z,
+            };
+            matrix = Matrix4::scaling(inverse).mul(&matrix);
+        }
+        matrix
+    }
+
+    // Times of all the keyframes, sorted
+    pub fn key_times(&self) -> Vec<f32> {
+        let mut times: Vec<f32> = self
+            .position
+            .iter()
+            .flat_map(|track| track.times())
+            .chain(self.rotation.iter().flat_map(|track| track.times()))
+            .chain(self.scale.iter().flat_map(|track| track.times()))
+            .collect();
+        times.sort_by(|a, b| a.total_cmp(b));
+        times.dedup();
+        times
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const Y: Vec3 = Vec3 {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+
+    // Angle in degrees of a rotation around the y axis
+    fn y_angle(q: Quaternion) -> f32 {
+        let m = q.to_matrix().m;
+        m[0][2].atan2(m[0][0]).to_degrees()
+    }
+
+    fn rotations(keys: &[(f32, f32)], interpolation: Interpolation) -> Track<Quaternion> {
+        let keys = keys
+            .iter()
+            .map(|&(time, degrees)| (time, Quaternion::from_axis_angle(Y, degrees)))
+            .collect();
+        Track::rotations(keys, interpolation).unwrap()
+    }
+
+    #[test]
+    fn rotations_go_through_their_keyframes() {
+        for interpolation in [Interpolation::Linear, Interpolation::Spline] {
+            let track = rotations(&[(0.0, 0.0), (1.0, 90.0), (2.0, 120.0)], interpolation);
+            for &(time, degrees) in &[
+                (-1.0, 0.0),
+                (0.0, 0.0),
+                (1.0, 90.0),
+                (2.0, 120.0),
+                (3.0, 120.0),
+            ] {
+                assert!((y_angle(track.value(time)) - degrees).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn rotations_are_halfway_at_the_midpoint() {
+        let track = rotations(&[(0.0, 0.0), (1.0, 90.0)], Interpolation::Linear);
+        let q = track.value(0.5);
+        assert!((q.dot(&q) - 1.0).abs() < 1e-5);
+        assert!((y_angle(q) - 45.0).abs() < 1e-3);
+        // The other way around the circle is longer, so 350 to 10 degrees goes
+        // through 0 rather than 180
+        let track = rotations(&[(0.0, 350.0), (1.0, 10.0)], Interpolation::Linear);
+        assert!(y_angle(track.value(0.5)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn inverse_matrices_undo_the_animation() {
+        let animation = Animation {
+            position: Some(
+                Track::new(
+                    vec![(0.0, Y), (1.0, Y * -3.0), (2.0, Y * 4.0)],
+                    Interpolation::Spline,
+                )
+                .unwrap(),
+            ),
+            rotation: Some(rotations(
+                &[(0.0, 10.0), (2.0, 200.0)],
+                Interpolation::Linear,
+            )),
+            scale: Some(
+                Track::new(
+                    vec![
+                        (
+                            0.5,
+                            Vec3 {
+                                x: 2.0,
+                                y: 1.0,
+                                z: 0.5,
+                            },
+                        ),
+                        (
+                            1.5,
+                            Vec3 {
+                                x: 0.3,
+                                y: 3.0,
+                                z: 1.0,
+                            },
+                        ),
+                    ],
+                    Interpolation::Linear,
+                )
+                .unwrap(),
+            ),
+        };
+        for &time in &[0.0, 0.3, 1.0, 1.7, 2.5] {
+            let product = animation.matrix(time).mul(&animation.inverse_matrix(time));
+            for (i, row) in product.m.iter().enumerate() {
+                for (j, &value) in row.iter().enumerate() {
+                    let expected = if i == j { 1.0 } else { 0.0 };
+                    assert!((value - expected).abs() < 1e-4, "{:?} at {}", product, time);
+                }
+            }
+        }
+    }
+}
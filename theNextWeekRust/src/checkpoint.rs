@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::film::{Film, Pixel, PIXEL_BYTES};
+use crate::sampler::{hash, hash_bytes, SamplerKind};
+use crate::scene::RenderSettings;
+use crate::tile::Tile;
+use crate::{Background, Camera};
+
+// Checkpoints keep the pixels of an unfinished render : "RTCHECK1", then little
+// endian a fingerprint of what the samples depend on (u64), the width and the
+// height (u32), and the state of each pixel by rows from the top. The random
+// numbers of a sample only depend on the seed, the pixel and the sample index, so
+// the sample counts are all the state of the generators there is to keep
+const MAGIC: &[u8; 8] = b"RTCHECK1";
+const HEADER_BYTES: usize = 24;
+
+thread_local! {
+    static ASSETS: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// Notes the content of a file read while loading the scene (models, materials,
+// textures and grids), so that a checkpoint is refused once one of them changes.
+// The files are noted by the thread loading the scene
+pub fn record_asset(content: &[u8]) {
+    ASSETS.with(|assets| assets.borrow_mut().push(hash_bytes(content)));
+}
+
+// Hashes of the files noted since the last call, in the order they were read
+pub fn take_assets() -> Vec<u64> {
+    ASSETS.with(|assets| assets.borrow_mut().split_off(0))
+}
+
+// Hash of the scene, of the files it uses and of the settings which change the
+// samples, the rendered region included. The number of samples is only part of it for the stratified
+// sampler, whose strata depend on it : the renders of the other samplers can be
+// resumed with more samples
+pub fn fingerprint(
+    scene: &[u8],
+    assets: &[u64],
+    settings: &RenderSettings,
+    background: &Background,
+    camera: &Camera,
+    region: Tile,
+    seed: u64,
+) -> u64 {
+    hash(&[
+        hash_bytes(scene),
+        hash(assets),
+        hash_bytes(format!("{:?}", background).as_bytes()),
+        seed,
+        settings.image_width as u64,
+        u64::from(settings.aspect_ratio.to_bits()),
+        settings.max_depth as u64,
+        u64::from(settings.light_sampling),
+        settings.sampler as u64,
+        match settings.sampler {
+            SamplerKind::Stratified => settings.sample_per_pixel as u64,
+            _ => 0,
+        },
+        settings.min_samples as u64,
+        settings
+            .adaptive_threshold
+            .map_or(0, |threshold| u64::from(threshold.to_bits())),
+        u64::from(camera.time0.to_bits()),
+        u64::from(camera.time1.to_bits()),
+        region.x0 as u64,
+        region.y0 as u64,
+        region.x1 as u64,
+        region.y1 as u64,
+    ])
+}
+
+pub fn write(output: &mut dyn Write, fingerprint: u64, film: &Film) -> io::Result<()> {
+    output.write_all(MAGIC)?;
+    output.write_all(&fingerprint.to_le_bytes())?;
+    output.write_all(&(film.width as u32).to_le_bytes())?;
+    output.write_all(&(film.height as u32).to_le_bytes())?;
+    for pixel in &film.pixels {
+        output.write_all(&pixel.to_bytes())?;
+    }
+    Ok(())
+}
+
+// Pixels of a checkpoint, which must come from a render of the same scene with
+// the same settings
+pub fn read(path: &Path, fingerprint: u64) -> Result<Film, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if data.len() < HEADER_BYTES || &data[..8] != MAGIC {
+        return Err(format!("{}: not a checkpoint", path.display()));
+    }
+    let u32_at =
+        |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+    if u64::from_le_bytes(data[8..16].try_into().unwrap()) != fingerprint {
+        return Err(format!(
+            "{}: the checkpoint comes from another scene or other settings",
+            path.display()
+        ));
+    }
+    let (width, height) = (u32_at(16), u32_at(20));
+    if data.len() != HEADER_BYTES + width * height * PIXEL_BYTES {
+        return Err(format!("{}: truncated checkpoint", path.display()));
+    }
+    let mut film = Film::new(width, height);
+    film.pixels = data[HEADER_BYTES..]
+        .chunks(PIXEL_BYTES)
+        .map(Pixel::from_bytes)
+        .collect();
+    Ok(film)
+}
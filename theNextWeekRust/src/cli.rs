@@ -0,0 +1,693 @@
+use std::path::{Path, PathBuf};
+
+use crate::image::ImageFormat;
+use crate::progress::ProgressMode;
+use crate::sampler::SamplerKind;
+use crate::scene::RenderSettings;
+use crate::tile::{Tile, TileOrder};
+use crate::{Background, Vec3};
+
+pub const USAGE: &str = "Usage: ray_tracer [OPTIONS]
+
+Renders a scene and writes the image to the standard output or to a file.
+
+Options:
+  -s, --scene <SCENE>          Scene to render : \"random\", a .toml scene file or a .obj model [default: random]
+  -w, --width <PIXELS>         Image width [default: 400, or the value of the scene file]
+  -a, --aspect-ratio <RATIO>   Image width / height, as a number or as W:H [default: 3:2, or the value of the scene file]
+  -n, --samples <N>            Samples per pixel [default: 100, or the value of the scene file]
+  -d, --max-depth <N>          Maximum number of ray bounces [default: 50, or the value of the scene file]
+  -b, --background <COLOR>     Colour of the rays leaving the scene : \"sky\", \"black\" or R,G,B
+                               [default: sky, or the value of the scene file]
+      --no-light-sampling      Only follow the scattered rays, without sampling the lights
+  -j, --threads <N>            Number of render threads [default: number of CPUs]
+      --tile-size <PIXELS>     Size of the square tiles handed to the threads
+                               [default: 32, or the value of the scene file]
+      --tile-order <ORDER>     Order of the tiles : spiral from the centre, hilbert or scanline
+                               [default: spiral, or the value of the scene file]
+      --seed <N>               Seed of the random scene layout [default: random] and of the samples
+                               [default: 0, or the value of the scene file]
+      --sampler <SAMPLER>      Spread of the samples : independent, stratified, halton or sobol
+                               [default: independent, or the value of the scene file]
+      --adaptive <ERROR>       Stops sampling a pixel once its error is below ERROR, the standard error of
+                               its gamma corrected luminance (0.01 is about 2 levels out of 256), up to
+                               --samples samples [default: off, or the value of the scene file]
+      --min-samples <N>        Samples of each pixel before --adaptive can stop it
+                               [default: 16, or the value of the scene file]
+      --progressive <N>        Renders in passes of N samples per pixel, rewriting the output image after
+                               each one, so that it can be looked at or the render stopped early
+      --checkpoint <FILE>      Saves the state of the render to FILE after each pass of --progressive, or
+                               every 16 samples per pixel
+      --resume                 Carries on the render saved in the --checkpoint file, if there is one, up
+                               to --samples, giving the same image as a render that was not stopped
+      --crop <X0,Y0,X1,Y1>     Only renders the window from the pixel X0,Y0 included to X1,Y1 excluded,
+                               counted from the top left corner, or given as fractions of the image
+                               with decimal points (0.25,0.25,0.75,0.75), and writes it alone
+      --full-frame             Writes the whole image with the --crop window, black around it
+      --sample-map <FILE>      Also writes the number of samples of each pixel, as an image whose white
+                               pixels took the most samples, or the counts themselves in the linear formats
+      --progress <MODE>        Progress of the render on the standard error : text, a line with the time
+                               left, json, an object per line, or none
+                               [default: text when the standard error is a terminal, none otherwise]
+      --stats <FILE>           Writes statistics of the render as JSON : rays, intersection tests, lengths
+                               of the paths and time taken by each part of the render
+  -o, --output <FILE>          Output file [default: standard output]
+      --frames <FIRST-LAST>    Renders the frames FIRST to LAST of the animations, frame N starting at time
+                               N / FPS, each one to a file whose number replaces the # of the output name
+                               or is added before its extension [default: frame_####.png]
+      --fps <N>                Frames per second of the sequences [default: 24, or the value of the scene file]
+      --shutter <FRACTION>     Part of each frame during which the shutter is open
+                               [default: 0.5, or the value of the scene file]
+  -f, --format <FORMAT>        Output format : p3 (ASCII PPM), p6 (binary PPM), png, or the linear
+                               hdr (Radiance), pfm, exr (ZIP compressed) and exr-none (uncompressed)
+                               [default: from the output extension (.ppm, .png, .hdr, .pfm, .exr),
+                               p3 on the standard output]
+  -h, --help                   Print this help";
+
+// Window of the image to render, in pixels or in fractions of the image size
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Crop {
+    Pixels([usize; 4]),
+    Fractions([f32; 4]),
+}
+
+impl Crop {
+    // Pixels of the window in an image of the given size, which must hold it
+    pub fn region(&self, width: usize, height: usize) -> Result<Tile, String> {
+        let region = match *self {
+            Crop::Pixels([x0, y0, x1, y1]) => Tile { x0, y0, x1, y1 },
+            // The window takes every pixel it touches
+            Crop::Fractions([x0, y0, x1, y1]) => Tile {
+                x0: (x0 * width as f32).floor() as usize,
+                y0: (y0 * height as f32).floor() as usize,
+                x1: (x1 * width as f32).ceil() as usize,
+                y1: (y1 * height as f32).ceil() as usize,
+            },
+        };
+        if region.x1 > width
+            || region.y1 > height
+            || region.x0 >= region.x1
+            || region.y0 >= region.y1
+        {
+            return Err(format!(
+                "the crop window {},{} to {},{} is not inside the {}x{} image",
+                region.x0, region.y0, region.x1, region.y1, width, height
+            ));
+        }
+        Ok(region)
+    }
+}
+
+pub struct Options {
+    pub help: bool,
+    pub scene: String,
+    pub image_width: Option<i32>,
+    pub aspect_ratio: Option<f32>,
+    pub sample_per_pixel: Option<i32>,
+    pub max_depth: Option<i32>,
+    pub background: Option<Background>,
+    pub light_sampling: Option<bool>,
+    pub threads: Option<usize>,
+    pub tile_size: Option<i32>,
+    pub tile_order: Option<TileOrder>,
+    pub seed: Option<u64>,
+    pub sampler: Option<SamplerKind>,
+    pub adaptive_threshold: Option<f32>,
+    pub min_samples: Option<i32>,
+    pub progressive: Option<i32>,
+    pub sample_map: Option<PathBuf>,
+    pub checkpoint: Option<PathBuf>,
+    pub resume: bool,
+    pub crop: Option<Crop>,
+    pub full_frame: bool,
+    pub progress: Option<ProgressMode>,
+    pub stats: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub format: Option<ImageFormat>,
+    pub frames: Option<(u32, u32)>,
+    pub fps: Option<f32>,
+    pub shutter: Option<f32>,
+}
+
+impl Options {
+    // Settings given on the command line take precedence over the ones of the scene
+    pub fn override_settings(&self, settings: &mut RenderSettings) {
+        if let Some(image_width) = self.image_width {
+            settings.image_width = image_width;
+        }
+        if let Some(aspect_ratio) = self.aspect_ratio {
+            settings.aspect_ratio = aspect_ratio;
+        }
+        if let Some(sample_per_pixel) = self.sample_per_pixel {
+            settings.sample_per_pixel = sample_per_pixel;
+        }
+        if let Some(max_depth) = self.max_depth {
+            settings.max_depth = max_depth;
+        }
+        if let Some(light_sampling) = self.light_sampling {
+            settings.light_sampling = light_sampling;
+        }
+        if let Some(fps) = self.fps {
+            settings.fps = fps;
+        }
+        if let Some(shutter) = self.shutter {
+            settings.shutter = shutter;
+        }
+        if let Some(seed) = self.seed {
+            settings.seed = seed;
+        }
+        if let Some(sampler) = self.sampler {
+            settings.sampler = sampler;
+        }
+        if let Some(threshold) = self.adaptive_threshold {
+            settings.adaptive_threshold = Some(threshold);
+        }
+        if let Some(min_samples) = self.min_samples {
+            settings.min_samples = min_samples;
+        }
+        if let Some(tile_size) = self.tile_size {
+            settings.tile_size = tile_size;
+        }
+        if let Some(tile_order) = self.tile_order {
+            settings.tile_order = tile_order;
+        }
+    }
+
+    // An explicit --format wins, otherwise the format follows the extension of the output file
+    pub fn image_format(&self) -> Result<ImageFormat, String> {
+        match (self.format, &self.output) {
+            (Some(format), _) => Ok(format),
+            (None, Some(path)) => ImageFormat::from_extension(path).ok_or_else(|| {
+                format!(
+                    "cannot guess the image format of {}, use --format",
+                    path.display()
+                )
+            }),
+            (None, None) if self.frames.is_some() => Ok(ImageFormat::Png),
+            (None, None) => Ok(ImageFormat::PpmAscii),
+        }
+    }
+
+    // The sample map is written in the format of its extension
+    pub fn sample_map_format(&self) -> Result<Option<ImageFormat>, String> {
+        match &self.sample_map {
+            Some(path) => ImageFormat::from_extension(path).map(Some).ok_or_else(|| {
+                format!(
+                    "cannot guess the image format of the sample map {}",
+                    path.display()
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+
+    // File of a frame of a sequence : the last run of # of the output name is
+    // replaced by the frame number padded with zeros, or _0001 is added before
+    // the extension of names without any
+    pub fn frame_path(&self, frame: u32, format: ImageFormat) -> PathBuf {
+        let pattern = match &self.output {
+            Some(path) => path.clone(),
+            None => PathBuf::from(format!("frame_####.{}", format.extension())),
+        };
+        numbered_path(&pattern, frame)
+    }
+
+    // Files of the sample map, of the checkpoint and of the statistics of a frame,
+    // numbered like the images
+    pub fn sample_map_path(&self, frame: Option<u32>) -> Option<PathBuf> {
+        self.sample_map.as_ref().map(|path| frame_file(path, frame))
+    }
+
+    pub fn checkpoint_path(&self, frame: Option<u32>) -> Option<PathBuf> {
+        self.checkpoint.as_ref().map(|path| frame_file(path, frame))
+    }
+
+    pub fn stats_path(&self, frame: Option<u32>) -> Option<PathBuf> {
+        self.stats.as_ref().map(|path| frame_file(path, frame))
+    }
+}
+
+fn frame_file(path: &Path, frame: Option<u32>) -> PathBuf {
+    match frame {
+        Some(frame) => numbered_path(path, frame),
+        None => path.to_path_buf(),
+    }
+}
+
+fn numbered_path(pattern: &Path, frame: u32) -> PathBuf {
+    let name = pattern
+        .file_name()
+        .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+    let name = match name.rfind('#') {
+        Some(end) => {
+            let start = name[..end].trim_end_matches('#').len();
+            let width = end + 1 - start;
+            format!(
+                "{}{:0width$}{}",
+                &name[..start],
+                frame,
+                &name[end + 1..],
+                width = width
+            )
+        }
+        None => {
+            let stem_end = name.rfind('.').filter(|&i| i > 0).unwrap_or(name.len());
+            format!("{}_{:04}{}", &name[..stem_end], frame, &name[stem_end..])
+        }
+    };
+    pattern.with_file_name(name)
+}
+
+pub fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        help: false,
+        scene: "random".to_string(),
+        image_width: None,
+        aspect_ratio: None,
+        sample_per_pixel: None,
+        max_depth: None,
+        background: None,
+        light_sampling: None,
+        threads: None,
+        tile_size: None,
+        tile_order: None,
+        seed: None,
+        sampler: None,
+        adaptive_threshold: None,
+        min_samples: None,
+        progressive: None,
+        sample_map: None,
+        checkpoint: None,
+        resume: false,
+        crop: None,
+        full_frame: false,
+        progress: None,
+        stats: None,
+        output: None,
+        format: None,
+        frames: None,
+        fps: None,
+        shutter: None,
+    };
+
+    while let Some(arg) = args.next() {
+        // Both `--option value` and `--option=value` are accepted
+        let (name, mut inline_value) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => {
+                (arg[..i].to_string(), Some(arg[i + 1..].to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = || {
+            inline_value
+                .take()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+        match name.as_str() {
+            "-h" | "--help" => options.help = true,
+            "-s" | "--scene" => options.scene = value()?,
+            "-w" | "--width" => options.image_width = Some(parse_positive(&name, &value()?)?),
+            "-a" | "--aspect-ratio" => options.aspect_ratio = Some(parse_aspect_ratio(&value()?)?),
+            "-n" | "--samples" => {
+                options.sample_per_pixel = Some(parse_positive(&name, &value()?)?)
+            }
+            "-d" | "--max-depth" => options.max_depth = Some(parse_positive(&name, &value()?)?),
+            "-b" | "--background" => options.background = Some(parse_background(&value()?)?),
+            "--no-light-sampling" => options.light_sampling = Some(false),
+            "-j" | "--threads" => {
+                options.threads = Some(parse_positive(&name, &value()?)? as usize)
+            }
+            "--tile-size" => options.tile_size = Some(parse_positive(&name, &value()?)?),
+            "--tile-order" => {
+                let order = value()?;
+                options.tile_order = Some(
+                    TileOrder::from_name(&order)
+                        .ok_or_else(|| format!("unknown tile order {:?}", order))?,
+                )
+            }
+            "--seed" => {
+                let seed = value()?;
+                options.seed = Some(
+                    seed.parse()
+                        .map_err(|_| format!("invalid value {:?} for {}", seed, name))?,
+                )
+            }
+            "--sampler" => {
+                let sampler = value()?;
+                options.sampler = Some(
+                    SamplerKind::from_name(&sampler)
+                        .ok_or_else(|| format!("unknown sampler {:?}", sampler))?,
+                )
+            }
+            "--adaptive" => {
+                let threshold = value()?;
+                options.adaptive_threshold = Some(
+                    threshold
+                        .parse()
+                        .ok()
+                        .filter(|threshold: &f32| threshold.is_finite() && *threshold > 0.0)
+                        .ok_or_else(|| {
+                            format!(
+                                "invalid value {:?} for {}, expected a positive number",
+                                threshold, name
+                            )
+                        })?,
+                )
+            }
+            "--min-samples" => options.min_samples = Some(parse_positive(&name, &value()?)?),
+            "--progressive" => options.progressive = Some(parse_positive(&name, &value()?)?),
+            "--checkpoint" => options.checkpoint = Some(PathBuf::from(value()?)),
+            "--resume" => options.resume = true,
+            "--crop" => options.crop = Some(parse_crop(&value()?)?),
+            "--full-frame" => options.full_frame = true,
+            "--progress" => {
+                let mode = value()?;
+                options.progress = Some(
+                    ProgressMode::from_name(&mode)
+                        .ok_or_else(|| format!("unknown progress mode {:?}", mode))?,
+                )
+            }
+            "--stats" => options.stats = Some(PathBuf::from(value()?)),
+            "--sample-map" => options.sample_map = Some(PathBuf::from(value()?)),
+            "-o" | "--output" => options.output = Some(PathBuf::from(value()?)),
+            "-f" | "--format" => {
+                let format = value()?;
+                options.format = Some(
+                    ImageFormat::from_name(&format)
+                        .ok_or_else(|| format!("unknown output format {:?}", format))?,
+                )
+            }
+            "--frames" => options.frames = Some(parse_frames(&value()?)?),
+            "--fps" => {
+                let fps = value()?;
+                options.fps = Some(
+                    fps.parse()
+                        .ok()
+                        .filter(|fps: &f32| fps.is_finite() && *fps > 0.0)
+                        .ok_or_else(|| {
+                            format!(
+                                "invalid value {:?} for {}, expected a positive number",
+                                fps, name
+                            )
+                        })?,
+                )
+            }
+            "--shutter" => {
+                let shutter = value()?;
+                options.shutter = Some(
+                    shutter
+                        .parse()
+                        .ok()
+                        .filter(|shutter| (0.0..=1.0).contains(shutter))
+                        .ok_or_else(|| {
+                            format!(
+                                "invalid value {:?} for {}, expected a number between 0 and 1",
+                                shutter, name
+                            )
+                        })?,
+                )
+            }
+            _ => return Err(format!("unknown option {}", name)),
+        }
+    }
+    if options.resume && options.checkpoint.is_none() {
+        return Err("--resume needs the --checkpoint file to resume".to_string());
+    }
+    if options.full_frame && options.crop.is_none() {
+        return Err("--full-frame needs a --crop window".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_positive(name: &str, value: &str) -> Result<i32, String> {
+    match value.parse::<i32>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!(
+            "invalid value {:?} for {}, expected a positive integer",
+            value, name
+        )),
+    }
+}
+
+// Four pixel numbers, or four fractions written with decimal points
+fn parse_crop(value: &str) -> Result<Crop, String> {
+    let error = || format!("invalid crop window {:?}, expected X0,Y0,X1,Y1", value);
+    let values: Vec<&str> = value.split(',').map(str::trim).collect();
+    if values.len() != 4 {
+        return Err(error());
+    }
+    if values.iter().any(|value| value.contains('.')) {
+        let fractions = values
+            .iter()
+            .map(|value| value.parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| error())?;
+        match fractions[..] {
+            [x0, y0, x1, y1]
+                if 0.0 <= x0 && x0 < x1 && x1 <= 1.0 && 0.0 <= y0 && y0 < y1 && y1 <= 1.0 =>
+            {
+                Ok(Crop::Fractions([x0, y0, x1, y1]))
+            }
+            _ => Err(error()),
+        }
+    } else {
+        let pixels = values
+            .iter()
+            .map(|value| value.parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| error())?;
+        match pixels[..] {
+            [x0, y0, x1, y1] if x0 < x1 && y0 < y1 => Ok(Crop::Pixels([x0, y0, x1, y1])),
+            _ => Err(error()),
+        }
+    }
+}
+
+// A single frame N, or the range FIRST-LAST
+fn parse_frames(value: &str) -> Result<(u32, u32), String> {
+    let error = || format!("invalid frames {:?}, expected N or FIRST-LAST", value);
+    let (first, last) = match value.find('-') {
+        Some(i) => (&value[..i], &value[i + 1..]),
+        None => (value, value),
+    };
+    let first: u32 = first.trim().parse().map_err(|_| error())?;
+    let last: u32 = last.trim().parse().map_err(|_| error())?;
+    if first <= last {
+        Ok((first, last))
+    } else {
+        Err(error())
+    }
+}
+
+fn parse_aspect_ratio(value: &str) -> Result<f32, String> {
+    let error = || format!("invalid aspect ratio {:?}, expected a number or W:H", value);
+    let ratio = match value.find(':') {
+        Some(i) => {
+            let width: f32 = value[..i].parse().map_err(|_| error())?;
+            let height: f32 = value[i + 1..].parse().map_err(|_| error())?;
+            width / height
+        }
+        None => value.parse().map_err(|_| error())?,
+    };
+    if ratio.is_finite() && ratio > 0.0 {
+        Ok(ratio)
+    } else {
+        Err(error())
+    }
+}
+
+fn parse_background(value: &str) -> Result<Background, String> {
+    let error = || {
+        format!(
+            "invalid background {:?}, expected sky, black or R,G,B",
+            value
+        )
+    };
+    match value {
+        "sky" => Ok(Background::Sky),
+        "black" => Ok(Background::Color(Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        })),
+        _ => {
+            let components = value
+                .split(',')
+                .map(|c| c.trim().parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|_| error())?;
+            match components[..] {
+                [x, y, z] if x >= 0.0 && y >= 0.0 && z >= 0.0 => {
+                    Ok(Background::Color(Vec3 { x, y, z }))
+                }
+                _ => Err(error()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn options_take_their_values_in_both_forms() {
+        let options = parse(&[
+            "-w",
+            "320",
+            "--samples=64",
+            "--aspect-ratio",
+            "16:9",
+            "--seed=7",
+            "--sampler",
+            "sobol",
+            "--adaptive",
+            "0.02",
+            "--tile-order",
+            "hilbert",
+            "--frames",
+            "3-5",
+            "--shutter",
+            "0",
+            "-o",
+            "out.png",
+            "--no-light-sampling",
+        ])
+        .unwrap();
+        assert_eq!(options.image_width, Some(320));
+        assert_eq!(options.sample_per_pixel, Some(64));
+        assert!((options.aspect_ratio.unwrap() - 16.0 / 9.0).abs() < 1e-6);
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.sampler, Some(SamplerKind::Sobol));
+        assert_eq!(options.adaptive_threshold, Some(0.02));
+        assert_eq!(options.tile_order, Some(TileOrder::Hilbert));
+        assert_eq!(options.frames, Some((3, 5)));
+        assert_eq!(options.shutter, Some(0.0));
+        assert_eq!(options.output, Some(PathBuf::from("out.png")));
+        assert_eq!(options.light_sampling, Some(false));
+        assert_eq!(options.scene, "random");
+
+        let mut settings = RenderSettings::default();
+        options.override_settings(&mut settings);
+        assert_eq!(settings.image_width, 320);
+        assert_eq!(settings.sampler, SamplerKind::Sobol);
+        assert!(!settings.light_sampling);
+        assert_eq!(settings.max_depth, RenderSettings::default().max_depth);
+    }
+
+    #[test]
+    fn bad_values_are_refused() {
+        for args in [
+            &["--width", "0"][..],
+            &["--samples", "-4"],
+            &["--width"],
+            &["--aspect-ratio", "16:0"],
+            &["--seed", "-1"],
+            &["--sampler", "owen"],
+            &["--adaptive", "0"],
+            &["--adaptive", "NaN"],
+            &["--shutter", "1.5"],
+            &["--fps", "inf"],
+            &["--frames", "5-3"],
+            &["--background", "1,2"],
+            &["--format", "gif"],
+            &["--progress", "bar"],
+            &["--crop", "10,10,5,20"],
+            &["--resume"],
+            &["--full-frame"],
+            &["--unknown"],
+        ]
+        .iter()
+        {
+            assert!(parse(args).is_err(), "{:?} was accepted", args);
+        }
+        assert_eq!(
+            parse(&["--width", "0"]).err().unwrap(),
+            "invalid value \"0\" for --width, expected a positive integer"
+        );
+    }
+
+    #[test]
+    fn crop_windows_are_pixels_or_fractions() {
+        assert_eq!(
+            parse_crop("120,80,240,160"),
+            Ok(Crop::Pixels([120, 80, 240, 160]))
+        );
+        assert_eq!(
+            parse_crop("0.3, 0.2, 0.6, 1"),
+            Ok(Crop::Fractions([0.3, 0.2, 0.6, 1.0]))
+        );
+        for value in [
+            "1,2,3",
+            "0.5,0.2,0.4,0.6",
+            "0,0,1.5,1",
+            "1,2,x,4",
+            "4,2,4,6",
+        ]
+        .iter()
+        {
+            assert!(parse_crop(value).is_err(), "{:?} was accepted", value);
+        }
+
+        // Fractions take every pixel they touch
+        let region = Crop::Fractions([0.25, 0.2, 0.5, 0.45])
+            .region(100, 10)
+            .unwrap();
+        assert_eq!(
+            region,
+            Tile {
+                x0: 25,
+                y0: 2,
+                x1: 50,
+                y1: 5
+            }
+        );
+        assert!(Crop::Pixels([0, 0, 10, 10]).region(10, 10).is_ok());
+        assert!(Crop::Pixels([0, 0, 11, 10]).region(10, 10).is_err());
+        // Windows too thin for any pixel
+        assert!(Crop::Fractions([0.5, 0.5, 0.5001, 0.5001])
+            .region(10, 10)
+            .is_ok());
+    }
+
+    #[test]
+    fn frame_numbers_replace_the_last_hashes() {
+        let numbered = |pattern: &str, frame| numbered_path(Path::new(pattern), frame);
+        assert_eq!(
+            numbered("out/frame_###.png", 7),
+            Path::new("out/frame_007.png")
+        );
+        assert_eq!(numbered("a#_b##.png", 3), Path::new("a#_b03.png"));
+        assert_eq!(numbered("f#.png", 1234), Path::new("f1234.png"));
+        assert_eq!(
+            numbered("out/render.png", 12),
+            Path::new("out/render_0012.png")
+        );
+        assert_eq!(numbered("render", 5), Path::new("render_0005"));
+        assert_eq!(numbered(".hidden", 5), Path::new(".hidden_0005"));
+        assert_eq!(
+            numbered("render.v2.exr", 5),
+            Path::new("render.v2_0005.exr")
+        );
+
+        let options = parse(&["--stats", "stats.json", "--sample-map", "map_##.png"]).unwrap();
+        assert_eq!(options.stats_path(None), Some(PathBuf::from("stats.json")));
+        assert_eq!(
+            options.stats_path(Some(4)),
+            Some(PathBuf::from("stats_0004.json"))
+        );
+        assert_eq!(
+            options.sample_map_path(Some(4)),
+            Some(PathBuf::from("map_04.png"))
+        );
+        assert_eq!(
+            options.frame_path(9, ImageFormat::Png),
+            PathBuf::from("frame_0009.png")
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::convert::TryInto;
+
+use crate::tile::Tile;
+use crate::Vec3;
+
+// Samples gathered by a pixel : their sum and number, with the running mean and
+// sum of squared deviations of their luminance (Welford's algorithm) which tell
+// how far the pixel is from converging
+#[derive(Debug, Copy, Clone)]
+pub struct Pixel {
+    pub sum: Vec3,
+    pub samples: u32,
+    mean: f64,
+    squared_deviations: f64,
+}
+
+fn luminance(color: Vec3) -> f64 {
+    f64::from(0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z)
+}
+
+// Size of a pixel in the checkpoints
+pub const PIXEL_BYTES: usize = 32;
+
+// Darkest luminance used when comparing the error to the brightness, so that
+// black pixels with a little noise can converge
+const MIN_LUMINANCE: f64 = 1e-4;
+
+impl Pixel {
+    pub fn new() -> Pixel {
+        Pixel {
+            sum: Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            samples: 0,
+            mean: 0.0,
+            squared_deviations: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, color: Vec3) {
+        self.sum += color;
+        self.samples += 1;
+        let luminance = luminance(color);
+        let delta = luminance - self.mean;
+        self.mean += delta / f64::from(self.samples);
+        self.squared_deviations += delta * (luminance - self.mean);
+    }
+
+    // Mean of the samples
+    pub fn color(&self) -> Vec3 {
+        self.sum / self.samples.max(1) as f32
+    }
+
+    // Standard error of the mean luminance once gamma corrected, as the square
+    // root of the images spreads the errors of the dark pixels. It is in the
+    // units of the displayed values, 0.01 being about 2 levels out of 256
+    pub fn error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        let n = f64::from(self.samples);
+        let variance = self.squared_deviations / (n - 1.0);
+        let standard_error = (variance / n).sqrt();
+        (standard_error / (2.0 * self.mean.max(MIN_LUMINANCE).sqrt())) as f32
+    }
+
+    // Sum, number of samples, mean and squared deviations, little endian
+    pub fn to_bytes(self) -> [u8; PIXEL_BYTES] {
+        let mut bytes = [0; PIXEL_BYTES];
+        bytes[0..4].copy_from_slice(&self.sum.x.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.sum.y.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.sum.z.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.samples.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.mean.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.squared_deviations.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Pixel {
+        let f32_at =
+            |offset: usize| f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let f64_at =
+            |offset: usize| f64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        Pixel {
+            sum: Vec3 {
+                x: f32_at(0),
+                y: f32_at(4),
+                z: f32_at(8),
+            },
+            samples: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            mean: f64_at(16),
+            squared_deviations: f64_at(24),
+        }
+    }
+}
+
+// Pixels of an image, by rows from the top, of which only the region is
+// rendered, the other pixels staying black
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Pixel>,
+    pub region: Tile,
+}
+
+impl Film {
+    // Pixels without any sample yet, all of them rendered
+    pub fn new(width: usize, height: usize) -> Film {
+        Film {
+            width,
+            height,
+            pixels: vec![Pixel::new(); width * height],
+            region: Tile {
+                x0: 0,
+                y0: 0,
+                x1: width,
+                y1: height,
+            },
+        }
+    }
+
+    // Image of the region alone
+    pub fn cropped(&self) -> Film {
+        let mut film = Film::new(self.region.width(), self.region.height());
+        film.pixels = self.tile(self.region);
+        film
+    }
+
+    // Copy of the pixels of a tile, by rows from its top
+    pub fn tile(&self, tile: Tile) -> Vec<Pixel> {
+        (tile.y0..tile.y1)
+            .flat_map(|y| &self.pixels[y * self.width + tile.x0..y * self.width + tile.x1])
+            .copied()
+            .collect()
+    }
+
+    pub fn set_tile(&mut self, tile: Tile, pixels: &[Pixel]) {
+        for (y, row) in (tile.y0..tile.y1).zip(pixels.chunks(tile.width())) {
+            self.pixels[y * self.width + tile.x0..y * self.width + tile.x1].copy_from_slice(row);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(level: f32) -> Vec3 {
+        Vec3 {
+            x: level,
+            y: level,
+            z: level,
+        }
+    }
+
+    #[test]
+    fn the_error_needs_two_samples() {
+        let mut pixel = Pixel::new();
+        assert_eq!(pixel.error(), f32::INFINITY);
+        pixel.add(grey(0.5));
+        assert_eq!(pixel.error(), f32::INFINITY);
+        pixel.add(grey(0.5));
+        assert_eq!(pixel.error(), 0.0);
+    }
+
+    #[test]
+    fn the_error_is_the_standard_error_of_the_gamma_corrected_mean() {
+        // Luminances 0.2 and 0.6 : mean 0.4, variance 0.08, standard error 0.2
+        let mut pixel = Pixel::new();
+        pixel.add(grey(0.2));
+        pixel.add(grey(0.6));
+        let expected = 0.2 / (2.0 * 0.4f32.sqrt());
+        assert!((pixel.error() - expected).abs() < 1e-5, "{}", pixel.error());
+        // The error falls as more samples of the same spread come
+        for _ in 0..49 {
+            pixel.add(grey(0.2));
+            pixel.add(grey(0.6));
+        }
+        assert!(pixel.error() < expected / 5.0, "{}", pixel.error());
+    }
+
+    #[test]
+    fn pixels_read_back_unchanged() {
+        let mut pixel = Pixel::new();
+        pixel.add(grey(0.25));
+        pixel.add(Vec3 {
+            x: 1.0,
+            y: 0.0,
+            z: 3.0,
+        });
+        let read = Pixel::from_bytes(&pixel.to_bytes());
+        assert_eq!(read.to_bytes(), pixel.to_bytes());
+        assert_eq!(read.error(), pixel.error());
+    }
+}
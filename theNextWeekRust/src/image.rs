@@ -0,0 +1,799 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+
+use crate::checkpoint;
+
+#[derive(Debug, Copy, Clone)]
+pub enum ImageFormat {
+    // Plain text P3 PPM, one pixel per line
+    PpmAscii,
+    // Binary P6 PPM
+    PpmBinary,
+    Png,
+    // Linear floating point formats, written without tone mapping
+    RadianceHdr,
+    Pfm,
+    ExrUncompressed,
+    ExrZip,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "p3" => Some(ImageFormat::PpmAscii),
+            "p6" | "ppm" => Some(ImageFormat::PpmBinary),
+            "png" => Some(ImageFormat::Png),
+            "hdr" => Some(ImageFormat::RadianceHdr),
+            "pfm" => Some(ImageFormat::Pfm),
+            "exr" | "exr-zip" => Some(ImageFormat::ExrZip),
+            "exr-none" => Some(ImageFormat::ExrUncompressed),
+            _ => None,
+        }
+    }
+
+    pub fn from_extension(path: &Path) -> Option<ImageFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "ppm" => Some(ImageFormat::PpmBinary),
+            "png" => Some(ImageFormat::Png),
+            "hdr" => Some(ImageFormat::RadianceHdr),
+            "pfm" => Some(ImageFormat::Pfm),
+            "exr" => Some(ImageFormat::ExrZip),
+            _ => None,
+        }
+    }
+
+    // Extension of the files written in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::PpmAscii | ImageFormat::PpmBinary => "ppm",
+            ImageFormat::Png => "png",
+            ImageFormat::RadianceHdr => "hdr",
+            ImageFormat::Pfm => "pfm",
+            ImageFormat::ExrUncompressed | ImageFormat::ExrZip => "exr",
+        }
+    }
+
+    pub fn is_high_dynamic_range(self) -> bool {
+        match self {
+            ImageFormat::PpmAscii | ImageFormat::PpmBinary | ImageFormat::Png => false,
+            ImageFormat::RadianceHdr
+            | ImageFormat::Pfm
+            | ImageFormat::ExrUncompressed
+            | ImageFormat::ExrZip => true,
+        }
+    }
+}
+
+// Writes an 8 bits per channel RGB image, `rgb` holding the rows from top to bottom
+pub fn write(
+    output: &mut dyn Write,
+    format: ImageFormat,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+) -> io::Result<()> {
+    match format {
+        ImageFormat::PpmAscii => {
+            writeln!(output, "P3")?;
+            writeln!(output, "{:?} {:?}", width, height)?;
+            writeln!(output, "{:?}", 255)?;
+            for pixel in rgb.chunks(3) {
+                writeln!(output, "{:?} {:?} {:?}", pixel[0], pixel[1], pixel[2])?;
+            }
+        }
+        ImageFormat::PpmBinary => {
+            write!(output, "P6\n{} {}\n255\n", width, height)?;
+            output.write_all(rgb)?;
+        }
+        ImageFormat::Png => write_png(output, width, height, rgb)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not an 8 bits format", format),
+            ))
+        }
+    }
+    output.flush()
+}
+
+// Writes a linear floating point RGB image, `rgb` holding the rows from top to bottom
+pub fn write_hdr(
+    output: &mut dyn Write,
+    format: ImageFormat,
+    width: usize,
+    height: usize,
+    rgb: &[f32],
+) -> io::Result<()> {
+    match format {
+        ImageFormat::RadianceHdr => write_radiance(output, width, height, rgb)?,
+        ImageFormat::Pfm => {
+            // Negative scale for little endian data, rows are stored from bottom to top
+            write!(output, "PF\n{} {}\n-1.0\n", width, height)?;
+            for row in rgb.chunks(width * 3).rev() {
+                for value in row {
+                    output.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        ImageFormat::ExrUncompressed => write_exr(output, width, height, rgb, false)?,
+        ImageFormat::ExrZip => write_exr(output, width, height, rgb, true)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a floating point format", format),
+            ))
+        }
+    }
+    output.flush()
+}
+
+fn write_png(output: &mut dyn Write, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    output.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, truecolour, deflate compression, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(output, b"IHDR", &header)?;
+
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    let stride = width * 3;
+    let mut previous = vec![0; stride];
+    let mut filtered = vec![0; stride];
+    for row in rgb.chunks(stride.max(1)).take(height) {
+        let filter = filter_row(row, &previous, &mut filtered);
+        encoder.write_all(&[filter])?;
+        encoder.write_all(&filtered)?;
+        previous.copy_from_slice(row);
+    }
+    write_chunk(output, b"IDAT", &encoder.finish()?)?;
+
+    write_chunk(output, b"IEND", &[])
+}
+
+fn write_chunk(output: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    output.write_all(&(data.len() as u32).to_be_bytes())?;
+    output.write_all(kind)?;
+    output.write_all(data)?;
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    output.write_all(&crc.sum().to_be_bytes())
+}
+
+// Applies every PNG filter to the row and keeps the one with the smallest sum of
+// absolute values, the usual heuristic to get the most compressible output
+fn filter_row(row: &[u8], previous: &[u8], filtered: &mut [u8]) -> u8 {
+    let mut best_filter = 0;
+    let mut best_sum = u64::MAX;
+    let mut candidate = vec![0; row.len()];
+    for filter in 0..5 {
+        for i in 0..row.len() {
+            let left = if i >= 3 { row[i - 3] } else { 0 };
+            let up = previous[i];
+            let up_left = if i >= 3 { previous[i - 3] } else { 0 };
+            let predicted = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                _ => paeth(left, up, up_left),
+            };
+            candidate[i] = row[i].wrapping_sub(predicted);
+        }
+        let sum: u64 = candidate
+            .iter()
+            .map(|&b| (b as i8).unsigned_abs() as u64)
+            .sum();
+        if sum < best_sum {
+            best_sum = sum;
+            best_filter = filter;
+            filtered.copy_from_slice(&candidate);
+        }
+    }
+    best_filter
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn write_radiance(
+    output: &mut dyn Write,
+    width: usize,
+    height: usize,
+    rgb: &[f32],
+) -> io::Result<()> {
+    write!(
+        output,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+    for row in rgb.chunks(width * 3).take(height) {
+        let pixels: Vec<[u8; 4]> = row.chunks(3).map(rgbe).collect();
+        // Run length encoding is only defined for these widths, other
+        // images are written as flat RGBE values
+        if !(8..0x8000).contains(&width) {
+            for pixel in pixels {
+                output.write_all(&pixel)?;
+            }
+            continue;
+        }
+        let mut line = vec![2, 2, (width >> 8) as u8, (width & 0xff) as u8];
+        for component in 0..4 {
+            let values: Vec<u8> = pixels.iter().map(|pixel| pixel[component]).collect();
+            run_length_encode(&values, &mut line);
+        }
+        output.write_all(&line)?;
+    }
+    Ok(())
+}
+
+// Shared exponent encoding of a colour, the mantissas being scaled by the largest component
+fn rgbe(color: &[f32]) -> [u8; 4] {
+    let max = color[0].max(color[1]).max(color[2]);
+    if !max.is_finite() || max < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // Up to 2^127, the largest f32 values filling the mantissas
+    let exponent = (max.log2().floor() as i32 + 1).min(127);
+    let scale = 256.0 / 2f32.powi(exponent);
+    let mantissa = |c: f32| (c.max(0.0) * scale).min(255.0) as u8;
+    [
+        mantissa(color[0]),
+        mantissa(color[1]),
+        mantissa(color[2]),
+        (exponent + 128) as u8,
+    ]
+}
+
+// Encodes one component of a scanline as runs of identical bytes (count + 128, value)
+// and literal sequences (count, values...)
+fn run_length_encode(values: &[u8], line: &mut Vec<u8>) {
+    const MIN_RUN: usize = 4;
+    let mut i = 0;
+    while i < values.len() {
+        let mut run_start = i;
+        let mut run_length = 0;
+        while run_start < values.len() {
+            run_length = 1;
+            while run_start + run_length < values.len()
+                && run_length < 127
+                && values[run_start + run_length] == values[run_start]
+            {
+                run_length += 1;
+            }
+            if run_length >= MIN_RUN {
+                break;
+            }
+            run_start += run_length;
+        }
+        while i < run_start {
+            let count = (run_start - i).min(128);
+            line.push(count as u8);
+            line.extend_from_slice(&values[i..i + count]);
+            i += count;
+        }
+        if run_length >= MIN_RUN {
+            line.push(128 + run_length as u8);
+            line.push(values[run_start]);
+            i = run_start + run_length;
+        }
+    }
+}
+
+// Scanline OpenEXR file with 32 bits float B, G and R channels. ZIP compression
+// works on blocks of 16 scanlines, uncompressed files store one scanline per block
+fn write_exr(
+    output: &mut dyn Write,
+    width: usize,
+    height: usize,
+    rgb: &[f32],
+    zip: bool,
+) -> io::Result<()> {
+    let lines_per_block = if zip { 16 } else { 1 };
+
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    let mut channels = vec![];
+    // Channels are stored in alphabetical order
+    for name in &[b"B", b"G", b"R"] {
+        channels.extend_from_slice(*name);
+        channels.push(0);
+        // FLOAT pixel type, not perceptually linear, x and y sampling of 1
+        channels.extend_from_slice(&2i32.to_le_bytes());
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    let mut window = vec![];
+    for value in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+    exr_attribute(&mut header, "channels", "chlist", &channels);
+    exr_attribute(
+        &mut header,
+        "compression",
+        "compression",
+        &[if zip { 3 } else { 0 }],
+    );
+    exr_attribute(&mut header, "dataWindow", "box2i", &window);
+    exr_attribute(&mut header, "displayWindow", "box2i", &window);
+    exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    exr_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    let mut blocks = vec![];
+    for first_line in (0..height).step_by(lines_per_block) {
+        let mut data = vec![];
+        for y in first_line..(first_line + lines_per_block).min(height) {
+            let row = &rgb[y * width * 3..(y + 1) * width * 3];
+            for channel in &[2, 1, 0] {
+                for pixel in row.chunks(3) {
+                    data.extend_from_slice(&pixel[*channel].to_le_bytes());
+                }
+            }
+        }
+        if zip {
+            let compressed = exr_zip(&data)?;
+            // Blocks which do not shrink are stored uncompressed
+            if compressed.len() < data.len() {
+                data = compressed;
+            }
+        }
+        blocks.push((first_line, data));
+    }
+
+    // The offset table gives the position of each block from the start of the file
+    let mut offset = header.len() + blocks.len() * 8;
+    for (_, data) in &blocks {
+        header.extend_from_slice(&(offset as u64).to_le_bytes());
+        offset += 8 + data.len();
+    }
+    output.write_all(&header)?;
+    for (first_line, data) in blocks {
+        output.write_all(&(first_line as i32).to_le_bytes())?;
+        output.write_all(&(data.len() as i32).to_le_bytes())?;
+        output.write_all(&data)?;
+    }
+    Ok(())
+}
+
+fn exr_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+// Bytes are split between even and odd positions, then delta encoded, before zlib compression
+fn exr_zip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reordered: Vec<u8> = data.iter().step_by(2).copied().collect();
+    reordered.extend(data.iter().skip(1).step_by(2));
+    let mut previous = reordered.first().copied().unwrap_or(0);
+    for byte in reordered.iter_mut().skip(1) {
+        let current = *byte;
+        *byte = current.wrapping_sub(previous).wrapping_add(128);
+        previous = current;
+    }
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(&reordered)?;
+    encoder.finish()
+}
+
+// Reads a PNG or a PPM image, given by its rows from top to bottom as RGB values
+// in [0, 1] without any change of their gamma encoding
+pub fn read(path: &Path) -> Result<(usize, usize, Vec<f32>), String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    checkpoint::record_asset(&data);
+    decode(&data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn decode(data: &[u8]) -> Result<(usize, usize, Vec<f32>), String> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        read_png(data)
+    } else if data.starts_with(b"P") {
+        read_ppm(data)
+    } else {
+        Err("unknown image format, expected PNG or PPM".to_string())
+    }
+}
+
+fn read_png(data: &[u8]) -> Result<(usize, usize, Vec<f32>), String> {
+    let mut header = None;
+    let mut palette = vec![];
+    let mut compressed = vec![];
+    let mut position = 8;
+    while position + 8 <= data.len() {
+        let length = u32::from_be_bytes([
+            data[position],
+            data[position + 1],
+            data[position + 2],
+            data[position + 3],
+        ]) as usize;
+        let kind = &data[position + 4..position + 8];
+        let chunk = data
+            .get(position + 8..position + 8 + length)
+            .ok_or("truncated PNG chunk")?;
+        match kind {
+            b"IHDR" if length == 13 => header = Some(chunk.to_vec()),
+            b"PLTE" => palette = chunk.to_vec(),
+            b"IDAT" => compressed.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+        // Chunk data followed by its CRC
+        position += 12 + length;
+    }
+
+    let header = header.ok_or("missing PNG header")?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let bit_depth = header[8] as usize;
+    let color_type = header[9];
+    if header[12] != 0 {
+        return Err("interlaced PNG images are not supported".to_string());
+    }
+    let channels = match color_type {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return Err(format!("invalid PNG colour type {}", color_type)),
+    };
+    if ![1, 2, 4, 8, 16].contains(&bit_depth) {
+        return Err(format!("invalid PNG bit depth {}", bit_depth));
+    }
+
+    let mut raw = vec![];
+    ZlibDecoder::new(&compressed[..])
+        .read_to_end(&mut raw)
+        .map_err(|e| format!("invalid PNG data: {}", e))?;
+    let stride = (width * channels * bit_depth).div_ceil(8);
+    // Distance to the same byte of the previous pixel, used by the filters
+    let pixel_size = ((channels * bit_depth) / 8).max(1);
+    if raw.len() < (stride + 1) * height {
+        return Err("truncated PNG data".to_string());
+    }
+
+    let max = ((1u32 << bit_depth) - 1) as f32;
+    let mut previous = vec![0u8; stride];
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for row in raw.chunks(stride + 1).take(height) {
+        let mut line = row[1..].to_vec();
+        unfilter_row(row[0], &mut line, &previous, pixel_size)?;
+        let sample = |index: usize| -> u32 {
+            match bit_depth {
+                16 => u16::from_be_bytes([line[2 * index], line[2 * index + 1]]) as u32,
+                8 => line[index] as u32,
+                _ => {
+                    let bit = index * bit_depth;
+                    let shift = 8 - bit_depth - bit % 8;
+                    ((line[bit / 8] >> shift) as u32) & ((1 << bit_depth) - 1)
+                }
+            }
+        };
+        for x in 0..width {
+            match color_type {
+                3 => {
+                    let index = sample(x) as usize * 3;
+                    let color = palette
+                        .get(index..index + 3)
+                        .ok_or("PNG palette index out of range")?;
+                    rgb.extend(color.iter().map(|&c| c as f32 / 255.0));
+                }
+                // Grey, with or without alpha
+                0 | 4 => {
+                    let grey = sample(x * channels) as f32 / max;
+                    rgb.extend_from_slice(&[grey, grey, grey]);
+                }
+                // Alpha is ignored
+                _ => {
+                    for c in 0..3 {
+                        rgb.push(sample(x * channels + c) as f32 / max);
+                    }
+                }
+            }
+        }
+        previous = line;
+    }
+    Ok((width, height, rgb))
+}
+
+fn unfilter_row(
+    filter: u8,
+    line: &mut [u8],
+    previous: &[u8],
+    pixel_size: usize,
+) -> Result<(), String> {
+    for i in 0..line.len() {
+        let left = if i >= pixel_size {
+            line[i - pixel_size]
+        } else {
+            0
+        };
+        let up = previous[i];
+        let up_left = if i >= pixel_size {
+            previous[i - pixel_size]
+        } else {
+            0
+        };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(format!("invalid PNG filter {}", filter)),
+        };
+        line[i] = line[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+// Plain (P2, P3) and binary (P5, P6) grey or colour images
+fn read_ppm(data: &[u8]) -> Result<(usize, usize, Vec<f32>), String> {
+    let magic = data.get(..2).ok_or("truncated PPM header")?;
+    let (channels, binary) = match magic {
+        b"P2" => (1, false),
+        b"P3" => (3, false),
+        b"P5" => (1, true),
+        b"P6" => (3, true),
+        _ => return Err("unknown image format, expected PNG or PPM".to_string()),
+    };
+
+    // Whitespace separated header fields, comments running to the end of the line
+    let mut position = 2;
+    let mut next_token = |data: &[u8]| -> Result<String, String> {
+        loop {
+            match data.get(position) {
+                Some(b'#') => {
+                    while data.get(position).is_some_and(|&c| c != b'\n') {
+                        position += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => position += 1,
+                Some(_) => break,
+                None => return Err("truncated PPM data".to_string()),
+            }
+        }
+        let start = position;
+        while data.get(position).is_some_and(|c| !c.is_ascii_whitespace()) {
+            position += 1;
+        }
+        Ok(String::from_utf8_lossy(&data[start..position]).into_owned())
+    };
+    let mut number = |data: &[u8]| -> Result<usize, String> {
+        let token = next_token(data)?;
+        token
+            .parse()
+            .map_err(|_| format!("invalid number {:?} in PPM image", token))
+    };
+    let width = number(data)?;
+    let height = number(data)?;
+    let max_value = number(data)?;
+    if max_value == 0 || max_value > 65535 {
+        return Err(format!("invalid PPM maximum value {}", max_value));
+    }
+
+    let count = width * height * channels;
+    let samples: Vec<f32> = if binary {
+        // A single whitespace character separates the header from the data
+        let start = position + 1;
+        let sample_size = if max_value > 255 { 2 } else { 1 };
+        let bytes = data
+            .get(start..start + count * sample_size)
+            .ok_or("truncated PPM data")?;
+        bytes
+            .chunks(sample_size)
+            .map(|b| {
+                let value = if sample_size == 2 {
+                    u16::from_be_bytes([b[0], b[1]]) as f32
+                } else {
+                    b[0] as f32
+                };
+                value / max_value as f32
+            })
+            .collect()
+    } else {
+        (0..count)
+            .map(|_| number(data).map(|value| value as f32 / max_value as f32))
+            .collect::<Result<_, _>>()?
+    };
+
+    let rgb = if channels == 1 {
+        samples
+            .iter()
+            .flat_map(|&grey| [grey, grey, grey])
+            .collect()
+    } else {
+        samples
+    };
+    Ok((width, height, rgb))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn rgbe_scales_the_mantissas_by_the_largest_component() {
+        assert_eq!(rgbe(&[1.0, 0.5, 0.25]), [128, 64, 32, 129]);
+        assert_eq!(rgbe(&[0.0, 0.0, 0.0]), [0, 0, 0, 0]);
+        assert_eq!(rgbe(&[f32::MAX, 1.0, 0.0]), [255, 0, 0, 255]);
+        assert_eq!(rgbe(&[f32::INFINITY, 1.0, 0.0]), [0, 0, 0, 0]);
+        assert_eq!(rgbe(&[f32::NAN, f32::NAN, f32::NAN]), [0, 0, 0, 0]);
+    }
+
+    // Colours of a small image with all the byte values, odd sized so that the
+    // rows are not aligned on anything
+    fn test_image() -> (usize, usize, Vec<u8>) {
+        let (width, height) = (13, 7);
+        let rgb = (0..width * height * 3)
+            .map(|i| (i * 97 % 256) as u8)
+            .collect();
+        (width, height, rgb)
+    }
+
+    #[test]
+    fn eight_bits_images_read_back_unchanged() {
+        let (width, height, rgb) = test_image();
+        for format in [
+            ImageFormat::Png,
+            ImageFormat::PpmBinary,
+            ImageFormat::PpmAscii,
+        ] {
+            let mut data = vec![];
+            write(&mut data, format, width, height, &rgb).unwrap();
+            let (read_width, read_height, read) = decode(&data).unwrap();
+            assert_eq!((read_width, read_height), (width, height), "{:?}", format);
+            let bytes: Vec<u8> = read.iter().map(|&c| (c * 255.0).round() as u8).collect();
+            assert_eq!(bytes, rgb, "{:?}", format);
+        }
+    }
+
+    // Linear radiance of a small image, with values above 1
+    fn test_radiance(width: usize, height: usize) -> Vec<f32> {
+        (0..width * height * 3)
+            .map(|i| (i % 17) as f32 * 0.25)
+            .collect()
+    }
+
+    #[test]
+    fn radiance_files_hold_every_pixel() {
+        // Flat RGBE values below 8 pixels wide, run length encoded scanlines above
+        for (width, height) in [(5, 3), (13, 7)] {
+            let rgb = test_radiance(width, height);
+            let mut data = vec![];
+            write_hdr(&mut data, ImageFormat::RadianceHdr, width, height, &rgb).unwrap();
+            let header = format!(
+                "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+                height, width
+            );
+            assert!(data.starts_with(header.as_bytes()));
+            let mut pixels = vec![];
+            let mut position = header.len();
+            for _ in 0..height {
+                if width < 8 {
+                    pixels.extend(
+                        data[position..position + width * 4]
+                            .chunks(4)
+                            .map(|p| p.to_vec()),
+                    );
+                    position += width * 4;
+                    continue;
+                }
+                assert_eq!(data[position..position + 4], [2, 2, 0, width as u8]);
+                position += 4;
+                let mut components = vec![];
+                for _ in 0..4 {
+                    let mut values = vec![];
+                    while values.len() < width {
+                        let count = data[position] as usize;
+                        if count > 128 {
+                            values.extend(std::iter::repeat_n(data[position + 1], count - 128));
+                            position += 2;
+                        } else {
+                            values.extend_from_slice(&data[position + 1..position + 1 + count]);
+                            position += 1 + count;
+                        }
+                    }
+                    components.push(values);
+                }
+                pixels.extend((0..width).map(|x| components.iter().map(|c| c[x]).collect()));
+            }
+            assert_eq!(position, data.len());
+            let expected: Vec<Vec<u8>> = rgb.chunks(3).map(|p| rgbe(p).to_vec()).collect();
+            assert_eq!(pixels, expected);
+        }
+    }
+
+    #[test]
+    fn pfm_files_hold_the_rows_from_the_bottom() {
+        let (width, height) = (13, 7);
+        let rgb = test_radiance(width, height);
+        let mut data = vec![];
+        write_hdr(&mut data, ImageFormat::Pfm, width, height, &rgb).unwrap();
+        let header = b"PF\n13 7\n-1.0\n";
+        assert!(data.starts_with(header));
+        assert_eq!(data.len(), header.len() + width * height * 12);
+        let first = f32::from_le_bytes(data[header.len()..header.len() + 4].try_into().unwrap());
+        assert_eq!(first, rgb[(height - 1) * width * 3]);
+    }
+
+    #[test]
+    fn exr_files_give_their_size_and_blocks() {
+        let (width, height) = (13, 40);
+        let rgb = test_radiance(width, height);
+        for (format, lines_per_block) in
+            [(ImageFormat::ExrUncompressed, 1), (ImageFormat::ExrZip, 16)]
+        {
+            let mut data = vec![];
+            write_hdr(&mut data, format, width, height, &rgb).unwrap();
+            assert!(data.starts_with(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]));
+            let attribute = b"dataWindow\0box2i\0";
+            let start = data
+                .windows(attribute.len())
+                .position(|w| w == attribute)
+                .unwrap()
+                + attribute.len();
+            let int = |at: usize| i32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+            assert_eq!(
+                (0..5).map(|i| int(start + 4 * i)).collect::<Vec<_>>(),
+                [16, 0, 0, width as i32 - 1, height as i32 - 1]
+            );
+            // The header ends after the last attribute and an empty name, followed
+            // by the offsets of the blocks, each one giving its first line and size
+            let last = b"screenWindowWidth\0float\0";
+            let header_end =
+                data.windows(last.len()).position(|w| w == last).unwrap() + last.len() + 9;
+            let blocks = height.div_ceil(lines_per_block);
+            let mut position = header_end + blocks * 8;
+            for block in 0..blocks {
+                let offset = &data[header_end + block * 8..header_end + block * 8 + 8];
+                assert_eq!(
+                    u64::from_le_bytes(offset.try_into().unwrap()) as usize,
+                    position
+                );
+                assert_eq!(int(position), (block * lines_per_block) as i32);
+                if lines_per_block == 1 {
+                    assert_eq!(int(position + 4) as usize, width * 12);
+                }
+                position += 8 + int(position + 4) as usize;
+            }
+            assert_eq!(position, data.len(), "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::f32::consts::PI;
+
+use crate::rect::Plane;
+use crate::sampler::Sampler;
+use crate::{HitableObject, Material, MovingSphere, Ray, Sphere, Vec3, World};
+
+// Light sampling picks one of the emissive spheres or rectangles uniformly, then
+// a direction towards it : uniformly inside the cone subtended by a sphere, or
+// towards a uniform point of a rectangle. The density of the chosen direction is
+// the mean of the densities of every light, which is also what the BSDF sampled
+// rays are weighted against
+impl World {
+    pub fn sample_light(&self, origin: Vec3, time: f32, sampler: &mut Sampler) -> Option<Vec3> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let index =
+            ((sampler.get_1d() * self.lights.len() as f32) as usize).min(self.lights.len() - 1);
+        self.objects[self.lights[index]].sample_direction(origin, time, sampler)
+    }
+
+    pub fn light_pdf(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        if self.lights.is_empty() {
+            return 0.0;
+        }
+        let total: f32 = self
+            .lights
+            .iter()
+            .map(|&i| self.objects[i].direction_pdf(origin, direction, time))
+            .sum();
+        total / self.lights.len() as f32
+    }
+}
+
+// Shapes of the emissive objects that can be sampled
+enum LightShape {
+    Sphere(Vec3, f32),
+    Rect(Plane),
+}
+
+impl HitableObject {
+    pub fn is_sampled_light(&self) -> bool {
+        self.light_shape(0.0).is_some()
+    }
+
+    fn sample_direction(&self, origin: Vec3, time: f32, sampler: &mut Sampler) -> Option<Vec3> {
+        match self.light_shape(time)? {
+            LightShape::Sphere(center, radius) => sample_cone(origin, center, radius, sampler),
+            LightShape::Rect(plane) => {
+                let (u, v) = sampler.get_2d();
+                Some(plane.point_at(u, v) - origin)
+            }
+        }
+    }
+
+    // Density of `direction` when sampling this light from `origin`, over solid angles
+    fn direction_pdf(&self, origin: Vec3, direction: Vec3, time: f32) -> f32 {
+        match self.light_shape(time) {
+            Some(LightShape::Sphere(center, radius)) => cone_pdf(origin, direction, center, radius),
+            Some(LightShape::Rect(plane)) => {
+                let r = Ray {
+                    origin,
+                    direction,
+                    time,
+                };
+                match plane.intersect(&r, 0.001, f32::INFINITY) {
+                    Some((t, _, _)) => {
+                        let distance_squared = t * t * direction.length_squared();
+                        let cosine = direction.unit_vector().dot(plane.normal()).abs();
+                        distance_squared / (cosine * plane.area())
+                    }
+                    None => 0.0,
+                }
+            }
+            None => 0.0,
+        }
+    }
+
+    fn light_shape(&self, time: f32) -> Option<LightShape> {
+        match self {
+            HitableObject::Sphere(Sphere {
+                center,
+                radius,
+                material: Material::DiffuseLight { .. },
+            }) => Some(LightShape::Sphere(*center, *radius)),
+            HitableObject::MovingSphere(MovingSphere {
+                center0,
+                center1,
+                time0,
+                time1,
+                radius,
+                material: Material::DiffuseLight { .. },
+            }) => Some(LightShape::Sphere(
+                MovingSphere::center(*center0, *center1, *time0, *time1, time),
+                *radius,
+            )),
+            HitableObject::XYRect(rect) => emissive_rect(&rect.material, rect.plane()),
+            HitableObject::XZRect(rect) => emissive_rect(&rect.material, rect.plane()),
+            HitableObject::YZRect(rect) => emissive_rect(&rect.material, rect.plane()),
+            _ => None,
+        }
+    }
+}
+
+fn emissive_rect(material: &Material, plane: Plane) -> Option<LightShape> {
+    match material {
+        Material::DiffuseLight { .. } => Some(LightShape::Rect(plane)),
+        _ => None,
+    }
+}
+
+// Solid angle of the cone of directions from `origin` towards the sphere, none
+// when the origin is inside the sphere
+fn cone_solid_angle(origin: Vec3, center: Vec3, radius: f32) -> Option<(f32, f32)> {
+    let distance_squared = (center - origin).length_squared();
+    let sin_theta_max_squared = radius * radius / distance_squared;
+    if sin_theta_max_squared >= 1.0 {
+        return None;
+    }
+    let cos_theta_max = (1.0 - sin_theta_max_squared).sqrt();
+    // 1 - cos, written to keep its precision for small and distant lights
+    let one_minus_cos = sin_theta_max_squared / (1.0 + cos_theta_max);
+    Some((cos_theta_max, 2.0 * PI * one_minus_cos))
+}
+
+fn sample_cone(origin: Vec3, center: Vec3, radius: f32, sampler: &mut Sampler) -> Option<Vec3> {
+    let (_, solid_angle) = cone_solid_angle(origin, center, radius)?;
+    let w = (center - origin).unit_vector();
+    let a = if w.x.abs() > 0.9 {
+        Vec3 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        }
+    } else {
+        Vec3 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    };
+    let v = w.cross(a).unit_vector();
+    let u = w.cross(v);
+
+    let (u1, u2) = sampler.get_2d();
+    let one_minus_cos = u1 * solid_angle / (2.0 * PI);
+    let cos_theta = 1.0 - one_minus_cos;
+    let sin_theta = (one_minus_cos * (2.0 - one_minus_cos)).sqrt();
+    let phi = 2.0 * PI * u2;
+    Some(u * (sin_theta * phi.cos()) + v * (sin_theta * phi.sin()) + w * cos_theta)
+}
+
+fn cone_pdf(origin: Vec3, direction: Vec3, center: Vec3, radius: f32) -> f32 {
+    match cone_solid_angle(origin, center, radius) {
+        Some((cos_theta_max, solid_angle))
+            if direction.unit_vector().dot((center - origin).unit_vector()) >= cos_theta_max =>
+        {
+            1.0 / solid_angle
+        }
+        _ => 0.0,
+    }
+}
+
+// Weight of a sample drawn with density `pdf` when `other_pdf` could also have produced it
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf_squared = pdf * pdf;
+    let sum = pdf_squared + other_pdf * other_pdf;
+    if sum > 0.0 {
+        pdf_squared / sum
+    } else {
+        0.0
+    }
+}
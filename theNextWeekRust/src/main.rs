@@ -1,5 +1,43 @@
-use std::time::SystemTime;
-use rayon::prelude::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+use rand::{Rng, SeedableRng, StdRng};
+
+mod animation;
+mod bvh;
+mod checkpoint;
+mod cli;
+mod film;
+mod image;
+mod light;
+mod medium;
+mod mesh;
+mod obj;
+mod progress;
+mod rect;
+mod sampler;
+mod scene;
+mod stats;
+mod texture;
+mod tile;
+mod transform;
+mod voxel;
+use animation::Animation;
+use bvh::{Aabb, Bvh};
+use film::{Film, Pixel};
+use image::ImageFormat;
+use medium::{ConstantMedium, GridMedium};
+use mesh::Mesh;
+use progress::{Progress, ProgressMode};
+use rect::{Cuboid, XYRect, XZRect, YZRect};
+use sampler::{Dimensions, Sampler};
+use scene::{CameraSettings, RenderSettings, Scene};
+use stats::{Counter, Stats, Timings};
+use texture::Texture;
+use tile::Tile;
+use transform::Instance;
 
 
 // #[derive(Debug, Copy, Clone)]
@@ -47,47 +85,41 @@ impl Vec3 {
         }
     }
 
-    fn write_color(mut self, sample_per_pixel: i32) {
-        self /= sample_per_pixel as f32;
-        let ir = (256.0 * clamp(self.x.sqrt(), 0.0, 0.999)) as i32;
-        let ig = (256.0 * clamp(self.y.sqrt(), 0.0, 0.999)) as i32;
-        let ib = (256.0 * clamp(self.z.sqrt(), 0.0, 0.999)) as i32;
-        println!("{:?} {:?} {:?}", ir, ig, ib);
-    }
-
-    fn random(min: f32, max: f32) -> Vec3 {
+    fn random<R: Rng>(rng: &mut R, min: f32, max: f32) -> Vec3 {
         Vec3 {
-            x: min + (max - min) * rand::random::<f32>(),
-            y: min + (max - min) * rand::random::<f32>(),
-            z: min + (max - min) * rand::random::<f32>(),
+            x: min + (max - min) * rng.gen::<f32>(),
+            y: min + (max - min) * rng.gen::<f32>(),
+            z: min + (max - min) * rng.gen::<f32>(),
         }
     }
 
-    fn random_in_unit_sphere() -> Vec3 {
-        loop {
-            let p = Vec3::random(- 1.0, 1.0);
-            if p.length() < 1.0 {
-                return p
-            }
-        }
+    // A direction and a distance from the center, rather than rejecting the points
+    // of a cube, so that each point takes the same numbers of the sampler
+    fn random_in_unit_sphere(sampler: &mut Sampler) -> Vec3 {
+        let direction = Vec3::random_unit_vector(sampler);
+        direction * sampler.get_1d().cbrt()
     }
-    fn random_unit_vector() -> Vec3 {
-        let a: f32 = 2.0 * std::f32::consts::PI * rand::random::<f32>();
-        let z: f32 = - 1.0 + 2.0 * rand::random::<f32>();
+    fn random_unit_vector(sampler: &mut Sampler) -> Vec3 {
+        let (u, v) = sampler.get_2d();
+        let a: f32 = 2.0 * std::f32::consts::PI * u;
+        let z: f32 = - 1.0 + 2.0 * v;
         let r: f32 = (1.0 - z *z).sqrt();
-        Vec3{x: r * a.cos(), y: r * a.sin(), z: z}
+        Vec3{x: r * a.cos(), y: r * a.sin(), z}
     }
-    fn random_in_unit_disk() -> Vec3 {
-        loop {
-            let p = Vec3{
-                x: - 1.0 + 2.0 * rand::random::<f32>(),
-                y: - 1.0 + 2.0 * rand::random::<f32>(),
-                z: 0.0,
-            };
-            if p.length() < 1.0 {
-                return p
-            }
+    // Concentric mapping of the square onto the disk (Shirley and Chiu), which keeps
+    // the samples spread as well as they were in the square
+    fn random_in_unit_disk(sampler: &mut Sampler) -> Vec3 {
+        let (u, v) = sampler.get_2d();
+        let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+        if a == 0.0 && b == 0.0 {
+            return Vec3{x: 0.0, y: 0.0, z: 0.0}
         }
+        let (r, theta) = if a.abs() > b.abs() {
+            (a, std::f32::consts::FRAC_PI_4 * (b / a))
+        } else {
+            (b, std::f32::consts::FRAC_PI_2 - std::f32::consts::FRAC_PI_4 * (a / b))
+        };
+        Vec3{x: r * theta.cos(), y: r * theta.sin(), z: 0.0}
     }
     fn reflect(v: Vec3, n: Vec3) -> Vec3 {
         v - n * 2.0 * v.dot(n)
@@ -161,23 +193,82 @@ impl std::ops::Neg for Vec3 {
 }
 
 // todo : implement sur ray ?
-fn ray_color(r: &Ray, world: &World, depth: i32) -> Vec3 {
-     // param : nb_ray: &mut i32
-    // *nb_ray += 1;
+// `scattering_pdf` is the density with which the previous material chose `r`, when
+// light sampling could also have chosen it
+fn ray_color(r: &Ray, world: &World, background: &Background, depth: i32, scattering_pdf: Option<f32>, sampler: &mut Sampler) -> Vec3 {
     if depth <= 0 {
+        stats::count(Counter::MaxDepthPaths);
         return Vec3{x: 0.0, y: 0.0, z: 0.0}
     }
+    stats::count(Counter::PathRays);
+
+    sampler.start(Dimensions::Medium);
+    if let Some(ray_hitten) = world.hit(r, 0.001, 1000000.0, sampler) {
+        sampler.start(Dimensions::Scatter);
+        let mut color = ray_hitten.material.emitted(&ray_hitten);
+        if let Some(pdf) = scattering_pdf {
+            if color.length_squared() > 0.0 {
+                color = color * light::power_heuristic(pdf, world.light_pdf(r.origin, r.direction, r.time));
+            }
+        }
+        if let Some((scattered, attenuation)) = ray_hitten.material.scatter(r, &ray_hitten, sampler) {
+            let pdf = ray_hitten.material.scattering_pdf(&ray_hitten, scattered.direction);
+            // Next event estimation, only done when the scattered ray could still reach
+            // a light so that both estimates cover the same paths
+            if pdf.is_some() && depth > 1 {
+                color += sample_direct_light(r, &ray_hitten, attenuation, world, sampler);
+            }
+            color += ray_color(&scattered, world, background, depth - 1, pdf, sampler) * attenuation;
+        }
+        return color
+    }
+
+    background.color(r)
+}
 
-    if let Some(ray_hitten) = world.hit(r, 0.001, 1000000.0) {
-        if let Some((scattered, attenuation)) = ray_hitten.material.scatter(r, &ray_hitten) {
-            return ray_color(&scattered, world, depth - 1) * attenuation
+// Light reaching `rec` from a direction chosen by light sampling, weighted against
+// the chance of the material scattering in that direction
+fn sample_direct_light(r: &Ray, rec: &HitRecord, attenuation: Vec3, world: &World, sampler: &mut Sampler) -> Vec3 {
+    let black = Vec3{x: 0.0, y: 0.0, z: 0.0};
+    sampler.start(Dimensions::Light);
+    let direction = match world.sample_light(rec.p, r.time, sampler) {
+        Some(direction) => direction,
+        None => return black
+    };
+    let light_pdf = world.light_pdf(rec.p, direction, r.time);
+    let material_pdf = rec.material.scattering_pdf(rec, direction).unwrap_or(0.0);
+    if light_pdf <= 0.0 || material_pdf <= 0.0 {
+        return black
+    }
+    let shadow_ray = Ray{origin: rec.p, direction, time: r.time};
+    stats::count(Counter::ShadowRays);
+    match world.hit_through_volumes(&shadow_ray, 0.001, 1000000.0, sampler) {
+        Some((light_hit, transmittance)) => {
+            light_hit.material.emitted(&light_hit) * attenuation
+                * (transmittance * material_pdf / light_pdf * light::power_heuristic(light_pdf, material_pdf))
         }
-        return Vec3{x: 0.0, y: 0.0, z: 0.0}
+        None => black
     }
+}
+
+// Light coming from rays which escape the scene
+#[derive(Debug, Copy, Clone)]
+enum Background {
+    Sky,
+    Color(Vec3)
+}
 
-    let unit_direction = r.direction.unit_vector();
-    let t = 0.5 * (unit_direction.y + 1.0);
-    Vec3{x: 1.0, y: 1.0, z: 1.0} * (1.0 - t) + Vec3{x: 0.5, y: 0.7, z: 1.0} * t
+impl Background {
+    fn color(&self, r: &Ray) -> Vec3 {
+        match self {
+            Background::Sky => {
+                let unit_direction = r.direction.unit_vector();
+                let t = 0.5 * (unit_direction.y + 1.0);
+                Vec3{x: 1.0, y: 1.0, z: 1.0} * (1.0 - t) + Vec3{x: 0.5, y: 0.7, z: 1.0} * t
+            }
+            Background::Color(color) => *color
+        }
+    }
 }
 
 struct Ray {
@@ -192,16 +283,30 @@ impl Ray {
     }
 }
 
-struct HitRecord {
+struct HitRecord<'a> {
     p: Vec3,
     normal: Vec3,
     t: f32,
     front_face: bool,
-    material: Material
+    material: &'a Material,
+    // Surface coordinates used to look up textures
+    u: f32,
+    v: f32
 }
 
 struct World {
     objects: Vec<HitableObject>,
+    // Hierarchy of the objects which do not move, built once, and of the moving
+    // ones, rebuilt for each shutter interval. Both index into `static_objects`
+    // and `moving_objects`, which index into `objects`
+    bvh: Bvh,
+    static_objects: Vec<usize>,
+    moving_bvh: Bvh,
+    moving_objects: Vec<usize>,
+    // Indices of the objects sampled as lights
+    lights: Vec<usize>,
+    // Indices of the grid volumes, which shadow rays go through
+    volumes: Vec<usize>
 }
 
 // enum HitableObject {
@@ -216,34 +321,94 @@ impl MovingSphere {
     }
 }
 
+// Longitude and latitude of a point of the unit sphere, u going around the y axis
+// starting from -x and v going from the bottom to the top
+fn sphere_uv(p: Vec3) -> (f32, f32) {
+    let theta = (- p.y).acos();
+    let phi = (- p.z).atan2(p.x) + std::f32::consts::PI;
+    (phi / (2.0 * std::f32::consts::PI), theta / std::f32::consts::PI)
+}
+
 
 enum HitableObject {
     Sphere(Sphere),//{center: Vec3, radius: f32, material: Material},
-    MovingSphere(MovingSphere)//{center0: Vec3, center1: Vec3, time0: f32, time1: f32, radius: f32, material: Material}
+    MovingSphere(MovingSphere),//{center0: Vec3, center1: Vec3, time0: f32, time1: f32, radius: f32, material: Material}
+    Mesh(Mesh),
+    XYRect(XYRect),
+    XZRect(XZRect),
+    YZRect(YZRect),
+    Box(Cuboid),
+    Instance(Instance),
+    ConstantMedium(ConstantMedium),
+    GridMedium(GridMedium)
 }
 
 
 impl World {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let mut closest_so_far = t_max;
-        let mut return_record = None;
-
-        for object in &self.objects {
-            if let Some(hitten) = object.hit(&r, t_min, closest_so_far) {
-                closest_so_far = hitten.t;
-                return_record = Some(hitten);
+    fn new(objects: Vec<HitableObject>) -> World {
+        let (moving_objects, static_objects): (Vec<usize>, Vec<usize>) = (0..objects.len()).partition(|&i| objects[i].is_moving());
+        let boxes = |indices: &[usize]| -> Vec<Aabb> {indices.iter().map(|&i| objects[i].bounding_box()).collect()};
+        let bvh = Bvh::new(&boxes(&static_objects));
+        let moving_bvh = Bvh::new(&boxes(&moving_objects));
+        let lights = (0..objects.len()).filter(|&i| objects[i].is_sampled_light()).collect();
+        let volumes = (0..objects.len()).filter(|&i| matches!(objects[i], HitableObject::GridMedium(_))).collect();
+        World {bvh, static_objects, moving_bvh, moving_objects, objects, lights, volumes}
+    }
+
+    // Fits the boxes of the moving objects to where they are between time0 and time1,
+    // the times of the rays to come. The hierarchy of the static objects is kept
+    fn set_shutter(&mut self, time0: f32, time1: f32) {
+        let boxes: Vec<Aabb> = self.moving_objects.iter().map(|&i| self.objects[i].bounding_box_between(time0, time1)).collect();
+        self.moving_bvh = Bvh::new(&boxes);
+    }
+
+    // Closest hit among the objects, `hit_object` testing one of them
+    fn hit_with<'a, F>(&'a self, r: &Ray, t_min: f32, t_max: f32, mut hit_object: F) -> Option<HitRecord<'a>>
+    where F: FnMut(&'a HitableObject, f32, f32) -> Option<HitRecord<'a>> {
+        let closest = self.bvh.hit(r, t_min, t_max, |i, t_min, closest_so_far| hit_object(&self.objects[self.static_objects[i]], t_min, closest_so_far));
+        let t_max = closest.as_ref().map_or(t_max, |rec| rec.t);
+        self.moving_bvh.hit(r, t_min, t_max, |i, t_min, closest_so_far| hit_object(&self.objects[self.moving_objects[i]], t_min, closest_so_far))
+            .or(closest)
+    }
+
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, sampler: &mut Sampler) -> Option<HitRecord<'_>> {
+        self.hit_with(r, t_min, t_max, |object, t_min, t_max| object.hit(r, t_min, t_max, sampler))
+    }
+}
+
+impl HitableObject {
+    fn is_moving(&self) -> bool {
+        match self {
+            HitableObject::MovingSphere(_) => true,
+            HitableObject::Instance(instance) => instance.animation.is_some(),
+            _ => false
+        }
+    }
+
+    // Box enclosing the object at every time between time0 and time1
+    fn bounding_box_between(&self, time0: f32, time1: f32) -> Aabb {
+        match self {
+            HitableObject::MovingSphere(MovingSphere {center0, center1, time0: start, time1: end, radius, ..}) => {
+                let r = Vec3{x: *radius, y: *radius, z: *radius};
+                let c0 = MovingSphere::center(*center0, *center1, *start, *end, time0);
+                let c1 = MovingSphere::center(*center0, *center1, *start, *end, time1);
+                Aabb::surrounding_box(Aabb{min: c0 - r, max: c0 + r}, Aabb{min: c1 - r, max: c1 + r})
             }
+            HitableObject::Instance(instance) => instance.bounding_box_between(time0, time1),
+            _ => self.bounding_box()
         }
-        return return_record
     }
 }
 
+// The volumes draw the distances to their collisions from the sampler, the
+// surfaces do not use it
 trait Hitable {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, sampler: &mut Sampler) -> Option<HitRecord<'_>>;
+    fn bounding_box(&self) -> Aabb;
 }
 
 impl Hitable for HitableObject {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, sampler: &mut Sampler) -> Option<HitRecord<'_>> {
         match self {
             HitableObject::Sphere(Sphere {center, radius, material}) => {
                 let oc = r.origin - *center;
@@ -259,12 +424,15 @@ impl Hitable for HitableObject {
                         let p = r.at(temp);
                         let normal = (p - *center) / *radius;
                         let front_face = r.direction.dot(normal) < 0.0;
+                        let (u, v) = sphere_uv(normal);
                         return Some(HitRecord {
                             t: temp,
-                            p: p,
+                            p,
                             normal: if front_face {normal} else {- normal},
-                            front_face: front_face,
-                            material: *material
+                            front_face,
+                            material,
+                            u,
+                            v
                         })
                     }
                     let temp = (- half_b + root) / a;
@@ -272,12 +440,15 @@ impl Hitable for HitableObject {
                         let p = r.at(temp);
                         let normal = (p - *center) / *radius;
                         let front_face = r.direction.dot(normal) < 0.0;
+                        let (u, v) = sphere_uv(normal);
                         return Some(HitRecord {
                             t: temp,
-                            p: p,
+                            p,
                             normal: if front_face {normal} else {- normal},
-                            front_face: front_face,
-                            material: *material
+                            front_face,
+                            material,
+                            u,
+                            v
                         })
                     }
                 }
@@ -297,12 +468,15 @@ impl Hitable for HitableObject {
                         let p = r.at(temp);
                         let normal = (p - MovingSphere::center(*center0, *center1, *time0, *time1, r.time)) / *radius;
                         let front_face = r.direction.dot(normal) < 0.0;
+                        let (u, v) = sphere_uv(normal);
                         return Some(HitRecord {
                             t: temp,
-                            p: p,
+                            p,
                             normal: if front_face {normal} else {- normal},
-                            front_face: front_face,
-                            material: *material
+                            front_face,
+                            material,
+                            u,
+                            v
                         })
                     }
                     let temp = (- half_b + root) / a;
@@ -310,17 +484,53 @@ impl Hitable for HitableObject {
                         let p = r.at(temp);
                         let normal = (p - MovingSphere::center(*center0, *center1, *time0, *time1, r.time)) / *radius;
                         let front_face = r.direction.dot(normal) < 0.0;
+                        let (u, v) = sphere_uv(normal);
                         return Some(HitRecord {
                             t: temp,
-                            p: p,
+                            p,
                             normal: if front_face {normal} else {- normal},
-                            front_face: front_face,
-                            material: *material
+                            front_face,
+                            material,
+                            u,
+                            v
                         })
                     }
                 }
                 None
             }
+            HitableObject::Mesh(mesh) => mesh.hit(r, t_min, t_max, sampler),
+            HitableObject::XYRect(rect) => rect.hit(r, t_min, t_max, sampler),
+            HitableObject::XZRect(rect) => rect.hit(r, t_min, t_max, sampler),
+            HitableObject::YZRect(rect) => rect.hit(r, t_min, t_max, sampler),
+            HitableObject::Box(cuboid) => cuboid.hit(r, t_min, t_max, sampler),
+            HitableObject::Instance(instance) => instance.hit(r, t_min, t_max, sampler),
+            HitableObject::ConstantMedium(medium) => medium.hit(r, t_min, t_max, sampler),
+            HitableObject::GridMedium(medium) => medium.hit(r, t_min, t_max, sampler)
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        match self {
+            HitableObject::Sphere(Sphere {center, radius, ..}) => {
+                let r = Vec3{x: *radius, y: *radius, z: *radius};
+                Aabb{min: *center - r, max: *center + r}
+            }
+            // Box enclosing the sphere along its whole path between time0 and time1
+            HitableObject::MovingSphere(MovingSphere {center0, center1, radius, ..}) => {
+                let r = Vec3{x: *radius, y: *radius, z: *radius};
+                Aabb::surrounding_box(
+                    Aabb{min: *center0 - r, max: *center0 + r},
+                    Aabb{min: *center1 - r, max: *center1 + r}
+                )
+            }
+            HitableObject::Mesh(mesh) => mesh.bounding_box(),
+            HitableObject::XYRect(rect) => rect.bounding_box(),
+            HitableObject::XZRect(rect) => rect.bounding_box(),
+            HitableObject::YZRect(rect) => rect.bounding_box(),
+            HitableObject::Box(cuboid) => cuboid.bounding_box(),
+            HitableObject::Instance(instance) => instance.bounding_box(),
+            HitableObject::ConstantMedium(medium) => medium.bounding_box(),
+            HitableObject::GridMedium(medium) => medium.bounding_box()
         }
     }
 }
@@ -334,10 +544,13 @@ struct Camera {
     v: Vec3,
     lens_radius: f32,
     time0: f32,
-    time1: f32
+    time1: f32,
+    // Motion of the camera around lookfrom, following the ray times
+    animation: Option<Animation>
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     fn new(lookfrom: Vec3, lookat: Vec3, vup: Vec3, vfov: f32, aspect_ratio: f32, aperture: f32, focus_dist: f32, t0: f32, t1: f32) -> Camera {
         let theta = vfov * std::f32::consts::PI / 180.0;
         let h = (theta / 2.0).tan();
@@ -354,47 +567,61 @@ impl Camera {
         let lower_left_corner = origin - horizontal / 2.0 - vertical / 2.0 - w * focus_dist;
         let lens_radius = aperture / 2.0;
         Camera {
-            origin: origin,
-            horizontal: horizontal,
-            vertical: vertical,
-            lower_left_corner: lower_left_corner,
-            u: u,
-            v: v,
-            lens_radius: lens_radius,
+            origin,
+            horizontal,
+            vertical,
+            lower_left_corner,
+            u,
+            v,
+            lens_radius,
             time0: t0,
-            time1: t1
+            time1: t1,
+            animation: None
         }
     }
 
-    fn get_ray(&self, u: f32, v: f32) -> Ray {
-       let rd = Vec3::random_in_unit_disk() * self.lens_radius;
+    fn get_ray(&self, u: f32, v: f32, sampler: &mut Sampler) -> Ray {
+       sampler.start(Dimensions::Lens);
+       let rd = Vec3::random_in_unit_disk(sampler) * self.lens_radius;
        let offset = self.u * rd.x + self.v * rd.y;
-       Ray{
-          origin: self.origin + offset,
-          direction: self.lower_left_corner + self.horizontal * u + self.vertical * v - self.origin - offset,
-          time: self.time0 + (self.time1 - self.time0) * rand::random::<f32>()
-      }
+       let direction = self.lower_left_corner + self.horizontal * u + self.vertical * v - self.origin - offset;
+       sampler.start(Dimensions::Time);
+       let time = self.time0 + (self.time1 - self.time0) * sampler.get_1d();
+       match &self.animation {
+          Some(animation) => {
+             let matrix = animation.matrix(time);
+             Ray{
+                origin: self.origin + matrix.transform_point(offset),
+                direction: matrix.transform_vector(direction),
+                time
+             }
+          }
+          None => Ray{origin: self.origin + offset, direction, time}
+       }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 enum Material {
-    Lambertian {albedo: Vec3},
+    Lambertian {albedo: Texture},
     Metal {albedo: Vec3, fuzz: f32},
-    Dielectric {ref_idx: f32}
+    Dielectric {ref_idx: f32},
+    DiffuseLight {emit: Texture},
+    // Phase function of the volumes, scattering the same in every direction
+    Isotropic {albedo: Texture}
 }
 
 impl Material {
-    fn scatter(&self, r: &Ray, rec: &HitRecord) -> Option<(Ray, Vec3)> {
+    fn scatter(&self, r: &Ray, rec: &HitRecord, sampler: &mut Sampler) -> Option<(Ray, Vec3)> {
         match self {
             Material::Lambertian {albedo} => {
-                let scatter_direction = rec.normal + Vec3::random_unit_vector();
+                let scatter_direction = rec.normal + Vec3::random_unit_vector(sampler);
                 let scattered = Ray{origin: rec.p, direction: scatter_direction, time: r.time};
-                Some((scattered, *albedo))
+                Some((scattered, albedo.value(rec.u, rec.v, rec.p)))
             }
             Material::Metal {albedo, fuzz} => {
                 let reflected = Vec3::reflect(r.direction.unit_vector(), rec.normal);
-                let scattered = Ray{origin: rec.p, direction: reflected + Vec3::random_in_unit_sphere() * *fuzz, time: 0.0};
+                let scattered = Ray{origin: rec.p, direction: reflected + Vec3::random_in_unit_sphere(sampler) * *fuzz, time: r.time};
                 if scattered.direction.dot(rec.normal) > 0.0 {
                     Some((scattered, *albedo))
                 } else {
@@ -410,21 +637,46 @@ impl Material {
 
                 if etai_over_etat * sin_theta > 1.0 {
                     let reflected = Vec3::reflect(unit_direction, rec.normal);
-                    let scattered = Ray{origin: rec.p, direction: reflected, time: 0.0};
+                    let scattered = Ray{origin: rec.p, direction: reflected, time: r.time};
                     return Some((scattered, Vec3{x: 1.0, y: 1.0, z: 1.0}))
                 }
 
                 let reflect_prob = schlick(cos_theta, etai_over_etat);
-                if rand::random::<f32>() < reflect_prob {
+                if sampler.get_1d() < reflect_prob {
                     let reflected = Vec3::reflect(unit_direction, rec.normal);
-                    let scattered = Ray{origin: rec.p, direction: reflected, time: 0.0};
+                    let scattered = Ray{origin: rec.p, direction: reflected, time: r.time};
                     return Some((scattered, Vec3{x: 1.0, y: 1.0, z: 1.0}))
                 }
 
                 let refracted = Vec3::refract(unit_direction, rec.normal, etai_over_etat);
-                let scattered = Ray{origin: rec.p, direction: refracted, time: 0.0};
+                let scattered = Ray{origin: rec.p, direction: refracted, time: r.time};
                 Some((scattered, Vec3{x: 1.0, y: 1.0, z: 1.0}))
             }
+            Material::DiffuseLight {..} => None,
+            Material::Isotropic {albedo} => {
+                let scattered = Ray{origin: rec.p, direction: Vec3::random_unit_vector(sampler), time: r.time};
+                Some((scattered, albedo.value(rec.u, rec.v, rec.p)))
+            }
+        }
+    }
+
+    // Density of the directions picked by scatter, none for the specular materials
+    // whose directions cannot be found by sampling the lights
+    fn scattering_pdf(&self, rec: &HitRecord, direction: Vec3) -> Option<f32> {
+        match self {
+            Material::Lambertian {..} => {
+                let cosine = rec.normal.dot(direction.unit_vector());
+                Some(cosine.max(0.0) / std::f32::consts::PI)
+            }
+            Material::Isotropic {..} => Some(1.0 / (4.0 * std::f32::consts::PI)),
+            _ => None
+        }
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        match self {
+            Material::DiffuseLight {emit} => emit.value(rec.u, rec.v, rec.p),
+            _ => Vec3{x: 0.0, y: 0.0, z: 0.0}
         }
     }
 }
@@ -492,29 +744,31 @@ impl Material {
 //     World{objects: spheres}
 // }
 
-fn random_scene() -> World {
+// The layout of the spheres only depends on the seed
+fn random_scene(seed: u64) -> Scene {
+    let mut rng = StdRng::from_seed(&[seed as usize][..]);
     let mut spheres = vec![];
     spheres.push(HitableObject::Sphere( Sphere{
         center: Vec3{x: 0.0, y: - 1000.0, z: 0.0},
         radius: 1000.0,
-        material: Material::Lambertian{albedo: Vec3{x: 0.5, y: 0.5, z: 0.5}}
+        material: Material::Lambertian{albedo: Texture::Solid(Vec3{x: 0.5, y: 0.5, z: 0.5})}
     }));
 
     for a in - 11..11 {
         for b in -11..11 {
-            let choose_mat = rand::random::<f32>();
+            let choose_mat = rng.gen::<f32>();
             let center = Vec3{
-                x: a as f32 + 0.9 * rand::random::<f32>(),
+                x: a as f32 + 0.9 * rng.gen::<f32>(),
                 y: 0.2,
-                z: b as f32 + 0.9 * rand::random::<f32>()
+                z: b as f32 + 0.9 * rng.gen::<f32>()
             };
 
             if (center - Vec3{x: 4.0, y: 0.2, z: 0.0}).length() > 0.9 {
                 if choose_mat < 0.8 {
-                    let albedo = Vec3::random(0.0, 1.0) * Vec3::random(0.0, 1.0);
+                    let albedo = Vec3::random(&mut rng, 0.0, 1.0) * Vec3::random(&mut rng, 0.0, 1.0);
                     let center2 = center + Vec3{
                         x: 0.0,
-                        y: 0.5 * rand::random::<f32>(),
+                        y: 0.5 * rng.gen::<f32>(),
                         z: 0.0,
                     };
                     spheres.push(HitableObject::MovingSphere( MovingSphere{
@@ -523,19 +777,19 @@ fn random_scene() -> World {
                         time0: 0.0,
                         time1: 1.0,
                         radius: 0.2,
-                        material: Material::Lambertian{albedo: albedo}
+                        material: Material::Lambertian{albedo: Texture::Solid(albedo)}
                     }))
                 } else if choose_mat < 0.95 {
-                    let albedo = Vec3::random(0.0, 0.5);
-                    let fuzz = rand::random::<f32>() * 0.5;
+                    let albedo = Vec3::random(&mut rng, 0.0, 0.5);
+                    let fuzz = rng.gen::<f32>() * 0.5;
                     spheres.push(HitableObject::Sphere( Sphere{
-                        center: center,
+                        center,
                         radius: 0.2,
-                        material: Material::Metal{albedo: albedo, fuzz: fuzz}
+                        material: Material::Metal{albedo, fuzz}
                     }))
                 } else {
                     spheres.push(HitableObject::Sphere( Sphere{
-                        center: center,
+                        center,
                         radius: 0.2,
                         material: Material::Dielectric{ref_idx: 1.5}
                     }))
@@ -552,7 +806,7 @@ fn random_scene() -> World {
     spheres.push(HitableObject::Sphere( Sphere{
         center: Vec3{x: - 4.0, y: 1.0, z: 0.0},
         radius: 1.0,
-        material: Material::Lambertian{albedo: Vec3{x: 0.4, y: 0.2, z: 0.1}}
+        material: Material::Lambertian{albedo: Texture::Solid(Vec3{x: 0.4, y: 0.2, z: 0.1})}
     }));
     spheres.push(HitableObject::Sphere( Sphere{
         center: Vec3{x: 4.0, y: 1.0, z: 0.0},
@@ -560,88 +814,858 @@ fn random_scene() -> World {
         material: Material::Metal{albedo: Vec3{x: 0.7, y: 0.6, z: 0.4}, fuzz: 0.0}
     }));
 
-    World{objects: spheres}
+    let camera = CameraSettings {
+        lookfrom: Vec3{x: 13.0, y: 2.0, z: 3.0},
+        lookat: Vec3{x: 0.0, y: 0.0, z: 0.0},
+        vup: Vec3{x: 0.0, y: 1.0, z: 0.0},
+        vfov: 20.0,
+        aperture: 0.1,
+        focus_dist: 10.0,
+        time0: 0.0,
+        time1: 1.0,
+        animation: None
+    };
+    Scene {world: World::new(spheres), camera, background: Background::Sky, settings: RenderSettings::default()}
+}
+
+// A Wavefront OBJ model lying on a ground sphere, framed from the same direction as the random scene
+fn obj_scene(path: &str) -> Result<Scene, String> {
+    let mesh = obj::load_obj(std::path::Path::new(path), Material::Lambertian{albedo: Texture::Solid(Vec3{x: 0.7, y: 0.7, z: 0.7})})?;
+    let model_box = mesh.bounding_box();
+    let model_center = model_box.centroid();
+    let radius = (model_box.max - model_box.min).length() / 2.0;
+    let ground_radius = 2000.0 * radius;
+
+    let objects = vec![
+        HitableObject::Sphere( Sphere{
+            center: Vec3{x: model_center.x, y: model_box.min.y - ground_radius, z: model_center.z},
+            radius: ground_radius,
+            material: Material::Lambertian{albedo: Texture::Solid(Vec3{x: 0.5, y: 0.5, z: 0.5})}
+        }),
+        HitableObject::Mesh(mesh)
+    ];
+
+    let vfov: f32 = 20.0;
+    let dist_to_focus = radius / (vfov / 2.0 * std::f32::consts::PI / 180.0).sin();
+    let camera = CameraSettings {
+        lookfrom: model_center + Vec3{x: 13.0, y: 2.0, z: 3.0}.unit_vector() * dist_to_focus,
+        lookat: model_center,
+        vup: Vec3{x: 0.0, y: 1.0, z: 0.0},
+        vfov,
+        aperture: 0.0,
+        focus_dist: dist_to_focus,
+        time0: 0.0,
+        time1: 1.0,
+        animation: None
+    };
+    Ok(Scene {world: World::new(objects), camera, background: Background::Sky, settings: RenderSettings::default()})
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
 }
 
 fn main() {
     let now = SystemTime::now();
 
-    // todo : voir const si on met en maj
-    // todo voir trait object pas juste sphere
-    let aspect_ratio: f32 = 3.0 / 2.0;
-    let sample_per_pixel = 100;
-    let image_width: i32 = 400;
-    let image_height: i32 = (image_width as f32 / aspect_ratio) as i32;
-    let max_depth = 50;
-
-    println!("P3");
-    println!("{:?} {:?}", image_width, image_height);
-    println!("{:?}", 255);
-
-    let lookfrom = Vec3{x: 13.0, y: 2.0, z: 3.0};
-    let lookat = Vec3{x: 0.0, y: 0.0, z: 0.0};
-    let vup = Vec3{x: 0.0, y: 1.0, z: 0.0};
-    let dist_to_focus = 10.0;
-    let aperture = 0.1;
-    let camera = Camera::new(
-        lookfrom,
-        lookat,
-        vup,
-        20.0,
-        aspect_ratio,
-        aperture,
-        dist_to_focus,
-        0.0,
-        1.0
-     );
-
-    let world = random_scene();
-    // let mut nb_ray = &mut 0;
-
-    // for height in (0..image_height).rev() {
-    //     // eprintln!("{:?}", height);
-    //     for width in 0..image_width {
-    //         let mut pixel_color = Vec3{x: 0.0, y: 0.0, z: 0.0};
-    //         for _ in 0..sample_per_pixel {
-    //             let u: f32 = (width as f32 + rand::random::<f32>()) / (image_width as f32 - 1.);
-    //             let v: f32 = (height as f32 + rand::random::<f32>()) / (image_height as f32 - 1.);
-    //             let r: Ray = camera.get_ray(u, v);
-    //             pixel_color += ray_color(&r, &world, max_depth);
-    //         }
-    //         pixel_color.write_color(sample_per_pixel);
-    //     }
-    // }
-
-
-    let v: Vec<Vec<Vec3>> = (0..image_height)
-        .into_par_iter()
-        .rev()
-        .map(|height| (0..image_width)
-        .map(|width| {
-            let mut pixel_color = Vec3{x: 0.0, y: 0.0, z: 0.0};
-            for _ in 0..sample_per_pixel {
-                let u: f32 = (width as f32 + rand::random::<f32>()) / (image_width as f32 - 1.);
-                let v: f32 = (height as f32 + rand::random::<f32>()) / (image_height as f32 - 1.);
-                let r: Ray = camera.get_ray(u, v);
-                pixel_color += ray_color(&r, &world, max_depth);
+    let options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n\nFor more information, try --help", e);
+            std::process::exit(2);
+        }
+    };
+    if options.help {
+        println!("{}", cli::USAGE);
+        return
+    }
+    if let Some(threads) = options.threads {
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global().unwrap();
+    }
+
+    let format = options.image_format().unwrap_or_else(|e| exit_with_error(&e));
+    let sample_map_format = options.sample_map_format().unwrap_or_else(|e| exit_with_error(&e));
+    let progress_mode = options.progress.unwrap_or_else(ProgressMode::default_for_stderr);
+    if options.progressive.is_some() && options.output.is_none() && options.frames.is_none() {
+        exit_with_error("progressive rendering rewrites the image, it needs an output file");
+    }
+
+    let scene_start = Instant::now();
+    // What the scene is made of, for the checkpoints to recognize it
+    let mut scene_source = std::fs::read(&options.scene).unwrap_or_default();
+    let scene = if options.scene == "random" {
+        let seed = options.seed.unwrap_or_else(rand::random);
+        eprintln!("random scene seed : {}", seed);
+        scene_source = format!("random {}", seed).into_bytes();
+        Ok(random_scene(seed))
+    } else if options.scene.ends_with(".toml") {
+        scene::load_scene(std::path::Path::new(&options.scene))
+    } else if options.scene.ends_with(".obj") {
+        obj_scene(&options.scene)
+    } else {
+        Err(format!("unknown scene {:?}, expected \"random\", a .toml or a .obj file", options.scene))
+    };
+    let Scene {mut world, camera, mut background, mut settings} = scene.unwrap_or_else(|e| exit_with_error(&e));
+    let scene_seconds = scene_start.elapsed().as_secs_f64();
+    let assets = checkpoint::take_assets();
+    if let Some(option_background) = options.background {
+        background = option_background;
+    }
+    options.override_settings(&mut settings);
+    let mut camera = camera.camera(settings.aspect_ratio);
+    if !settings.light_sampling {
+        world.lights.clear();
+    }
+    if options.stats.is_some() {
+        stats::count_intersection_tests();
+    }
+
+    // A crop window only renders its pixels, which get the same samples as in
+    // the whole image
+    let (image_width, image_height) = image_size(&settings);
+    let region = match options.crop {
+        Some(crop) => crop.region(image_width, image_height).unwrap_or_else(|e| exit_with_error(&e)),
+        None => Tile {x0: 0, y0: 0, x1: image_width, y1: image_height},
+    };
+
+    // A sequence moves the shutter interval of the camera to each frame in turn, the
+    // static objects keeping their hierarchy from one frame to the next
+    let frames: Vec<Option<u32>> = match options.frames {
+        Some((first, last)) => (first..=last).map(Some).collect(),
+        None => vec![None],
+    };
+    for frame in frames {
+        if let Some(frame) = frame {
+            let (time0, time1) = shutter_interval(&settings, frame);
+            camera.time0 = time0;
+            camera.time1 = time1;
+        }
+        let frame_start = Instant::now();
+        let mut timings = Timings {scene: scene_seconds, ..Timings::default()};
+        let mut stats = Stats::default();
+        world.set_shutter(camera.time0, camera.time1);
+        timings.hierarchy = frame_start.elapsed().as_secs_f64();
+
+        let path = match frame {
+            Some(frame) => Some(options.frame_path(frame, format)),
+            None => options.output.clone(),
+        };
+        // The temporary file of the output is tried before rendering so that a wrong
+        // path does not waste a render, an existing image being kept until then
+        if let Some(path) = &path {
+            let temporary = temporary_path(path);
+            if let Err(e) = File::create(&temporary).and_then(|_| std::fs::remove_file(&temporary)) {
+                exit_with_error(&format!("{}: {}", path.display(), e));
             }
-            pixel_color
-        })
-        .collect())
-        .collect();
-    write_image(v, sample_per_pixel);
+        }
+
+        // Each frame of a sequence has its own noise
+        let seed = match frame {
+            Some(frame) => sampler::hash(&[settings.seed, u64::from(frame)]),
+            None => settings.seed,
+        };
+        // A resumed render starts from the pixels of its checkpoint, which were all
+        // saved at the end of a pass
+        let checkpoint_path = options.checkpoint_path(frame);
+        let fingerprint = checkpoint::fingerprint(&scene_source, &assets, &settings, &background, &camera, region, seed);
+        let mut film = match &checkpoint_path {
+            Some(checkpoint_path) if options.resume && checkpoint_path.exists() => {
+                checkpoint::read(checkpoint_path, fingerprint).unwrap_or_else(|e| exit_with_error(&e))
+            }
+            Some(checkpoint_path) if options.resume => {
+                eprintln!("no checkpoint at {}, starting from the beginning", checkpoint_path.display());
+                new_image(&settings)
+            }
+            _ => new_image(&settings),
+        };
+        film.region = region;
+        let mut samples = film.pixels.iter().map(|pixel| pixel.samples as i32).max().unwrap_or(0);
+
+        // Progressive renders rewrite the image after each pass of samples, and the
+        // checkpoint is saved after each pass
+        let pass_samples = match (options.progressive, &checkpoint_path) {
+            (Some(pass_samples), _) => pass_samples,
+            (None, Some(_)) => CHECKPOINT_SAMPLES,
+            (None, None) => settings.sample_per_pixel,
+        };
+        let progress = Progress::new(progress_mode, frame, region.pixel_count(), samples as u32, settings.sample_per_pixel.max(samples) as u32);
+        loop {
+            let pass_start = samples;
+            samples = (samples + pass_samples).min(settings.sample_per_pixel.max(samples));
+            progress.start_pass(pass_start as u32, samples as u32);
+            let render_start = Instant::now();
+            stats.merge(&render(&world, &camera, &background, &settings, seed, &mut film, samples as u32, &|tile, samples, rays| progress.tile_done(tile, samples, rays)));
+            timings.render += render_start.elapsed().as_secs_f64();
+            progress.end_pass();
+            let finished = samples >= settings.sample_per_pixel;
+            let output_start = Instant::now();
+            if let Some(checkpoint_path) = &checkpoint_path {
+                if let Err(e) = save(checkpoint_path, |output| checkpoint::write(output, fingerprint, &film)) {
+                    exit_with_error(&format!("{}: {}", checkpoint_path.display(), e));
+                }
+            }
+            timings.output += output_start.elapsed().as_secs_f64();
+            if options.progressive.is_some() || checkpoint_path.is_some() {
+                eprintln!("{} samples per pixel : {:?}", samples, now.elapsed());
+            }
+            if options.progressive.is_none() && !finished {
+                continue
+            }
+            // The crop window is written alone, unless the whole frame is asked for
+            let output_start = Instant::now();
+            let cropped;
+            let image = if options.crop.is_some() && !options.full_frame {
+                cropped = film.cropped();
+                &cropped
+            } else {
+                &film
+            };
+            let written = match &path {
+                Some(path) => save(path, |output| write_image(output, image, format)),
+                None => {
+                    let mut output = BufWriter::new(io::stdout());
+                    write_image(&mut output, image, format).and_then(|_| output.flush())
+                }
+            };
+            if let Err(e) = written {
+                exit_with_error(&format!("could not write the image : {}", e));
+            }
+            if let (Some(map_path), Some(map_format)) = (options.sample_map_path(frame), sample_map_format) {
+                if let Err(e) = save(&map_path, |output| write_sample_map(output, image, settings.sample_per_pixel as u32, map_format)) {
+                    exit_with_error(&format!("{}: {}", map_path.display(), e));
+                }
+            }
+            timings.output += output_start.elapsed().as_secs_f64();
+            if finished {
+                break
+            }
+        }
+        progress.finish();
+        if let Some(stats_path) = options.stats_path(frame) {
+            timings.total = frame_start.elapsed().as_secs_f64();
+            let (width, height) = if options.crop.is_some() && !options.full_frame {
+                (region.width(), region.height())
+            } else {
+                (film.width, film.height)
+            };
+            if let Err(e) = save(&stats_path, |output| stats::write_report(output, &stats, &timings, frame, width, height)) {
+                exit_with_error(&format!("{}: {}", stats_path.display(), e));
+            }
+        }
+        if settings.adaptive_threshold.is_some() {
+            let samples: u64 = film.pixels.iter().map(|pixel| u64::from(pixel.samples)).sum();
+            let count = region.pixel_count().max(1);
+            eprintln!("adaptive sampling : {:.1} samples per pixel on average, out of {}", samples as f64 / count as f64, settings.sample_per_pixel);
+        }
+        if let (Some(_), Some(path)) = (frame, &path) {
+            eprintln!("{} : {:?}", path.display(), now.elapsed());
+        }
+    }
 
     eprintln!("{:?}", now.elapsed());
 }
 
-fn write_image(image: Vec<Vec<Vec3>>, sample_per_pixel: i32) {
-    for col in image {
-        for mut pixel in col {
-            pixel /= sample_per_pixel as f32;
-            let ir = (256.0 * clamp(pixel.x.sqrt(), 0.0, 0.999)) as i32;
-            let ig = (256.0 * clamp(pixel.y.sqrt(), 0.0, 0.999)) as i32;
-            let ib = (256.0 * clamp(pixel.z.sqrt(), 0.0, 0.999)) as i32;
-            println!("{:?} {:?} {:?}", ir, ig, ib);
+// Samples per pixel between two checkpoints, when the passes are not given
+const CHECKPOINT_SAMPLES: i32 = 16;
+
+// Adds samples to the pixels of the region of the film, tile by tile, until each one has `samples` samples
+// or is stopped by adaptive sampling. Each thread takes the next tile in the order
+// of the settings once it is done with one, then tells `on_tile` about it and
+// the numbers of samples and rays it took. Returns what the threads counted. A
+// sample only depends on `seed`, its pixel and its index, so an image rendered in
+// several passes is the same as in a single one, however the tiles are spread
+// over the threads
+#[allow(clippy::too_many_arguments)]
+fn render(world: &World, camera: &Camera, background: &Background, settings: &RenderSettings, seed: u64, film: &mut Film, samples: u32, on_tile: &(dyn Fn(Tile, u64, u64) + Sync)) -> Stats {
+    let image_width = film.width;
+    let image_height = film.height;
+    let sample_per_pixel = settings.sample_per_pixel;
+    let max_depth = settings.max_depth;
+
+    // Adaptive sampling stops the pixels whose error is low enough
+    let converged = |pixel: &Pixel| settings.adaptive_threshold.is_some_and(|threshold| {
+        pixel.samples >= settings.min_samples as u32 && pixel.error() < threshold
+    });
+    let tiles = tile::tiles(film.region, settings.tile_size as usize, settings.tile_order);
+    let next_tile = AtomicUsize::new(0);
+    // The film is only locked to copy the pixels of a tile in and out
+    let film = Mutex::new(film);
+    let stats = Mutex::new(Stats::default());
+    rayon::scope(|scope| {
+        for _ in 0..rayon::current_num_threads() {
+            scope.spawn(|_| {
+                let mut sampler = Sampler::new(settings.sampler, seed, sample_per_pixel as u32);
+                let mut thread_stats = Stats::default();
+                Stats::reset_thread();
+                while let Some(&tile) = tiles.get(next_tile.fetch_add(1, Ordering::Relaxed)) {
+                    let mut pixels = film.lock().unwrap().tile(tile);
+                    let mut tile_samples = 0;
+                    let tile_rays = stats::get(Counter::PathRays) + stats::get(Counter::ShadowRays);
+                    for (i, pixel) in pixels.iter_mut().enumerate() {
+                        let width = tile.x0 + i % tile.width();
+                        let height = image_height - 1 - (tile.y0 + i / tile.width());
+                        while pixel.samples < samples && !converged(pixel) {
+                            sampler.start_sample(width as u32, height as u32, pixel.samples);
+                            let (du, dv) = sampler.get_2d();
+                            let u: f32 = (width as f32 + du) / (image_width as f32 - 1.);
+                            let v: f32 = (height as f32 + dv) / (image_height as f32 - 1.);
+                            let r: Ray = camera.get_ray(u, v, &mut sampler);
+                            let path_start = stats::get(Counter::PathRays);
+                            pixel.add(ray_color(&r, world, background, max_depth, None, &mut sampler));
+                            thread_stats.add_path(stats::get(Counter::PathRays) - path_start);
+                            tile_samples += 1;
+                        }
+                    }
+                    film.lock().unwrap().set_tile(tile, &pixels);
+                    on_tile(tile, tile_samples, stats::get(Counter::PathRays) + stats::get(Counter::ShadowRays) - tile_rays);
+                }
+                thread_stats.add_thread_counts();
+                stats.lock().unwrap().merge(&thread_stats);
+            });
+        }
+    });
+    stats.into_inner().unwrap()
+}
+
+// Times at which the shutter opens and closes for a frame of a sequence
+fn shutter_interval(settings: &RenderSettings, frame: u32) -> (f32, f32) {
+    let time0 = frame as f32 / settings.fps;
+    (time0, time0 + settings.shutter / settings.fps)
+}
+
+fn image_size(settings: &RenderSettings) -> (usize, usize) {
+    let image_height = (settings.image_width as f32 / settings.aspect_ratio) as usize;
+    (settings.image_width as usize, image_height)
+}
+
+// Pixels without any sample yet, at the size of the settings
+fn new_image(settings: &RenderSettings) -> Film {
+    let (image_width, image_height) = image_size(settings);
+    Film::new(image_width, image_height)
+}
+
+fn write_image(output: &mut dyn Write, film: &Film, format: ImageFormat) -> io::Result<()> {
+    let (width, height) = (film.width, film.height);
+    if format.is_high_dynamic_range() {
+        let radiance: Vec<f32> = film
+            .pixels
+            .iter()
+            .flat_map(|pixel| {
+                let color = pixel.color();
+                [color.x, color.y, color.z]
+            })
+            .collect();
+        return image::write_hdr(output, format, width, height, &radiance);
+    }
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for pixel in &film.pixels {
+        let pixel = pixel.color();
+        rgb.push((256.0 * clamp(pixel.x.sqrt(), 0.0, 0.999)) as u8);
+        rgb.push((256.0 * clamp(pixel.y.sqrt(), 0.0, 0.999)) as u8);
+        rgb.push((256.0 * clamp(pixel.z.sqrt(), 0.0, 0.999)) as u8);
+    }
+    image::write(output, format, width, height, &rgb)
+}
+
+// Writes a file in one go : it is written next to its place then renamed, so that
+// viewers, or a render stopped while writing, never see half of an image
+fn save<F: FnOnce(&mut dyn Write) -> io::Result<()>>(path: &std::path::Path, write: F) -> io::Result<()> {
+    let temporary = temporary_path(path);
+    {
+        let mut output = BufWriter::new(File::create(&temporary)?);
+        write(&mut output)?;
+        output.flush()?;
+    }
+    std::fs::rename(&temporary, path)
+}
+
+fn temporary_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::path::PathBuf::from(temporary)
+}
+
+// Number of samples of each pixel : grey levels from black for no sample to white
+// for `max_samples`, or the counts themselves in the high dynamic range formats
+fn write_sample_map(output: &mut dyn Write, film: &Film, max_samples: u32, format: ImageFormat) -> io::Result<()> {
+    let (width, height) = (film.width, film.height);
+    let counts = film.pixels.iter().map(|pixel| pixel.samples as f32);
+    if format.is_high_dynamic_range() {
+        let values: Vec<f32> = counts.flat_map(|count| [count, count, count]).collect();
+        return image::write_hdr(output, format, width, height, &values);
+    }
+    let levels: Vec<u8> = counts
+        .flat_map(|count| {
+            let level = (255.0 * count / max_samples.max(1) as f32).round().min(255.0) as u8;
+            [level, level, level]
+        })
+        .collect();
+    image::write(output, format, width, height, &levels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampler::SamplerKind;
+
+    fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3{x, y, z}
+    }
+
+    #[test]
+    fn scattered_rays_keep_the_time() {
+        let materials = [
+            Material::Lambertian {albedo: Texture::Solid(vec3(0.5, 0.5, 0.5))},
+            Material::Metal {albedo: vec3(0.8, 0.8, 0.8), fuzz: 0.3},
+            Material::Dielectric {ref_idx: 1.5},
+            Material::Isotropic {albedo: Texture::Solid(vec3(0.5, 0.5, 0.5))}
+        ];
+        let mut sampler = Sampler::new(SamplerKind::Independent, 0, 1);
+        for material in materials.iter() {
+            for _ in 0..100 {
+                let r = Ray{origin: vec3(0.0, 0.0, 1.0), direction: vec3(0.3, 0.1, - 1.0), time: 0.7};
+                let normal = vec3(0.0, 0.0, 1.0);
+                let rec = HitRecord{p: vec3(0.3, 0.1, 0.0), normal, t: 1.0, front_face: true, material, u: 0.0, v: 0.0};
+                if let Some((scattered, _)) = material.scatter(&r, &rec, &mut sampler) {
+                    assert_eq!(scattered.time, 0.7, "{:?}", material);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn only_lights_emit_and_they_do_not_scatter() {
+        let normal = vec3(0.0, 0.0, 1.0);
+        let light = Material::DiffuseLight {emit: Texture::Solid(vec3(2.0, 3.0, 4.0))};
+        let grey = Material::Lambertian {albedo: Texture::Solid(vec3(0.5, 0.5, 0.5))};
+        let r = Ray{origin: vec3(0.0, 0.0, 1.0), direction: vec3(0.0, 0.0, - 1.0), time: 0.0};
+        let mut sampler = Sampler::new(SamplerKind::Independent, 0, 1);
+        let rec = HitRecord{p: vec3(0.0, 0.0, 0.0), normal, t: 1.0, front_face: true, material: &light, u: 0.0, v: 0.0};
+        let emitted = light.emitted(&rec);
+        assert_eq!([emitted.x, emitted.y, emitted.z], [2.0, 3.0, 4.0]);
+        assert!(light.scatter(&r, &rec, &mut sampler).is_none());
+        let emitted = grey.emitted(&HitRecord {material: &grey, ..rec});
+        assert_eq!([emitted.x, emitted.y, emitted.z], [0.0, 0.0, 0.0]);
+
+        // A camera ray hitting a light brings back its emission, whatever the background
+        let world = World::new(vec![HitableObject::Sphere(Sphere {center: vec3(0.0, 0.0, - 5.0), radius: 1.0, material: light})]);
+        for background in [Background::Sky, Background::Color(vec3(1.0, 1.0, 1.0))].iter() {
+            let color = ray_color(&r, &world, background, 10, None, &mut sampler);
+            assert_eq!([color.x, color.y, color.z], [2.0, 3.0, 4.0]);
+        }
+    }
+
+    #[test]
+    fn escaping_rays_bring_back_the_background() {
+        let ray = |y| Ray{origin: vec3(0.0, 0.0, 0.0), direction: vec3(0.0, y, - 1.0), time: 0.0};
+        let sky = |y| {
+            let color = Background::Sky.color(&ray(y));
+            [color.x, color.y, color.z]
+        };
+        // White at the horizon towards the bottom, blue towards the top
+        assert_eq!(sky(1e6), [0.5, 0.7, 1.0]);
+        assert_eq!(sky(- 1e6), [1.0, 1.0, 1.0]);
+        assert_eq!(sky(0.0), [0.75, 0.85, 1.0]);
+        let color = Background::Color(vec3(0.1, 0.2, 0.3)).color(&ray(0.5));
+        assert_eq!([color.x, color.y, color.z], [0.1, 0.2, 0.3]);
+
+        let empty = World::new(vec![]);
+        let mut sampler = Sampler::new(SamplerKind::Independent, 0, 1);
+        let color = ray_color(&ray(0.0), &empty, &Background::Sky, 10, None, &mut sampler);
+        assert_eq!([color.x, color.y, color.z], sky(0.0));
+    }
+
+    // Brightness of each column of a small render of a light moving from left to
+    // right during the shutter interval, optionally behind a pane of glass
+    fn motion_blur_profile(glass: bool) -> Vec<f32> {
+        let mut objects = vec![HitableObject::MovingSphere(MovingSphere {
+            center0: vec3(- 1.5, 0.0, - 6.0),
+            center1: vec3(1.5, 0.0, - 6.0),
+            time0: 0.0,
+            time1: 1.0,
+            radius: 0.5,
+            material: Material::DiffuseLight {emit: Texture::Solid(vec3(1.0, 1.0, 1.0))}
+        })];
+        if glass {
+            objects.push(HitableObject::Box(Cuboid {
+                min: vec3(- 10.0, - 10.0, - 3.1),
+                max: vec3(10.0, 10.0, - 3.0),
+                material: Material::Dielectric {ref_idx: 1.5}
+            }));
+        }
+        let world = World::new(objects);
+        let camera = Camera::new(vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, - 1.0), vec3(0.0, 1.0, 0.0), 40.0, 2.0, 0.0, 1.0, 0.0, 1.0);
+        let background = Background::Color(vec3(0.0, 0.0, 0.0));
+        let (width, height, samples) = (64, 32, 32);
+        let mut sampler = Sampler::new(SamplerKind::Independent, 0, samples);
+        (0..width).map(|i| {
+            let mut total = 0.0;
+            for j in 0..height {
+                for sample in 0..samples {
+                    sampler.start_sample(i, j, sample);
+                    let (du, dv) = sampler.get_2d();
+                    let u = (i as f32 + du) / width as f32;
+                    let v = (j as f32 + dv) / height as f32;
+                    let r = camera.get_ray(u, v, &mut sampler);
+                    total += ray_color(&r, &world, &background, 10, None, &mut sampler).x;
+                }
+            }
+            total / (height * samples) as f32
+        }).collect()
+    }
+
+    // Total, mean column and standard deviation of a profile
+    fn moments(profile: &[f32]) -> (f32, f32, f32) {
+        let total: f32 = profile.iter().sum();
+        let mean = profile.iter().enumerate().map(|(i, w)| i as f32 * w).sum::<f32>() / total;
+        let variance = profile.iter().enumerate().map(|(i, w)| (i as f32 - mean).powi(2) * w).sum::<f32>() / total;
+        (total, mean, variance.sqrt())
+    }
+
+    // The glass barely moves the rays, so the streak left by the light must look
+    // the same through it. Rays losing their time at the glass would only see the
+    // light where it is at the start of the interval
+    #[test]
+    fn motion_blur_is_the_same_through_glass() {
+        let (direct_total, direct_mean, direct_spread) = moments(&motion_blur_profile(false));
+        let (glass_total, glass_mean, glass_spread) = moments(&motion_blur_profile(true));
+
+        assert!((direct_mean - 31.5).abs() < 1.0, "streak centred on column {}", direct_mean);
+        assert!((glass_mean - direct_mean).abs() < 1.0, "streak centred on column {} through glass, {} without", glass_mean, direct_mean);
+        assert!((glass_spread / direct_spread - 1.0).abs() < 0.1, "streak spread of {} columns through glass, {} without", glass_spread, direct_spread);
+        // Only the reflections on both sides of the pane are lost, a few percents
+        assert!((glass_total / direct_total - 0.92).abs() < 0.12, "brightness of {} through glass, {} without", glass_total, direct_total);
+    }
+
+    // Both hierarchies, of the static and of the moving spheres, find the same
+    // closest hit as testing every object of the random scene
+    #[test]
+    fn hierarchies_find_the_closest_hit() {
+        let mut world = random_scene(7).world;
+        world.set_shutter(0.0, 1.0);
+        let mut rng = StdRng::from_seed(&[11][..]);
+        let mut sampler = Sampler::new(SamplerKind::Independent, 0, 1);
+        let mut hits = 0;
+        for _ in 0..20000 {
+            let r = Ray {
+                origin: vec3(rng.gen_range(- 13.0, 13.0), rng.gen_range(0.0, 4.0), rng.gen_range(- 13.0, 13.0)),
+                direction: vec3(rng.gen_range(- 1.0, 1.0), rng.gen_range(- 1.0, 1.0), rng.gen_range(- 1.0, 1.0)),
+                time: rng.gen()
+            };
+            let mut closest_so_far = 1000000.0;
+            let mut expected = None;
+            for object in world.objects.iter() {
+                if let Some(rec) = object.hit(&r, 0.001, closest_so_far, &mut sampler) {
+                    closest_so_far = rec.t;
+                    expected = Some(rec.t);
+                }
+            }
+            assert_eq!(world.hit(&r, 0.001, 1000000.0, &mut sampler).map(|rec| rec.t), expected);
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 5000);
+    }
+
+    // The collisions in the volumes come from the samples : the same ray goes
+    // through a fog to other places in other samples and with other seeds, the
+    // mean free path away on average
+    #[test]
+    fn volumes_draw_their_collisions_from_the_samples() {
+        let fog = HitableObject::ConstantMedium(ConstantMedium {
+            boundary: std::sync::Arc::new(HitableObject::Sphere(Sphere {center: vec3(0.0, 0.0, 0.0), radius: 1000.0, material: Material::Dielectric {ref_idx: 1.0}})),
+            density: 0.5,
+            phase_function: Material::Isotropic {albedo: Texture::Solid(vec3(0.5, 0.5, 0.5))}
+        });
+        let r = Ray {origin: vec3(0.0, 0.0, 0.0), direction: vec3(0.0, 0.0, 1.0), time: 0.0};
+        let distances = |kind, seed| -> Vec<f32> {
+            let mut sampler = Sampler::new(kind, seed, 256);
+            (0..256).map(|sample| {
+                sampler.start_sample(0, 0, sample);
+                sampler.start(Dimensions::Medium);
+                fog.hit(&r, 0.001, 1000000.0, &mut sampler).unwrap().t
+            }).collect()
+        };
+        for &sampler in SAMPLERS.iter() {
+            let distances_of_seed_1 = distances(sampler, 1);
+            assert!(distances_of_seed_1 != distances(sampler, 2), "the collisions did not change with the seed ({:?})", sampler);
+            let mean = distances_of_seed_1.iter().sum::<f32>() / 256.0;
+            assert!((mean - 2.0).abs() < 0.3, "mean distance of {} to the collisions ({:?})", mean, sampler);
+        }
+    }
+
+    // A small render of the random scene laid out by `layout`
+    struct Fixture {
+        scene: Scene,
+        camera: Camera,
+        settings: RenderSettings
+    }
+
+    impl Fixture {
+        fn new(layout: u64, settings: RenderSettings) -> Fixture {
+            let scene = random_scene(layout);
+            let camera = scene.camera.camera(settings.aspect_ratio);
+            Fixture {scene, camera, settings}
+        }
+
+        // A small render of a scene file, its lights sampled or not
+        fn load(path: &str, light_sampling: bool, settings: RenderSettings) -> Fixture {
+            let mut scene = scene::load_scene(std::path::Path::new(path)).unwrap();
+            if !light_sampling {
+                scene.world.lights.clear();
+            }
+            let camera = scene.camera.camera(settings.aspect_ratio);
+            Fixture {scene, camera, settings}
+        }
+
+        // Adds the samples of `seed` to the film, up to `samples` per pixel
+        fn render(&self, film: &mut Film, seed: u64, samples: u32) -> Stats {
+            render(&self.scene.world, &self.camera, &self.scene.background, &self.settings, seed, film, samples, &|_, _, _| ())
+        }
+
+        // A whole image rendered on `threads` threads
+        fn image(&self, threads: usize, seed: u64, samples: u32) -> (Film, Stats) {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let mut film = new_image(&self.settings);
+            let stats = pool.install(|| self.render(&mut film, seed, samples));
+            (film, stats)
         }
     }
+
+    fn same_pixels(a: &Film, b: &Film) -> bool {
+        a.pixels.len() == b.pixels.len() && a.pixels.iter().zip(&b.pixels).all(|(a, b)| a.to_bytes()[..] == b.to_bytes()[..])
+    }
+
+    const SAMPLERS: [SamplerKind; 4] = [SamplerKind::Independent, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol];
+
+    // The random numbers of a sample only depend on the seed, the pixel and the
+    // sample index, so the threads rendering the tiles do not change the image
+    #[test]
+    fn renders_do_not_depend_on_the_threads() {
+        for &sampler in SAMPLERS.iter() {
+            let fixture = Fixture::new(7, RenderSettings {image_width: 48, sample_per_pixel: 4, sampler, ..RenderSettings::default()});
+            let (image, _) = fixture.image(1, 3, 4);
+            assert!(same_pixels(&image, &fixture.image(4, 3, 4).0), "the image changed with the number of threads ({:?})", sampler);
+            assert!(same_pixels(&image, &fixture.image(3, 3, 4).0), "the image changed with the number of threads ({:?})", sampler);
+        }
+    }
+
+    #[test]
+    fn the_seed_changes_the_samples() {
+        for &sampler in SAMPLERS.iter() {
+            let fixture = Fixture::new(7, RenderSettings {image_width: 48, sample_per_pixel: 4, sampler, ..RenderSettings::default()});
+            assert!(!same_pixels(&fixture.image(4, 3, 4).0, &fixture.image(4, 5, 4).0), "the image did not change with the seed ({:?})", sampler);
+        }
+    }
+
+    #[test]
+    fn frames_open_the_shutter_at_their_time() {
+        let settings = RenderSettings {fps: 25.0, shutter: 0.5, ..RenderSettings::default()};
+        assert_eq!(shutter_interval(&settings, 0), (0.0, 0.02));
+        assert_eq!(shutter_interval(&settings, 50), (2.0, 2.02));
+        // A closed shutter takes the frame at a single instant
+        let settings = RenderSettings {fps: 10.0, shutter: 0.0, ..RenderSettings::default()};
+        assert_eq!(shutter_interval(&settings, 3), (0.3, 0.3));
+    }
+
+    #[test]
+    fn frames_are_written_to_numbered_files() {
+        let options = cli::parse_args(["--frames", "8-10", "-o", "out/shot_###.exr"].iter().map(|arg| arg.to_string())).unwrap();
+        let format = options.image_format().unwrap();
+        let (first, last) = options.frames.unwrap();
+        let paths: Vec<_> = (first..=last).map(|frame| options.frame_path(frame, format)).collect();
+        let expected: Vec<_> = ["out/shot_008.exr", "out/shot_009.exr", "out/shot_010.exr"].iter().map(std::path::PathBuf::from).collect();
+        assert_eq!(paths, expected);
+        // Without an output, sequences are written as PNG files
+        let options = cli::parse_args(["--frames", "2"].iter().map(|arg| arg.to_string())).unwrap();
+        let format = options.image_format().unwrap();
+        assert_eq!(options.frame_path(2, format), std::path::PathBuf::from("frame_0002.png"));
+    }
+
+    // Adaptive sampling renders of the random scene, or of an empty scene in front
+    // of a uniform background
+    fn adaptive(constant: bool, adaptive_threshold: Option<f32>) -> Film {
+        let mut fixture = Fixture::new(7, RenderSettings {image_width: 24, sample_per_pixel: 64, min_samples: 8, adaptive_threshold, ..RenderSettings::default()});
+        if constant {
+            fixture.scene.world = World::new(vec![]);
+            fixture.scene.background = Background::Color(vec3(0.3, 0.5, 0.7));
+        }
+        fixture.image(2, 3, 64).0
+    }
+
+    #[test]
+    fn constant_pixels_stop_at_the_minimum() {
+        let film = adaptive(true, Some(0.01));
+        assert!(film.pixels.iter().all(|pixel| pixel.samples == 8));
+    }
+
+    #[test]
+    fn noisy_pixels_go_on_to_the_maximum() {
+        let film = adaptive(false, Some(1e-4));
+        assert!(film.pixels.iter().all(|pixel| pixel.samples == 64 || (pixel.samples >= 8 && pixel.error() < 1e-4)));
+        assert!(film.pixels.iter().any(|pixel| pixel.samples == 64));
+    }
+
+    #[test]
+    fn without_a_threshold_every_pixel_gets_every_sample() {
+        for &constant in [true, false].iter() {
+            assert!(adaptive(constant, None).pixels.iter().all(|pixel| pixel.samples == 64));
+        }
+    }
+
+    // The map of the samples goes from black for none to white for the maximum
+    #[test]
+    fn the_sample_map_scales_the_counts() {
+        let mut film = Film::new(3, 1);
+        for (pixel, &samples) in film.pixels.iter_mut().zip([0, 8, 16].iter()) {
+            for _ in 0..samples {
+                pixel.add(vec3(0.5, 0.5, 0.5));
+            }
+        }
+        let mut data = vec![];
+        write_sample_map(&mut data, &film, 16, image::ImageFormat::PpmBinary).unwrap();
+        assert!(data.ends_with(&[0, 0, 0, 128, 128, 128, 255, 255, 255]));
+    }
+
+    // The pixels of a crop window are the same as in the whole image, and the
+    // others are left black
+    #[test]
+    fn crop_windows_match_the_whole_image() {
+        let fixture = Fixture::new(3, RenderSettings {image_width: 60, sample_per_pixel: 4, sampler: SamplerKind::Sobol, tile_size: 8, ..RenderSettings::default()});
+        let (whole, _) = fixture.image(4, 0, 4);
+        let mut window = new_image(&fixture.settings);
+        window.region = cli::Crop::Fractions([0.3, 0.1, 0.75, 0.5]).region(window.width, window.height).unwrap();
+        assert_eq!(window.region, Tile {x0: 18, y0: 4, x1: 45, y1: 20});
+        fixture.render(&mut window, 0, 4);
+        for y in 0..window.height {
+            for x in 0..window.width {
+                let (a, b) = (whole.pixels[y * window.width + x], window.pixels[y * window.width + x]);
+                if (18..45).contains(&x) && (4..20).contains(&y) {
+                    assert_eq!(a.to_bytes()[..], b.to_bytes()[..], "pixel {},{}", x, y);
+                } else {
+                    assert_eq!(b.samples, 0, "pixel {},{} outside of the window", x, y);
+                }
+            }
+        }
+        let cropped = window.cropped();
+        assert_eq!((cropped.width, cropped.height), (27, 16));
+        assert_eq!(cropped.pixels[0].to_bytes()[..], whole.pixels[4 * 60 + 18].to_bytes()[..]);
+    }
+
+    // Sampling the lights only lowers the noise : both estimators converge to
+    // the same brightness
+    #[test]
+    fn light_sampling_keeps_the_mean_radiance() {
+        let mean_radiance = |film: &Film| -> f32 {
+            let sum: Vec3 = film.pixels.iter().fold(vec3(0.0, 0.0, 0.0), |sum, pixel| sum + pixel.color());
+            (sum.x + sum.y + sum.z) / (3 * film.pixels.len()) as f32
+        };
+        for &(path, aspect_ratio) in &[("scenes/cornell_box.toml", 1.0), ("scenes/sphere_light.toml", 1.5)] {
+            let settings = || RenderSettings {image_width: 24, aspect_ratio, sample_per_pixel: 128, max_depth: 10, sampler: SamplerKind::Sobol, ..RenderSettings::default()};
+            let sampled = mean_radiance(&Fixture::load(path, true, settings()).image(4, 0, 128).0);
+            let scattered = mean_radiance(&Fixture::load(path, false, settings()).image(4, 0, 128).0);
+            assert!((sampled / scattered - 1.0).abs() < 0.03, "{} : {} with light sampling, {} without", path, sampled, scattered);
+        }
+    }
+
+    // Every sample starts a path, whose rays are all counted
+    #[test]
+    fn statistics_add_up() {
+        stats::count_intersection_tests();
+        let fixture = Fixture::new(9, RenderSettings {image_width: 30, sample_per_pixel: 3, max_depth: 6, ..RenderSettings::default()});
+        let (film, stats) = fixture.image(3, 0, 3);
+        assert_eq!(stats.camera_rays, film.pixels.iter().map(|pixel| u64::from(pixel.samples)).sum::<u64>());
+        assert_eq!(stats.path_lengths.iter().sum::<u64>(), stats.camera_rays);
+        assert_eq!(stats.path_lengths.iter().enumerate().map(|(rays, &paths)| rays as u64 * paths).sum::<u64>(), stats.path_rays);
+        assert!(stats.path_lengths.len() <= 7 && stats.path_lengths[0] == 0);
+        // The paths cut by max_depth have max_depth rays, the others may have as many
+        assert!(stats.max_depth_paths > 0 && stats.max_depth_paths <= stats.path_lengths[6]);
+        // Each ray tests the root of the hierarchy at least, and the sky is no light
+        assert!(stats.box_tests >= stats.path_rays && stats.primitive_tests > 0 && stats.shadow_rays == 0);
+    }
+
+    #[test]
+    fn statistics_do_not_depend_on_the_threads() {
+        stats::count_intersection_tests();
+        let fixture = Fixture::new(9, RenderSettings {image_width: 30, sample_per_pixel: 3, max_depth: 6, ..RenderSettings::default()});
+        assert!(fixture.image(3, 0, 3).1 == fixture.image(1, 0, 3).1, "the statistics changed with the number of threads");
+    }
+
+    // Passes only add the next samples of each pixel, adaptive sampling included
+    #[test]
+    fn passes_give_the_same_image_as_a_single_render() {
+        let fixture = Fixture::new(11, RenderSettings {image_width: 40, sample_per_pixel: 24, adaptive_threshold: Some(0.05), min_samples: 4, sampler: SamplerKind::Sobol, ..RenderSettings::default()});
+        let (single, _) = fixture.image(4, 0, 24);
+        let mut passes = new_image(&fixture.settings);
+        for &samples in [5, 10, 24].iter() {
+            fixture.render(&mut passes, 0, samples);
+        }
+        assert!(same_pixels(&single, &passes));
+        assert!(single.pixels.iter().any(|pixel| pixel.samples < 24), "no pixel was stopped by adaptive sampling");
+    }
+
+    // Writes the pixels of a film to a checkpoint and reads them back
+    fn checkpoint_round_trip(film: &Film, written: u64, read: u64) -> Result<Film, String> {
+        let path = std::env::temp_dir().join(format!("ray_tracer_test_{}_{}.checkpoint", std::process::id(), written));
+        save(&path, |output| checkpoint::write(output, written, film)).unwrap();
+        let resumed = checkpoint::read(&path, read);
+        std::fs::remove_file(&path).unwrap();
+        resumed
+    }
+
+    // A render carried on from a checkpoint ends as if it had never stopped
+    #[test]
+    fn resumed_renders_match_uninterrupted_ones() {
+        let fixture = Fixture::new(5, RenderSettings {image_width: 32, sample_per_pixel: 12, adaptive_threshold: Some(0.05), min_samples: 4, sampler: SamplerKind::Halton, ..RenderSettings::default()});
+        let (uninterrupted, _) = fixture.image(4, 1, 12);
+        let (stopped, _) = fixture.image(4, 1, 5);
+        let mut resumed = checkpoint_round_trip(&stopped, 42, 42).unwrap();
+        fixture.render(&mut resumed, 1, 12);
+        assert!(same_pixels(&uninterrupted, &resumed));
+    }
+
+    #[test]
+    fn checkpoints_of_other_settings_are_refused() {
+        let fixture = Fixture::new(5, RenderSettings {image_width: 8, sample_per_pixel: 2, ..RenderSettings::default()});
+        let (stopped, _) = fixture.image(1, 1, 1);
+        assert!(checkpoint_round_trip(&stopped, 43, 44).is_err(), "a checkpoint of other settings was resumed");
+    }
+
+    // The strata of the stratified sampler depend on the number of samples, which
+    // the other samplers can raise when resuming
+    #[test]
+    fn only_stratified_checkpoints_keep_their_number_of_samples() {
+        for &sampler in SAMPLERS.iter() {
+            let fingerprint = |sample_per_pixel| {
+                let fixture = Fixture::new(5, RenderSettings {image_width: 8, sample_per_pixel, sampler, ..RenderSettings::default()});
+                let film = new_image(&fixture.settings);
+                checkpoint::fingerprint(b"", &[], &fixture.settings, &fixture.scene.background, &fixture.camera, film.region, 1)
+            };
+            assert_eq!(fingerprint(16) != fingerprint(32), sampler == SamplerKind::Stratified, "{:?}", sampler);
+        }
+    }
+
+    // The files used by a scene are part of what its checkpoints depend on
+    #[test]
+    fn checkpoints_of_changed_assets_are_refused() {
+        let directory = std::env::temp_dir().join(format!("ray_tracer_test_{}_assets", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let scene_path = directory.join("scene.toml");
+        std::fs::write(&scene_path, "[render]\nimage_width = 8\n\n[camera]\nlookfrom = [0.0, 0.0, 5.0]\nlookat = [0.0, 0.0, 0.0]\nvfov = 40.0\n\n\
+            [[objects]]\ntype = \"grid_medium\"\npath = \"grid.raw\"\nresolution = [2, 1, 1]\nencoding = \"u8\"\nmin = [-1.0, -1.0, -1.0]\nmax = [1.0, 1.0, 1.0]\n").unwrap();
+        let fingerprint = |grid: &[u8]| {
+            std::fs::write(directory.join("grid.raw"), grid).unwrap();
+            let scene = scene::load_scene(&scene_path).unwrap();
+            let assets = checkpoint::take_assets();
+            let camera = scene.camera.camera(scene.settings.aspect_ratio);
+            let film = new_image(&scene.settings);
+            checkpoint::fingerprint(b"scene", &assets, &scene.settings, &scene.background, &camera, film.region, 1)
+        };
+        let first = fingerprint(&[10, 200]);
+        let same = fingerprint(&[10, 200]);
+        let changed = fingerprint(&[10, 201]);
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(first, same);
+        assert_ne!(first, changed);
+    }
 }
@@ -0,0 +1,350 @@
+use std::sync::Arc;
+
+use crate::bvh::Aabb;
+use crate::sampler::Sampler;
+use crate::voxel::VoxelGrid;
+use crate::{HitRecord, Hitable, HitableObject, Material, Ray, Vec3, World};
+
+// Volume of constant density filling a boundary, such as smoke or fog. Rays
+// entering it travel an exponentially distributed distance before scattering
+// with the phase function, which should be an isotropic material. The boundary
+// must be convex : only its first entry and exit points are looked for. Inside a
+// scaling instance, the density is per unit of length of the object space
+pub struct ConstantMedium {
+    pub boundary: Arc<HitableObject>,
+    pub density: f32,
+    pub phase_function: Material,
+}
+
+impl Hitable for ConstantMedium {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, sampler: &mut Sampler) -> Option<HitRecord<'_>> {
+        // The boundary is looked for along the whole line, as the ray can start inside it
+        let enter = self
+            .boundary
+            .hit(r, f32::NEG_INFINITY, f32::INFINITY, sampler)?
+            .t;
+        let exit = self
+            .boundary
+            .hit(r, enter + 0.0001, f32::INFINITY, sampler)?
+            .t;
+        let enter = enter.max(t_min);
+        let exit = exit.min(t_max);
+        if enter >= exit {
+            return None;
+        }
+
+        let ray_length = r.direction.length();
+        let distance_inside = (exit - enter) * ray_length;
+        let hit_distance = -(1.0 - sampler.get_1d()).ln() / self.density;
+        if hit_distance > distance_inside {
+            return None;
+        }
+
+        let t = enter + hit_distance / ray_length;
+        Some(HitRecord {
+            t,
+            p: r.at(t),
+            // Arbitrary, the phase function does not depend on it
+            normal: Vec3 {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            front_face: true,
+            material: &self.phase_function,
+            u: 0.0,
+            v: 0.0,
+        })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.boundary.bounding_box()
+    }
+}
+
+// Volume whose density comes from a voxel grid stretched over a box. Collisions
+// are found by delta tracking : tentative collisions are drawn as in a constant
+// medium of the maximum density, and each one is real with the ratio of the
+// local density to that maximum
+pub struct GridMedium {
+    pub grid: Arc<VoxelGrid>,
+    pub bounds: Aabb,
+    pub density_scale: f32,
+    pub phase_function: Material,
+    // Maximum of the scaled densities
+    majorant: f32,
+}
+
+impl GridMedium {
+    pub fn new(
+        grid: Arc<VoxelGrid>,
+        bounds: Aabb,
+        density_scale: f32,
+        phase_function: Material,
+    ) -> GridMedium {
+        GridMedium {
+            majorant: grid.max_density() * density_scale,
+            grid,
+            bounds,
+            density_scale,
+            phase_function,
+        }
+    }
+
+    fn density(&self, p: Vec3) -> f32 {
+        let size = self.bounds.max - self.bounds.min;
+        let local = p - self.bounds.min;
+        self.density_scale
+            * self.grid.density(Vec3 {
+                x: local.x / size.x,
+                y: local.y / size.y,
+                z: local.z / size.z,
+            })
+    }
+
+    // Part of [t_min, t_max] where the ray is inside the box
+    fn clip(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        let mut enter = t_min;
+        let mut exit = t_max;
+        let axes = [
+            (
+                r.origin.x,
+                r.direction.x,
+                self.bounds.min.x,
+                self.bounds.max.x,
+            ),
+            (
+                r.origin.y,
+                r.direction.y,
+                self.bounds.min.y,
+                self.bounds.max.y,
+            ),
+            (
+                r.origin.z,
+                r.direction.z,
+                self.bounds.min.z,
+                self.bounds.max.z,
+            ),
+        ];
+        for &(origin, direction, min, max) in axes.iter() {
+            let inv_d = 1.0 / direction;
+            let t0 = (min - origin) * inv_d;
+            let t1 = (max - origin) * inv_d;
+            enter = enter.max(t0.min(t1));
+            exit = exit.min(t0.max(t1));
+        }
+        if enter < exit && self.majorant > 0.0 {
+            Some((enter, exit))
+        } else {
+            None
+        }
+    }
+
+    // Distance to the next tentative collision, along a ray of length `ray_length`
+    fn free_flight(&self, ray_length: f32, sampler: &mut Sampler) -> f32 {
+        -(1.0 - sampler.get_1d()).ln() / (self.majorant * ray_length)
+    }
+
+    // Fraction of the light going through the medium between t_min and t_max,
+    // estimated by ratio tracking : the tentative collisions multiply it by the
+    // chance of being null instead of ending it, which is much less noisy than
+    // following the ray until a collision
+    pub fn transmittance(&self, r: &Ray, t_min: f32, t_max: f32, sampler: &mut Sampler) -> f32 {
+        let (mut t, exit) = match self.clip(r, t_min, t_max) {
+            Some(range) => range,
+            None => return 1.0,
+        };
+        let ray_length = r.direction.length();
+        let mut transmittance = 1.0;
+        loop {
+            t += self.free_flight(ray_length, sampler);
+            if t >= exit {
+                return transmittance;
+            }
+            transmittance *= 1.0 - self.density(r.at(t)) / self.majorant;
+        }
+    }
+}
+
+impl Hitable for GridMedium {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, sampler: &mut Sampler) -> Option<HitRecord<'_>> {
+        let (mut t, exit) = self.clip(r, t_min, t_max)?;
+        let ray_length = r.direction.length();
+        loop {
+            t += self.free_flight(ray_length, sampler);
+            if t >= exit {
+                return None;
+            }
+            let p = r.at(t);
+            if sampler.get_1d() * self.majorant < self.density(p) {
+                return Some(HitRecord {
+                    t,
+                    p,
+                    normal: Vec3 {
+                        x: 1.0,
+                        y: 0.0,
+                        z: 0.0,
+                    },
+                    front_face: true,
+                    material: &self.phase_function,
+                    u: 0.0,
+                    v: 0.0,
+                });
+            }
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bounds
+    }
+}
+
+impl World {
+    // Closest surface hit by a shadow ray, with the fraction of light left by
+    // the grid volumes in between. Other volumes are hit like surfaces
+    pub fn hit_through_volumes(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        sampler: &mut Sampler,
+    ) -> Option<(HitRecord<'_>, f32)> {
+        let rec = self.hit_with(
+            r,
+            t_min,
+            t_max,
+            |object, t_min, closest_so_far| match object {
+                HitableObject::GridMedium(_) => None,
+                object => object.hit(r, t_min, closest_so_far, sampler),
+            },
+        )?;
+        let transmittance = self
+            .volumes
+            .iter()
+            .map(|&i| match &self.objects[i] {
+                HitableObject::GridMedium(medium) => medium.transmittance(r, t_min, rec.t, sampler),
+                _ => 1.0,
+            })
+            .product();
+        Some((rec, transmittance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+    use crate::texture::Texture;
+
+    fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    // Unit box of the densities of a grid of `resolution` voxels along x
+    fn medium(densities: Vec<f32>, density_scale: f32) -> GridMedium {
+        let resolution = [densities.len(), 1, 1];
+        GridMedium::new(
+            Arc::new(VoxelGrid::new(resolution, densities, None).unwrap()),
+            Aabb {
+                min: vec3(0.0, 0.0, 0.0),
+                max: vec3(1.0, 1.0, 1.0),
+            },
+            density_scale,
+            Material::Isotropic {
+                albedo: Texture::Solid(vec3(1.0, 1.0, 1.0)),
+            },
+        )
+    }
+
+    // Along x through the middle of the box, the direction being 2 units long
+    // so that the distances are not the ray parameters
+    fn ray() -> Ray {
+        Ray {
+            origin: vec3(-1.0, 0.5, 0.5),
+            direction: vec3(2.0, 0.0, 0.0),
+            time: 0.0,
+        }
+    }
+
+    const RAYS: u32 = 20000;
+
+    fn mean_transmittance(medium: &GridMedium, t_max: f32) -> f32 {
+        let mut sampler = Sampler::new(SamplerKind::Independent, 5, 1);
+        let total: f32 = (0..RAYS)
+            .map(|_| medium.transmittance(&ray(), 0.0, t_max, &mut sampler))
+            .sum();
+        total / RAYS as f32
+    }
+
+    #[test]
+    fn ratio_tracking_matches_the_beer_lambert_law() {
+        // Through the whole box, then only through its first half
+        let constant = medium(vec![0.7; 8], 2.0);
+        let expected = (-1.4f32).exp();
+        let mean = mean_transmittance(&constant, 10.0);
+        assert!(
+            (mean - expected).abs() < 0.015,
+            "{} instead of {}",
+            mean,
+            expected
+        );
+        let expected = (-0.7f32).exp();
+        let mean = mean_transmittance(&constant, 0.75);
+        assert!(
+            (mean - expected).abs() < 0.015,
+            "{} instead of {}",
+            mean,
+            expected
+        );
+
+        // Densities going from 0 before x = 0.25 to 1 after x = 0.75, which add up
+        // to 0.5 along the ray
+        let ramp = medium(vec![0.0, 1.0], 2.0);
+        let expected = (-1.0f32).exp();
+        let mean = mean_transmittance(&ramp, 10.0);
+        assert!(
+            (mean - expected).abs() < 0.015,
+            "{} instead of {}",
+            mean,
+            expected
+        );
+    }
+
+    #[test]
+    fn rays_missing_the_box_go_through() {
+        let constant = medium(vec![1.0], 1.0);
+        let mut sampler = Sampler::new(SamplerKind::Independent, 5, 1);
+        let above = Ray {
+            origin: vec3(-1.0, 2.0, 0.5),
+            ..ray()
+        };
+        assert_eq!(constant.transmittance(&above, 0.0, 10.0, &mut sampler), 1.0);
+        assert_eq!(constant.transmittance(&ray(), 0.0, 0.5, &mut sampler), 1.0);
+        assert_eq!(
+            medium(vec![0.0], 1.0).transmittance(&ray(), 0.0, 10.0, &mut sampler),
+            1.0
+        );
+    }
+
+    #[test]
+    fn collisions_happen_as_often_as_the_light_is_absorbed() {
+        let ramp = medium(vec![0.0, 1.0], 2.0);
+        let mut sampler = Sampler::new(SamplerKind::Independent, 5, 1);
+        let mut hits = 0;
+        for _ in 0..RAYS {
+            if let Some(rec) = ramp.hit(&ray(), 0.0, 10.0, &mut sampler) {
+                // No collision where the density is 0
+                assert!(rec.p.x > 0.25 && rec.p.x < 1.0, "{}", rec.p.x);
+                hits += 1;
+            }
+        }
+        let expected = 1.0 - (-1.0f32).exp();
+        let fraction = hits as f32 / RAYS as f32;
+        assert!(
+            (fraction - expected).abs() < 0.015,
+            "{} instead of {}",
+            fraction,
+            expected
+        );
+    }
+}
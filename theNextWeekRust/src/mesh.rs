@@ -0,0 +1,192 @@
+use crate::bvh::{Aabb, Bvh};
+use crate::sampler::Sampler;
+use crate::{HitRecord, Hitable, Material, Ray, Vec3};
+
+// Indices into the vertex attribute arrays of the mesh. Normals and texture
+// coordinates are optional, as OBJ faces do not always reference them
+pub struct Triangle {
+    pub vertices: [usize; 3],
+    pub normals: Option<[usize; 3]>,
+    pub uvs: Option<[usize; 3]>,
+    pub material: usize,
+}
+
+// Indexed triangle mesh, with its own BVH over the triangles so the mesh
+// can be placed in the world as a single object
+pub struct Mesh {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f32, f32)>,
+    triangles: Vec<Triangle>,
+    materials: Vec<Material>,
+    bvh: Bvh,
+    bbox: Aabb,
+}
+
+impl Mesh {
+    #[cfg(test)]
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    #[cfg(test)]
+    pub fn triangles(&self) -> &[Triangle] {
+        &self.triangles
+    }
+
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<(f32, f32)>,
+        triangles: Vec<Triangle>,
+        materials: Vec<Material>,
+    ) -> Mesh {
+        let boxes: Vec<Aabb> = triangles
+            .iter()
+            .map(|triangle| triangle_box(&positions, triangle))
+            .collect();
+        let bbox = match boxes.split_first() {
+            Some((first, rest)) => rest
+                .iter()
+                .fold(*first, |acc, b| Aabb::surrounding_box(acc, *b)),
+            None => Aabb {
+                min: Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                max: Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+            },
+        };
+        Mesh {
+            bvh: Bvh::new(&boxes),
+            bbox,
+            positions,
+            normals,
+            uvs,
+            triangles,
+            materials,
+        }
+    }
+
+    // Möller-Trumbore intersection
+    fn hit_triangle(&self, index: usize, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let triangle = &self.triangles[index];
+        let [i0, i1, i2] = triangle.vertices;
+        let p0 = self.positions[i0];
+        let edge1 = self.positions[i1] - p0;
+        let edge2 = self.positions[i2] - p0;
+
+        let pvec = r.direction.cross(edge2);
+        let det = edge1.dot(pvec);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+
+        let tvec = r.origin - p0;
+        let b1 = tvec.dot(pvec) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qvec = tvec.cross(edge1);
+        let b2 = r.direction.dot(qvec) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = edge2.dot(qvec) * inv_det;
+        if t >= t_max || t <= t_min {
+            return None;
+        }
+        let b0 = 1.0 - b1 - b2;
+
+        let geometric_normal = edge1.cross(edge2).unit_vector();
+        let front_face = r.direction.dot(geometric_normal) < 0.0;
+        // Interpolated normals only change the shading, the side of the surface
+        // that was hit is always decided by the winding of the triangle
+        let mut shading_normal = match triangle.normals {
+            Some([n0, n1, n2]) => {
+                (self.normals[n0] * b0 + self.normals[n1] * b1 + self.normals[n2] * b2)
+                    .unit_vector()
+            }
+            None => geometric_normal,
+        };
+        if shading_normal.dot(geometric_normal) < 0.0 {
+            shading_normal = -shading_normal;
+        }
+        let (u, v) = match triangle.uvs {
+            Some([t0, t1, t2]) => (
+                self.uvs[t0].0 * b0 + self.uvs[t1].0 * b1 + self.uvs[t2].0 * b2,
+                self.uvs[t0].1 * b0 + self.uvs[t1].1 * b1 + self.uvs[t2].1 * b2,
+            ),
+            None => (b1, b2),
+        };
+
+        Some(HitRecord {
+            t,
+            p: r.at(t),
+            normal: if front_face {
+                shading_normal
+            } else {
+                -shading_normal
+            },
+            front_face,
+            material: &self.materials[triangle.material],
+            u,
+            v,
+        })
+    }
+}
+
+impl Hitable for Mesh {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        self.bvh.hit(r, t_min, t_max, |i, t_min, closest_so_far| {
+            self.hit_triangle(i, r, t_min, closest_so_far)
+        })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bbox
+    }
+}
+
+// Triangles aligned with an axis have a flat box, which is padded a little so
+// the slab test does not miss rays travelling in the plane of the box
+fn triangle_box(positions: &[Vec3], triangle: &Triangle) -> Aabb {
+    let [i0, i1, i2] = triangle.vertices;
+    let bbox = Aabb::surrounding_box(
+        Aabb {
+            min: positions[i0],
+            max: positions[i0],
+        },
+        Aabb::surrounding_box(
+            Aabb {
+                min: positions[i1],
+                max: positions[i1],
+            },
+            Aabb {
+                min: positions[i2],
+                max: positions[i2],
+            },
+        ),
+    );
+    let padding = Vec3 {
+        x: 1e-4,
+        y: 1e-4,
+        z: 1e-4,
+    };
+    Aabb {
+        min: bbox.min - padding,
+        max: bbox.max + padding,
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::mesh::{Mesh, Triangle};
+use crate::texture::{ImageTexture, Texture};
+use crate::{checkpoint, Material, Vec3};
+
+// Loads a Wavefront OBJ file as a single mesh. Polygons are triangulated as fans,
+// and materials referenced with `usemtl` are looked up in the `mtllib` files,
+// faces without a material using `default_material`
+pub fn load_obj(path: &Path, default_material: Material) -> Result<Mesh, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    checkpoint::record_asset(content.as_bytes());
+    parse_obj(&content, path, default_material)
+}
+
+// Mesh of the content of an OBJ file, `path` locating the `mtllib` files and
+// naming the file in the errors
+fn parse_obj(content: &str, path: &Path, default_material: Material) -> Result<Mesh, String> {
+    let mut positions = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut triangles = vec![];
+    let mut materials = vec![default_material.clone()];
+    let mut library: HashMap<String, Material> = HashMap::new();
+    let mut material_indices: HashMap<String, usize> = HashMap::new();
+    let mut current_material = 0;
+
+    for (line_number, line) in content.lines().enumerate() {
+        let error =
+            |message: String| format!("{}:{}: {}", path.display(), line_number + 1, message);
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => positions.push(parse_vec3(&arguments).map_err(error)?),
+            "vn" => normals.push(parse_vec3(&arguments).map_err(error)?),
+            "vt" => {
+                let u = parse_float(arguments.first()).map_err(error)?;
+                let v = match arguments.get(1) {
+                    Some(_) => parse_float(arguments.get(1)).map_err(error)?,
+                    None => 0.0,
+                };
+                uvs.push((u, v));
+            }
+            "f" => {
+                if arguments.len() < 3 {
+                    return Err(error("a face needs at least 3 vertices".to_string()));
+                }
+                let vertices = arguments
+                    .iter()
+                    .map(|vertex| {
+                        parse_face_vertex(vertex, positions.len(), uvs.len(), normals.len())
+                    })
+                    .collect::<Result<Vec<FaceVertex>, String>>()
+                    .map_err(error)?;
+                for i in 1..vertices.len() - 1 {
+                    let corners = [vertices[0], vertices[i], vertices[i + 1]];
+                    triangles.push(Triangle {
+                        vertices: [
+                            corners[0].position,
+                            corners[1].position,
+                            corners[2].position,
+                        ],
+                        uvs: match (corners[0].uv, corners[1].uv, corners[2].uv) {
+                            (Some(t0), Some(t1), Some(t2)) => Some([t0, t1, t2]),
+                            _ => None,
+                        },
+                        normals: match (corners[0].normal, corners[1].normal, corners[2].normal) {
+                            (Some(n0), Some(n1), Some(n2)) => Some([n0, n1, n2]),
+                            _ => None,
+                        },
+                        material: current_material,
+                    });
+                }
+            }
+            "mtllib" => {
+                let directory = path.parent().unwrap_or_else(|| Path::new(""));
+                for file in arguments {
+                    let mtl_path = directory.join(file);
+                    match load_mtl(&mtl_path) {
+                        Ok(mtl) => library.extend(mtl),
+                        Err(e) => eprintln!("warning: {}, using the default material", e),
+                    }
+                }
+            }
+            "usemtl" => {
+                let name = arguments.join(" ");
+                current_material = match material_indices.get(&name) {
+                    Some(index) => *index,
+                    None => {
+                        let material = match library.get(&name) {
+                            Some(material) => material.clone(),
+                            None => {
+                                eprintln!(
+                                    "warning: {}",
+                                    error(format!(
+                                        "unknown material {:?}, using the default material",
+                                        name
+                                    ))
+                                );
+                                default_material.clone()
+                            }
+                        };
+                        materials.push(material);
+                        material_indices.insert(name, materials.len() - 1);
+                        materials.len() - 1
+                    }
+                };
+            }
+            // Groups, smoothing groups, free-form geometry... are not supported
+            _ => {}
+        }
+    }
+
+    if triangles.is_empty() {
+        return Err(format!("{}: no faces found", path.display()));
+    }
+    Ok(Mesh::new(positions, normals, uvs, triangles, materials))
+}
+
+#[derive(Copy, Clone)]
+struct FaceVertex {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+// Parses `v`, `v/vt`, `v//vn` or `v/vt/vn`, indices being 1-based or negative
+// to count back from the last element defined so far
+fn parse_face_vertex(
+    vertex: &str,
+    nb_positions: usize,
+    nb_uvs: usize,
+    nb_normals: usize,
+) -> Result<FaceVertex, String> {
+    let mut parts = vertex.split('/');
+    let position = resolve_index(parts.next(), nb_positions, vertex)?
+        .ok_or_else(|| format!("missing vertex index in {:?}", vertex))?;
+    let uv = resolve_index(parts.next(), nb_uvs, vertex)?;
+    let normal = resolve_index(parts.next(), nb_normals, vertex)?;
+    Ok(FaceVertex {
+        position,
+        uv,
+        normal,
+    })
+}
+
+fn resolve_index(index: Option<&str>, count: usize, vertex: &str) -> Result<Option<usize>, String> {
+    let index = match index {
+        Some(index) if !index.is_empty() => index,
+        _ => return Ok(None),
+    };
+    let index: i64 = index
+        .parse()
+        .map_err(|_| format!("invalid index {:?} in {:?}", index, vertex))?;
+    let resolved = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(format!("index {} out of range in {:?}", index, vertex));
+    }
+    Ok(Some(resolved as usize))
+}
+
+fn parse_float(token: Option<&&str>) -> Result<f32, String> {
+    match token {
+        Some(token) => token
+            .parse::<f32>()
+            .map_err(|_| format!("invalid number {:?}", token)),
+        None => Err("missing number".to_string()),
+    }
+}
+
+fn parse_vec3(arguments: &[&str]) -> Result<Vec3, String> {
+    Ok(Vec3 {
+        x: parse_float(arguments.first())?,
+        y: parse_float(arguments.get(1))?,
+        z: parse_float(arguments.get(2))?,
+    })
+}
+
+// Subset of the MTL parameters which can be expressed with our materials
+struct MtlMaterial {
+    diffuse: Vec3,
+    diffuse_map: Option<Arc<ImageTexture>>,
+    specular: Vec3,
+    specular_exponent: f32,
+    refraction_index: f32,
+    dissolve: f32,
+    illum: i32,
+}
+
+impl MtlMaterial {
+    fn to_material(&self) -> Material {
+        match self.illum {
+            // Transparent or refractive illumination models, or a partially dissolved material
+            4 | 6 | 7 | 9 => Material::Dielectric {
+                ref_idx: self.refraction_index,
+            },
+            _ if self.dissolve < 1.0 => Material::Dielectric {
+                ref_idx: self.refraction_index,
+            },
+            // Illumination models with ray traced reflections, the Phong exponent
+            // is mapped to a fuzz so that high exponents give sharp reflections
+            3 | 5 | 8 => Material::Metal {
+                albedo: self.specular,
+                fuzz: (2.0 / (self.specular_exponent + 2.0)).sqrt().min(1.0),
+            },
+            _ => Material::Lambertian {
+                albedo: match &self.diffuse_map {
+                    Some(image) => Texture::Image(image.clone()),
+                    None => Texture::Solid(self.diffuse),
+                },
+            },
+        }
+    }
+}
+
+fn load_mtl(path: &Path) -> Result<HashMap<String, Material>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    checkpoint::record_asset(content.as_bytes());
+    let mut library = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+
+    for (line_number, line) in content.lines().enumerate() {
+        let error =
+            |message: String| format!("{}:{}: {}", path.display(), line_number + 1, message);
+        let line = line.split('#').next().unwrap_or("");
+        let mut tokens = line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl" {
+            if let Some((name, mtl)) = current.take() {
+                library.insert(name, mtl.to_material());
+            }
+            current = Some((
+                arguments.join(" "),
+                MtlMaterial {
+                    diffuse: Vec3 {
+                        x: 0.8,
+                        y: 0.8,
+                        z: 0.8,
+                    },
+                    diffuse_map: None,
+                    specular: Vec3 {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 0.0,
+                    },
+                    specular_exponent: 0.0,
+                    refraction_index: 1.5,
+                    dissolve: 1.0,
+                    illum: 2,
+                },
+            ));
+            continue;
+        }
+        let mtl = match current.as_mut() {
+            Some((_, mtl)) => mtl,
+            None => return Err(error(format!("{:?} before any newmtl", keyword))),
+        };
+        match keyword {
+            "Kd" => mtl.diffuse = parse_vec3(&arguments).map_err(error)?,
+            "Ks" => mtl.specular = parse_vec3(&arguments).map_err(error)?,
+            "Ns" => mtl.specular_exponent = parse_float(arguments.first()).map_err(error)?,
+            "Ni" => mtl.refraction_index = parse_float(arguments.first()).map_err(error)?,
+            "d" => mtl.dissolve = parse_float(arguments.first()).map_err(error)?,
+            "Tr" => mtl.dissolve = 1.0 - parse_float(arguments.first()).map_err(error)?,
+            // Texture options may come before the file name
+            "map_Kd" => {
+                let file = arguments
+                    .last()
+                    .ok_or_else(|| error("missing texture file".to_string()))?;
+                let directory = path.parent().unwrap_or_else(|| Path::new(""));
+                match ImageTexture::load(&directory.join(file)) {
+                    Ok(image) => mtl.diffuse_map = Some(Arc::new(image)),
+                    Err(e) => eprintln!("warning: {}, using the diffuse colour", e),
+                }
+            }
+            "illum" => {
+                mtl.illum = arguments
+                    .first()
+                    .and_then(|illum| illum.parse().ok())
+                    .ok_or_else(|| error("invalid illumination model".to_string()))?
+            }
+            // Other parameters (ambient colour, other texture maps...) have no equivalent in our materials
+            _ => {}
+        }
+    }
+    if let Some((name, mtl)) = current.take() {
+        library.insert(name, mtl.to_material());
+    }
+    Ok(library)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Mesh, String> {
+        parse_obj(
+            content,
+            Path::new("test.obj"),
+            Material::Dielectric { ref_idx: 1.5 },
+        )
+    }
+
+    fn vertices(mesh: &Mesh) -> Vec<[usize; 3]> {
+        mesh.triangles()
+            .iter()
+            .map(|triangle| triangle.vertices)
+            .collect()
+    }
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn polygons_are_triangulated_as_fans() {
+        let mesh = parse(&format!("{}v 0.5 1.5 0\nf 1 2 3 5 4\n", SQUARE)).unwrap();
+        assert_eq!(vertices(&mesh), [[0, 1, 2], [0, 2, 4], [0, 4, 3]]);
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_vertex() {
+        let content = format!("{}f -4 -3 -2 -1\nv 2 0 0\nf -1 -4 -3\n", SQUARE);
+        let mesh = parse(&content).unwrap();
+        assert_eq!(vertices(&mesh), [[0, 1, 2], [0, 2, 3], [4, 1, 2]]);
+        assert_eq!(mesh.positions()[4].x, 2.0);
+    }
+
+    #[test]
+    fn faces_may_reference_uvs_and_normals() {
+        let content = format!(
+            "{}vt 0 0\nvt 1 0\nvt 1 1\nvn 0 0 1\n\
+             f 1 2 3\nf 1/1 2/2 3/3\nf 1//1 2//1 3//1\nf 1/1/1 2/2/1 3/-1/-1\n",
+            SQUARE
+        );
+        let mesh = parse(&content).unwrap();
+        let attributes: Vec<_> = mesh
+            .triangles()
+            .iter()
+            .map(|triangle| (triangle.uvs, triangle.normals))
+            .collect();
+        assert_eq!(
+            attributes,
+            [
+                (None, None),
+                (Some([0, 1, 2]), None),
+                (None, Some([0, 0, 0])),
+                (Some([0, 1, 2]), Some([0, 0, 0])),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_faces_are_reported_with_their_line() {
+        let error = |content: &str| parse(&format!("{}{}", SQUARE, content)).err().unwrap();
+        assert_eq!(
+            error("f 1 2\n"),
+            "test.obj:5: a face needs at least 3 vertices"
+        );
+        assert_eq!(
+            error("f 1 2 5\n"),
+            "test.obj:5: index 5 out of range in \"5\""
+        );
+        assert_eq!(
+            error("f 1 2 -5\n"),
+            "test.obj:5: index -5 out of range in \"-5\""
+        );
+        assert_eq!(
+            error("f 1/1 2 3\n"),
+            "test.obj:5: index 1 out of range in \"1/1\""
+        );
+        assert_eq!(parse(SQUARE).err().unwrap(), "test.obj: no faces found");
+    }
+}
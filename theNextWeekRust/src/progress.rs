@@ -0,0 +1,288 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::tile::Tile;
+
+// How the progress of the renders is shown on the standard error
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ProgressMode {
+    None,
+    // A line rewritten in place, for the terminals
+    Text,
+    // One JSON object per line, for the programs following the renders
+    Json,
+}
+
+impl ProgressMode {
+    pub fn from_name(name: &str) -> Option<ProgressMode> {
+        match name {
+            "none" => Some(ProgressMode::None),
+            "text" => Some(ProgressMode::Text),
+            "json" => Some(ProgressMode::Json),
+            _ => None,
+        }
+    }
+
+    // The text line when the standard error is a terminal, nothing otherwise
+    pub fn default_for_stderr() -> ProgressMode {
+        if io::stderr().is_terminal() {
+            ProgressMode::Text
+        } else {
+            ProgressMode::None
+        }
+    }
+
+    fn interval(self) -> Duration {
+        match self {
+            ProgressMode::Json => Duration::from_secs(1),
+            _ => Duration::from_millis(200),
+        }
+    }
+}
+
+// Progress of the render of a frame, from `first_samples` samples per pixel (more
+// than 0 when resumed) to `last_samples`, told by the threads after each tile
+pub struct Progress {
+    mode: ProgressMode,
+    frame: Option<u32>,
+    start: Instant,
+    pixels: usize,
+    first_samples: u32,
+    last_samples: u32,
+    state: Mutex<State>,
+}
+
+struct State {
+    // Samples per pixel before and after the pass being rendered
+    pass: (u32, u32),
+    pass_pixels: usize,
+    samples: u64,
+    rays: u64,
+    last_report: Option<Instant>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    event: &'static str,
+    frame: Option<u32>,
+    // Part of the render done, from 0 to 1
+    done: f64,
+    samples: u64,
+    samples_per_second: f64,
+    // Path and shadow rays
+    rays: u64,
+    rays_per_second: f64,
+    elapsed_seconds: f64,
+    remaining_seconds: Option<f64>,
+}
+
+impl Progress {
+    pub fn new(
+        mode: ProgressMode,
+        frame: Option<u32>,
+        pixels: usize,
+        first_samples: u32,
+        last_samples: u32,
+    ) -> Progress {
+        Progress {
+            mode,
+            frame,
+            start: Instant::now(),
+            pixels,
+            first_samples,
+            last_samples,
+            state: Mutex::new(State {
+                pass: (first_samples, first_samples),
+                pass_pixels: 0,
+                samples: 0,
+                rays: 0,
+                last_report: None,
+            }),
+        }
+    }
+
+    pub fn start_pass(&self, from: u32, to: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.pass = (from, to);
+        state.pass_pixels = 0;
+    }
+
+    // A tile of the pass is done, after `samples` more samples and `rays` more rays
+    pub fn tile_done(&self, tile: Tile, samples: u64, rays: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.pass_pixels += tile.pixel_count();
+        state.samples += samples;
+        state.rays += rays;
+        let now = Instant::now();
+        if state
+            .last_report
+            .is_some_and(|last| now - last < self.mode.interval())
+        {
+            return;
+        }
+        state.last_report = Some(now);
+        match self.mode {
+            ProgressMode::None => (),
+            ProgressMode::Text => {
+                let report = self.report("progress", &state);
+                let remaining = match report.remaining_seconds {
+                    Some(seconds) => format!(", {} left", duration(seconds)),
+                    None => String::new(),
+                };
+                eprint!(
+                    "\r{:5.1}% : {:.2} M rays/s, {:.2} M samples/s, {} elapsed{}\x1b[K",
+                    100.0 * report.done,
+                    report.rays_per_second / 1e6,
+                    report.samples_per_second / 1e6,
+                    duration(report.elapsed_seconds),
+                    remaining
+                );
+                let _ = io::stderr().flush();
+            }
+            ProgressMode::Json => print_json(&self.report("progress", &state)),
+        }
+    }
+
+    // Erases the line of progress, before the other messages
+    pub fn end_pass(&self) {
+        if self.mode == ProgressMode::Text {
+            eprint!("\r\x1b[K");
+        }
+    }
+
+    pub fn finish(&self) {
+        self.end_pass();
+        if self.mode == ProgressMode::Json {
+            let mut state = self.state.lock().unwrap();
+            state.pass = (self.last_samples, self.last_samples);
+            print_json(&self.report("done", &state));
+        }
+    }
+
+    fn report(&self, event: &'static str, state: &State) -> Report {
+        let (from, to) = state.pass;
+        let pass_done = if self.pixels == 0 {
+            1.0
+        } else {
+            (state.pass_pixels as f64 / self.pixels as f64).min(1.0)
+        };
+        let done = if self.last_samples <= self.first_samples {
+            1.0
+        } else {
+            (f64::from(from - self.first_samples) + f64::from(to - from) * pass_done)
+                / f64::from(self.last_samples - self.first_samples)
+        };
+        let elapsed = self.start.elapsed().as_secs_f64();
+        Report {
+            event,
+            frame: self.frame,
+            done,
+            samples: state.samples,
+            samples_per_second: state.samples as f64 / elapsed.max(1e-9),
+            rays: state.rays,
+            rays_per_second: state.rays as f64 / elapsed.max(1e-9),
+            elapsed_seconds: elapsed,
+            remaining_seconds: if done > 0.0 {
+                Some(elapsed * (1.0 - done) / done)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+fn print_json(report: &Report) {
+    if let Ok(line) = serde_json::to_string(report) {
+        eprintln!("{}", line);
+    }
+}
+
+// Hours, minutes and seconds
+fn duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tile of `pixels` pixels on a row
+    fn row(pixels: usize) -> Tile {
+        Tile {
+            x0: 0,
+            y0: 0,
+            x1: pixels,
+            y1: 1,
+        }
+    }
+
+    fn report(progress: &Progress) -> Report {
+        progress.report("progress", &progress.state.lock().unwrap())
+    }
+
+    #[test]
+    fn the_passes_add_up_to_the_whole_render() {
+        let progress = Progress::new(ProgressMode::None, None, 100, 0, 32);
+        progress.start_pass(0, 16);
+        let start = report(&progress);
+        assert_eq!(start.done, 0.0);
+        assert_eq!(start.remaining_seconds, None);
+        progress.tile_done(row(50), 800, 2000);
+        let report_1 = report(&progress);
+        assert_eq!(report_1.done, 0.25);
+        assert_eq!((report_1.samples, report_1.rays), (800, 2000));
+        let remaining = report_1.remaining_seconds.unwrap();
+        assert!((remaining - 3.0 * report_1.elapsed_seconds).abs() < 1e-9);
+        assert!((report_1.rays_per_second * report_1.elapsed_seconds - 2000.0).abs() < 1e-6);
+        progress.tile_done(row(50), 800, 2000);
+        progress.start_pass(16, 32);
+        assert_eq!(report(&progress).done, 0.5);
+        progress.tile_done(row(100), 1600, 4000);
+        let end = report(&progress);
+        assert_eq!(end.done, 1.0);
+        assert_eq!(end.remaining_seconds, Some(0.0));
+        assert_eq!((end.samples, end.rays), (3200, 8000));
+    }
+
+    #[test]
+    fn resumed_renders_count_from_their_checkpoint() {
+        let progress = Progress::new(ProgressMode::None, Some(3), 100, 16, 32);
+        progress.start_pass(16, 24);
+        progress.tile_done(row(50), 400, 900);
+        let report = report(&progress);
+        assert_eq!(report.done, 0.25);
+        assert_eq!(report.frame, Some(3));
+        // Nothing left to render
+        let finished = Progress::new(ProgressMode::None, None, 100, 32, 32);
+        assert_eq!(
+            finished
+                .report("done", &finished.state.lock().unwrap())
+                .done,
+            1.0
+        );
+    }
+
+    #[test]
+    fn durations_are_in_minutes_or_hours() {
+        assert_eq!(duration(0.0), "0:00");
+        assert_eq!(duration(59.6), "1:00");
+        assert_eq!(duration(125.0), "2:05");
+        assert_eq!(duration(3599.0), "59:59");
+        assert_eq!(duration(3600.0), "1:00:00");
+        assert_eq!(duration(3725.0), "1:02:05");
+        assert_eq!(duration(100.0 * 3600.0 + 61.0), "100:01:01");
+    }
+}
@@ -0,0 +1,444 @@
+use crate::bvh::Aabb;
+use crate::sampler::Sampler;
+use crate::{HitRecord, Hitable, Material, Ray, Vec3};
+
+// Rectangle x0 <= x <= x1, y0 <= y <= y1 in the plane z = k, facing +z
+pub struct XYRect {
+    pub x0: f32,
+    pub x1: f32,
+    pub y0: f32,
+    pub y1: f32,
+    pub k: f32,
+    pub material: Material,
+}
+
+// Rectangle x0 <= x <= x1, z0 <= z <= z1 in the plane y = k, facing +y
+pub struct XZRect {
+    pub x0: f32,
+    pub x1: f32,
+    pub z0: f32,
+    pub z1: f32,
+    pub k: f32,
+    pub material: Material,
+}
+
+// Rectangle y0 <= y <= y1, z0 <= z <= z1 in the plane x = k, facing +x
+pub struct YZRect {
+    pub y0: f32,
+    pub y1: f32,
+    pub z0: f32,
+    pub z1: f32,
+    pub k: f32,
+    pub material: Material,
+}
+
+// Axis aligned box between two opposite corners, made of six rectangles facing outwards
+pub struct Cuboid {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub material: Material,
+}
+
+const X: usize = 0;
+const Y: usize = 1;
+const Z: usize = 2;
+
+fn component(v: Vec3, axis: usize) -> f32 {
+    match axis {
+        X => v.x,
+        Y => v.y,
+        _ => v.z,
+    }
+}
+
+// Rectangle spanning [a0, a1] x [b0, b1] along the axes `a` and `b`, at `k` on the
+// third axis. Its normal points towards the positive or the negative side of that axis
+pub struct Plane {
+    a: usize,
+    b: usize,
+    axis: usize,
+    a0: f32,
+    a1: f32,
+    b0: f32,
+    b1: f32,
+    k: f32,
+    outward: f32,
+}
+
+impl Plane {
+    fn point(&self, a: f32, b: f32, k: f32) -> Vec3 {
+        let mut values = [0.0; 3];
+        values[self.a] = a;
+        values[self.b] = b;
+        values[self.axis] = k;
+        Vec3 {
+            x: values[X],
+            y: values[Y],
+            z: values[Z],
+        }
+    }
+
+    pub fn normal(&self) -> Vec3 {
+        self.point(0.0, 0.0, self.outward)
+    }
+
+    pub fn area(&self) -> f32 {
+        (self.a1 - self.a0) * (self.b1 - self.b0)
+    }
+
+    // Point of the rectangle at the fractions (s, t) of its sides
+    pub fn point_at(&self, s: f32, t: f32) -> Vec3 {
+        self.point(
+            self.a0 + s * (self.a1 - self.a0),
+            self.b0 + t * (self.b1 - self.b0),
+            self.k,
+        )
+    }
+
+    // Distance along the ray and (u, v) coordinates of the intersection
+    pub fn intersect(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32, f32)> {
+        let t = (self.k - component(r.origin, self.axis)) / component(r.direction, self.axis);
+        // Also rejects the NaN of rays parallel to the plane
+        if !(t > t_min && t < t_max) {
+            return None;
+        }
+        let p = r.at(t);
+        let a = component(p, self.a);
+        let b = component(p, self.b);
+        if a < self.a0 || a > self.a1 || b < self.b0 || b > self.b1 {
+            return None;
+        }
+        Some((
+            t,
+            (a - self.a0) / (self.a1 - self.a0),
+            (b - self.b0) / (self.b1 - self.b0),
+        ))
+    }
+
+    pub fn hit<'a>(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        material: &'a Material,
+    ) -> Option<HitRecord<'a>> {
+        let (t, u, v) = self.intersect(r, t_min, t_max)?;
+        let normal = self.normal();
+        let front_face = r.direction.dot(normal) < 0.0;
+        Some(HitRecord {
+            t,
+            p: r.at(t),
+            normal: if front_face { normal } else { -normal },
+            front_face,
+            material,
+            u,
+            v,
+        })
+    }
+
+    // Flat box, padded like the triangle boxes so the slab test does not miss it
+    pub fn bounding_box(&self) -> Aabb {
+        Aabb {
+            min: self.point(self.a0, self.b0, self.k - 1e-4),
+            max: self.point(self.a1, self.b1, self.k + 1e-4),
+        }
+    }
+}
+
+impl XYRect {
+    pub fn plane(&self) -> Plane {
+        Plane {
+            a: X,
+            b: Y,
+            axis: Z,
+            a0: self.x0,
+            a1: self.x1,
+            b0: self.y0,
+            b1: self.y1,
+            k: self.k,
+            outward: 1.0,
+        }
+    }
+}
+
+impl XZRect {
+    pub fn plane(&self) -> Plane {
+        Plane {
+            a: X,
+            b: Z,
+            axis: Y,
+            a0: self.x0,
+            a1: self.x1,
+            b0: self.z0,
+            b1: self.z1,
+            k: self.k,
+            outward: 1.0,
+        }
+    }
+}
+
+impl YZRect {
+    pub fn plane(&self) -> Plane {
+        Plane {
+            a: Y,
+            b: Z,
+            axis: X,
+            a0: self.y0,
+            a1: self.y1,
+            b0: self.z0,
+            b1: self.z1,
+            k: self.k,
+            outward: 1.0,
+        }
+    }
+}
+
+impl Cuboid {
+    // The faces on the min side of each axis face the negative direction, so that
+    // rays entering the box always hit a front face
+    fn faces(&self) -> [Plane; 6] {
+        let face = |a: usize, b: usize, axis: usize, at_max: bool| Plane {
+            a,
+            b,
+            axis,
+            a0: component(self.min, a),
+            a1: component(self.max, a),
+            b0: component(self.min, b),
+            b1: component(self.max, b),
+            k: component(if at_max { self.max } else { self.min }, axis),
+            outward: if at_max { 1.0 } else { -1.0 },
+        };
+        [
+            face(X, Y, Z, false),
+            face(X, Y, Z, true),
+            face(X, Z, Y, false),
+            face(X, Z, Y, true),
+            face(Y, Z, X, false),
+            face(Y, Z, X, true),
+        ]
+    }
+}
+
+impl Hitable for XYRect {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        self.plane().hit(r, t_min, t_max, &self.material)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.plane().bounding_box()
+    }
+}
+
+impl Hitable for XZRect {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        self.plane().hit(r, t_min, t_max, &self.material)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.plane().bounding_box()
+    }
+}
+
+impl Hitable for YZRect {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        self.plane().hit(r, t_min, t_max, &self.material)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.plane().bounding_box()
+    }
+}
+
+impl Hitable for Cuboid {
+    fn hit(
+        &self,
+        r: &Ray,
+        t_min: f32,
+        t_max: f32,
+        _sampler: &mut Sampler,
+    ) -> Option<HitRecord<'_>> {
+        let mut closest = None;
+        let mut closest_so_far = t_max;
+        for face in self.faces().iter() {
+            if let Some(rec) = face.hit(r, t_min, closest_so_far, &self.material) {
+                closest_so_far = rec.t;
+                closest = Some(rec);
+            }
+        }
+        closest
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb {
+            min: self.min,
+            max: self.max,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::SamplerKind;
+    use crate::texture::Texture;
+
+    fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn xyz(v: Vec3) -> [f32; 3] {
+        [v.x, v.y, v.z]
+    }
+
+    fn grey() -> Material {
+        Material::Lambertian {
+            albedo: Texture::Solid(vec3(0.5, 0.5, 0.5)),
+        }
+    }
+
+    fn ray(origin: Vec3, direction: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction,
+            time: 0.0,
+        }
+    }
+
+    // 2 by 2 rectangle in the plane z = 2
+    fn xy_rect() -> XYRect {
+        XYRect {
+            x0: 1.0,
+            x1: 3.0,
+            y0: -1.0,
+            y1: 1.0,
+            k: 2.0,
+            material: grey(),
+        }
+    }
+
+    #[test]
+    fn the_corners_have_the_extreme_coordinates() {
+        let plane = xy_rect().plane();
+        let down = vec3(0.0, 0.0, -1.0);
+        for &(x, y, u, v) in [
+            (1.0, -1.0, 0.0, 0.0),
+            (3.0, -1.0, 1.0, 0.0),
+            (1.0, 1.0, 0.0, 1.0),
+            (3.0, 1.0, 1.0, 1.0),
+            (2.0, 0.5, 0.5, 0.75),
+        ]
+        .iter()
+        {
+            let hit = plane.intersect(&ray(vec3(x, y, 5.0), down), 0.0, 10.0);
+            assert_eq!(hit, Some((3.0, u, v)), "at ({}, {})", x, y);
+        }
+        assert_eq!(
+            plane.intersect(&ray(vec3(3.1, 0.0, 5.0), down), 0.0, 10.0),
+            None
+        );
+        assert_eq!(
+            plane.intersect(&ray(vec3(2.0, 0.0, 5.0), down), 0.0, 2.5),
+            None
+        );
+    }
+
+    #[test]
+    fn the_normal_faces_the_rays_hitting_the_back() {
+        let rect = xy_rect();
+        let mut sampler = Sampler::new(SamplerKind::Independent, 0, 1);
+        let front = rect
+            .hit(
+                &ray(vec3(2.0, 0.0, 5.0), vec3(0.0, 0.0, -1.0)),
+                0.0,
+                10.0,
+                &mut sampler,
+            )
+            .unwrap();
+        assert!(front.front_face);
+        assert_eq!(xyz(front.normal), [0.0, 0.0, 1.0]);
+        let back = rect
+            .hit(
+                &ray(vec3(2.0, 0.0, -5.0), vec3(0.0, 0.0, 1.0)),
+                0.0,
+                10.0,
+                &mut sampler,
+            )
+            .unwrap();
+        assert!(!back.front_face);
+        assert_eq!(xyz(back.normal), [0.0, 0.0, -1.0]);
+        // The texture coordinates do not depend on the side
+        assert_eq!((front.u, front.v), (back.u, back.v));
+    }
+
+    #[test]
+    fn rays_parallel_to_the_plane_miss_it() {
+        let plane = xy_rect().plane();
+        let along = vec3(1.0, 0.0, 0.0);
+        // In the plane, where the distance is 0 / 0, and beside it
+        assert_eq!(
+            plane.intersect(&ray(vec3(0.0, 0.0, 2.0), along), 0.0, 10.0),
+            None
+        );
+        assert_eq!(
+            plane.intersect(&ray(vec3(0.0, 0.0, 3.0), along), 0.0, 10.0),
+            None
+        );
+    }
+
+    #[test]
+    fn the_box_faces_point_outwards() {
+        let cuboid = Cuboid {
+            min: vec3(-1.0, -2.0, -3.0),
+            max: vec3(1.0, 2.0, 3.0),
+            material: grey(),
+        };
+        let mut sampler = Sampler::new(SamplerKind::Independent, 0, 1);
+        let axes = [
+            vec3(1.0, 0.0, 0.0),
+            vec3(-1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, -1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, 0.0, -1.0),
+        ];
+        for &outward in axes.iter() {
+            // From outside towards the centre, slightly off the axis so that no
+            // edge is hit
+            let origin = outward * 10.0 + vec3(0.1, 0.2, 0.3);
+            let rec = cuboid
+                .hit(&ray(origin, -outward), 0.0, 100.0, &mut sampler)
+                .unwrap();
+            assert!(rec.front_face, "{:?}", outward);
+            assert_eq!(xyz(rec.normal), xyz(outward));
+            // From the centre, the same face is hit from the inside
+            let rec = cuboid
+                .hit(&ray(vec3(0.1, 0.2, 0.3), outward), 0.0, 100.0, &mut sampler)
+                .unwrap();
+            assert!(!rec.front_face, "{:?}", outward);
+            assert_eq!(xyz(rec.normal), xyz(-outward));
+        }
+        for face in cuboid.faces().iter() {
+            let outward = face.normal();
+            let centre = face.point_at(0.5, 0.5);
+            assert!(outward.dot(centre) > 0.0, "{:?} at {:?}", outward, centre);
+        }
+    }
+}
@@ -0,0 +1,510 @@
+use serde::Deserialize;
+
+// Finalizer of SplitMix64, a bijection of the 64 bit integers spreading each
+// input bit over the whole output
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// Hash of a list of values, different when they come in a different order
+pub fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(GOLDEN_GAMMA, |h, &v| mix(h ^ v).wrapping_add(GOLDEN_GAMMA))
+}
+
+// Hash of a string of bytes
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut words = vec![bytes.len() as u64];
+    words.extend(bytes.chunks(8).map(|chunk| {
+        let mut word = [0; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        u64::from_le_bytes(word)
+    }));
+    hash(&words)
+}
+
+// Small and fast generator (SplitMix64), whose streams started from different
+// seeds are independent enough for sampling
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        mix(self.state)
+    }
+
+    // Uniform in [0, 1), from the 24 high bits which fit exactly in a f32
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+// How the numbers of the samples of a pixel are spread
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplerKind {
+    // Independent uniform numbers
+    #[default]
+    Independent,
+    // Jittered strata, in a grid for the pairs of numbers, shuffled so that the
+    // strata of the different dimensions are not correlated
+    Stratified,
+    // Halton sequence with a random shift for each pixel
+    Halton,
+    // Pairs of dimensions of the Sobol sequence, Owen scrambled and shuffled for
+    // each pixel
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name {
+            "independent" | "random" => Some(SamplerKind::Independent),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            _ => None,
+        }
+    }
+}
+
+// Uses of the numbers of a sample. Each one starts at a fixed dimension, the
+// same in every sample of a pixel, so that the well spread dimensions are given
+// to the same decisions however many numbers the previous ones took
+#[derive(Debug, Copy, Clone)]
+pub enum Dimensions {
+    // Position in the pixel, then on the lens, and time of the camera ray
+    Pixel,
+    Lens,
+    Time,
+    // Scattering at the next bounce of the path
+    Scatter,
+    // Choice of a light and of a point on it at the current bounce, and the
+    // volumes crossed by the shadow ray
+    Light,
+    // Volumes crossed by the ray leaving the current bounce, before the next one
+    Medium,
+}
+
+const PIXEL_DIMENSION: u32 = 0;
+const LENS_DIMENSION: u32 = 2;
+const TIME_DIMENSION: u32 = 4;
+const FIRST_BOUNCE_DIMENSION: u32 = 5;
+// Up to three numbers for scattering, four for light sampling, and one for the
+// volumes crossed on the way to the bounce
+const BOUNCE_DIMENSIONS: u32 = 8;
+const LIGHT_OFFSET: u32 = 3;
+const MEDIUM_OFFSET: u32 = 7;
+
+// Bases of the dimensions of the Halton sequence, the next dimensions being
+// independent uniform numbers
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// Largest f32 below 1
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+fn unit_f32(bits: u32) -> f32 {
+    (bits >> 8) as f32 / (1u32 << 24) as f32
+}
+
+// Digits of `index` in `base` mirrored around the radix point
+fn radical_inverse(base: u32, mut index: u32) -> f64 {
+    let inverse_base = 1.0 / f64::from(base);
+    let mut digits = 0.0;
+    let mut scale = 1.0;
+    while index > 0 {
+        scale *= inverse_base;
+        digits += f64::from(index % base) * scale;
+        index /= base;
+    }
+    digits
+}
+
+// First two dimensions of the Sobol sequence, as 32 bit fractions
+fn sobol(index: u32, dimension: u32) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+    let mut value = 0;
+    let mut direction = 1 << 31;
+    let mut index = index;
+    while index > 0 {
+        if index & 1 == 1 {
+            value ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    value
+}
+
+// Owen scrambling of a 32 bit fraction, each bit being flipped depending on
+// the seed and the bits above it (Laine-Karras hash with the constants of
+// Burley, "Practical Hash-based Owen Scrambling")
+fn owen_scramble(value: u32, seed: u32) -> u32 {
+    let mut x = value.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+// Element `index` of a random permutation of 0..length chosen by `seed`
+// (Kensler, "Correlated Multi-Jittered Sampling")
+fn permute(mut index: u32, length: u32, seed: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    let p = seed;
+    loop {
+        index ^= p;
+        index = index.wrapping_mul(0xe170_893d);
+        index ^= p >> 16;
+        index ^= (index & w) >> 4;
+        index ^= p >> 8;
+        index = index.wrapping_mul(0x0929_eb3f);
+        index ^= p >> 23;
+        index ^= (index & w) >> 1;
+        index = index.wrapping_mul(1 | p >> 27);
+        index = index.wrapping_mul(0x6935_fa69);
+        index ^= (index & w) >> 11;
+        index = index.wrapping_mul(0x74dc_b303);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0x9e50_1cc3);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0xc860_a3df);
+        index &= w;
+        index ^= index >> 5;
+        if index < length {
+            return (index.wrapping_add(p)) % length;
+        }
+    }
+}
+
+// Source of the numbers of the renderer. Everything is derived from the global
+// seed, the pixel and the sample index, so an image only depends on the seed and
+// not on the order in which the threads render the pixels. The independent
+// numbers come from a stream started for each sample
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    kind: SamplerKind,
+    seed: u64,
+    samples_per_pixel: u32,
+    pixel_seed: u64,
+    sample: u32,
+    rng: SplitMix64,
+    // Next dimension to use, and the end of the ones of the current use
+    dimension: u32,
+    end: u32,
+    bounce: u32,
+}
+
+impl Sampler {
+    pub fn new(kind: SamplerKind, seed: u64, samples_per_pixel: u32) -> Sampler {
+        Sampler {
+            kind,
+            seed,
+            samples_per_pixel: samples_per_pixel.max(1),
+            pixel_seed: seed,
+            sample: 0,
+            rng: SplitMix64::new(seed),
+            dimension: 0,
+            end: 0,
+            bounce: 0,
+        }
+    }
+
+    pub fn start_sample(&mut self, x: u32, y: u32, sample: u32) {
+        self.pixel_seed = hash(&[self.seed, u64::from(x), u64::from(y)]);
+        self.sample = sample;
+        self.rng = SplitMix64::new(hash(&[self.pixel_seed, u64::from(sample)]));
+        self.bounce = 0;
+        self.start(Dimensions::Pixel);
+    }
+
+    pub fn start(&mut self, dimensions: Dimensions) {
+        let (first, count) = match dimensions {
+            Dimensions::Pixel => (PIXEL_DIMENSION, 2),
+            Dimensions::Lens => (LENS_DIMENSION, 2),
+            Dimensions::Time => (TIME_DIMENSION, 1),
+            Dimensions::Scatter => {
+                self.bounce += 1;
+                (self.bounce_dimension(), LIGHT_OFFSET)
+            }
+            Dimensions::Light => (
+                self.bounce_dimension() + LIGHT_OFFSET,
+                MEDIUM_OFFSET - LIGHT_OFFSET,
+            ),
+            // The ray reaches the next bounce, whose scattering is yet to start
+            Dimensions::Medium => (
+                FIRST_BOUNCE_DIMENSION + BOUNCE_DIMENSIONS * self.bounce + MEDIUM_OFFSET,
+                BOUNCE_DIMENSIONS - MEDIUM_OFFSET,
+            ),
+        };
+        self.dimension = first;
+        self.end = first + count;
+    }
+
+    fn bounce_dimension(&self) -> u32 {
+        FIRST_BOUNCE_DIMENSION + BOUNCE_DIMENSIONS * (self.bounce.max(1) - 1)
+    }
+
+    // Takes the next `count` dimensions, None once the current use has run out
+    // of them, its next numbers being independent
+    fn take(&mut self, count: u32) -> Option<u32> {
+        let dimension = self.dimension;
+        if dimension + count > self.end {
+            return None;
+        }
+        self.dimension += count;
+        Some(dimension)
+    }
+
+    fn dimension_seed(&self, dimension: u32, purpose: u64) -> u32 {
+        hash(&[self.pixel_seed, u64::from(dimension), purpose]) as u32
+    }
+
+    // Uniform in [0, 1)
+    pub fn get_1d(&mut self) -> f32 {
+        if self.kind == SamplerKind::Independent {
+            return self.rng.next_f32();
+        }
+        match self.take(1) {
+            Some(dimension) => self.sample_1d(dimension),
+            None => self.rng.next_f32(),
+        }
+    }
+
+    // Uniform in [0, 1)²
+    pub fn get_2d(&mut self) -> (f32, f32) {
+        if self.kind == SamplerKind::Independent {
+            return (self.rng.next_f32(), self.rng.next_f32());
+        }
+        match self.take(2) {
+            Some(dimension) => self.sample_2d(dimension),
+            None => (self.rng.next_f32(), self.rng.next_f32()),
+        }
+    }
+
+    // Sample index among the samples of its set of `samples_per_pixel`, and the
+    // set, as the strata of each set are shuffled anew
+    fn index_in_set(&self) -> (u32, u64) {
+        (
+            self.sample % self.samples_per_pixel,
+            u64::from(self.sample / self.samples_per_pixel),
+        )
+    }
+
+    fn sample_1d(&mut self, dimension: u32) -> f32 {
+        match self.kind {
+            SamplerKind::Independent => self.rng.next_f32(),
+            SamplerKind::Stratified => {
+                let (index, set) = self.index_in_set();
+                let strata = self.samples_per_pixel;
+                let stratum = permute(index, strata, self.dimension_seed(dimension, set));
+                (stratum as f32 + self.rng.next_f32()) / strata as f32
+            }
+            SamplerKind::Halton => self.halton(dimension),
+            SamplerKind::Sobol => {
+                let index = owen_scramble(self.sample, self.dimension_seed(dimension, 0));
+                unit_f32(owen_scramble(
+                    sobol(index, 0),
+                    self.dimension_seed(dimension, 1),
+                ))
+            }
+        }
+    }
+
+    fn sample_2d(&mut self, dimension: u32) -> (f32, f32) {
+        match self.kind {
+            SamplerKind::Stratified => {
+                let (index, set) = self.index_in_set();
+                // Grid of at least as many strata as samples, the samples of a set
+                // falling in different ones
+                let columns = (self.samples_per_pixel as f32).sqrt() as u32;
+                let rows = self.samples_per_pixel.div_ceil(columns);
+                let stratum = permute(index, columns * rows, self.dimension_seed(dimension, set));
+                (
+                    ((stratum % columns) as f32 + self.rng.next_f32()) / columns as f32,
+                    ((stratum / columns) as f32 + self.rng.next_f32()) / rows as f32,
+                )
+            }
+            SamplerKind::Sobol => {
+                let index = owen_scramble(self.sample, self.dimension_seed(dimension, 0));
+                (
+                    unit_f32(owen_scramble(
+                        sobol(index, 0),
+                        self.dimension_seed(dimension, 1),
+                    )),
+                    unit_f32(owen_scramble(
+                        sobol(index, 1),
+                        self.dimension_seed(dimension, 2),
+                    )),
+                )
+            }
+            _ => (self.sample_1d(dimension), self.sample_1d(dimension + 1)),
+        }
+    }
+
+    fn halton(&mut self, dimension: u32) -> f32 {
+        match PRIMES.get(dimension as usize) {
+            Some(&base) => {
+                let shift = f64::from(unit_f32(self.dimension_seed(dimension, 0)));
+                let value = (radical_inverse(base, self.sample) + shift).fract();
+                (value as f32).min(ONE_MINUS_EPSILON)
+            }
+            None => self.rng.next_f32(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pairs of numbers of the samples of a pixel, for the given use
+    fn points(kind: SamplerKind, samples: u32, dimensions: Dimensions) -> Vec<(f32, f32)> {
+        let mut sampler = Sampler::new(kind, 9, samples);
+        (0..samples)
+            .map(|sample| {
+                sampler.start_sample(3, 5, sample);
+                sampler.start(dimensions);
+                sampler.get_2d()
+            })
+            .collect()
+    }
+
+    // Whether each cell of a columns x rows grid holds one of the points
+    fn one_per_cell(points: &[(f32, f32)], columns: usize, rows: usize) -> bool {
+        let mut counts = vec![0; columns * rows];
+        for &(x, y) in points {
+            counts[(y * rows as f32) as usize * columns + (x * columns as f32) as usize] += 1;
+        }
+        counts.iter().all(|&count| count == 1)
+    }
+
+    #[test]
+    fn permutations_take_every_index_once() {
+        for &length in [1, 2, 7, 16, 100].iter() {
+            for seed in 0..10 {
+                let mut taken: Vec<u32> = (0..length).map(|i| permute(i, length, seed)).collect();
+                taken.sort_unstable();
+                assert_eq!(taken, (0..length).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_samples_fall_in_different_strata() {
+        // A square grid, a rectangular one and the strata of a single dimension
+        assert!(one_per_cell(
+            &points(SamplerKind::Stratified, 16, Dimensions::Pixel),
+            4,
+            4
+        ));
+        assert!(one_per_cell(
+            &points(SamplerKind::Stratified, 8, Dimensions::Lens),
+            2,
+            4
+        ));
+        let mut sampler = Sampler::new(SamplerKind::Stratified, 9, 16);
+        let times: Vec<(f32, f32)> = (0..16)
+            .map(|sample| {
+                sampler.start_sample(3, 5, sample);
+                sampler.start(Dimensions::Time);
+                (sampler.get_1d(), 0.0)
+            })
+            .collect();
+        assert!(one_per_cell(&times, 16, 1));
+        // The next set of samples is spread again
+        let mut sampler = Sampler::new(SamplerKind::Stratified, 9, 16);
+        let second_set: Vec<(f32, f32)> = (16..32)
+            .map(|sample| {
+                sampler.start_sample(3, 5, sample);
+                sampler.get_2d()
+            })
+            .collect();
+        assert!(one_per_cell(&second_set, 4, 4));
+    }
+
+    #[test]
+    fn sobol_samples_fill_every_elementary_interval() {
+        for &dimensions in [Dimensions::Pixel, Dimensions::Lens].iter() {
+            let points = points(SamplerKind::Sobol, 16, dimensions);
+            for &(columns, rows) in [(1, 16), (2, 8), (4, 4), (8, 2), (16, 1)].iter() {
+                assert!(
+                    one_per_cell(&points, columns, rows),
+                    "{:?} in {}x{}",
+                    dimensions,
+                    columns,
+                    rows
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shifted_halton_samples_keep_their_spacing() {
+        // Bases 2 and 3 for the position in the pixel
+        let base_2 = points(SamplerKind::Halton, 16, Dimensions::Pixel);
+        assert!(one_per_cell(&base_2, 16, 1));
+        let base_3 = points(SamplerKind::Halton, 9, Dimensions::Pixel);
+        assert!(one_per_cell(&base_3, 1, 9));
+    }
+
+    #[test]
+    fn the_numbers_only_depend_on_the_pixel_and_the_sample() {
+        let kinds = [
+            SamplerKind::Independent,
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ];
+        for &kind in kinds.iter() {
+            let mut sampler = Sampler::new(kind, 9, 8);
+            let mut numbers = |x, sample| {
+                sampler.start_sample(x, 5, sample);
+                let mut numbers = vec![];
+                // Past the dimensions of the bounces, into the independent ones
+                for _ in 0..40 {
+                    sampler.start(Dimensions::Scatter);
+                    numbers.push(sampler.get_1d());
+                    numbers.push(sampler.get_2d().0);
+                    sampler.start(Dimensions::Light);
+                    numbers.push(sampler.get_1d());
+                    sampler.start(Dimensions::Medium);
+                    numbers.push(sampler.get_1d());
+                }
+                numbers
+            };
+            let first = numbers(3, 2);
+            assert!(first.iter().all(|n| (0.0..1.0).contains(n)), "{:?}", kind);
+            numbers(4, 2);
+            assert_eq!(numbers(3, 2), first, "{:?}", kind);
+            assert_ne!(numbers(3, 6), first, "{:?}", kind);
+            assert_ne!(numbers(4, 2), first, "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,892 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::animation::{Animation, Interpolation, Quaternion, Track};
+use crate::bvh::Aabb;
+use crate::medium::{ConstantMedium, GridMedium};
+use crate::rect::{Cuboid, XYRect, XZRect, YZRect};
+use crate::sampler::SamplerKind;
+use crate::texture::{ImageTexture, NoisePattern, Perlin, Texture};
+use crate::tile::TileOrder;
+use crate::transform::{Instance, Matrix4};
+use crate::voxel::{RawEncoding, VoxelGrid};
+use crate::{obj, Background, Camera, HitableObject, Material, MovingSphere, Sphere, Vec3, World};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct RenderSettings {
+    pub image_width: i32,
+    pub aspect_ratio: f32,
+    pub sample_per_pixel: i32,
+    pub max_depth: i32,
+    // Next event estimation towards the emissive spheres, combined with the
+    // material sampling by multiple importance sampling
+    pub light_sampling: bool,
+    // Frames per second of the animations rendered as sequences, and part of each
+    // frame during which the shutter is open
+    pub fps: f32,
+    pub shutter: f32,
+    // Seed of the random numbers of the samples, and how they are spread
+    pub seed: u64,
+    pub sampler: SamplerKind,
+    // Adaptive sampling : once a pixel has min_samples samples, it stops as soon
+    // as its error falls below the threshold, sample_per_pixel being the maximum
+    pub adaptive_threshold: Option<f32>,
+    pub min_samples: i32,
+    // Size in pixels of the square tiles shared between the threads, and the
+    // order in which they are rendered
+    pub tile_size: i32,
+    pub tile_order: TileOrder,
+}
+
+// Camera placement, the camera itself being built once the aspect ratio of the image is known
+pub struct CameraSettings {
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
+    pub vfov: f32,
+    pub aperture: f32,
+    pub focus_dist: f32,
+    pub time0: f32,
+    pub time1: f32,
+    pub animation: Option<Animation>,
+}
+
+impl CameraSettings {
+    pub fn camera(&self, aspect_ratio: f32) -> Camera {
+        let mut camera = Camera::new(
+            self.lookfrom,
+            self.lookat,
+            self.vup,
+            self.vfov,
+            aspect_ratio,
+            self.aperture,
+            self.focus_dist,
+            self.time0,
+            self.time1,
+        );
+        camera.animation = self.animation.clone();
+        camera
+    }
+}
+
+pub struct Scene {
+    pub world: World,
+    pub camera: CameraSettings,
+    pub background: Background,
+    pub settings: RenderSettings,
+}
+
+// Scene files are TOML documents such as :
+//
+// background = [0.0, 0.0, 0.0]
+//
+// [render]
+// image_width = 400
+// sample_per_pixel = 100
+//
+// [camera]
+// lookfrom = [13.0, 2.0, 3.0]
+// lookat = [0.0, 0.0, 0.0]
+// vfov = 20.0
+//
+// [materials.ground]
+// type = "lambertian"
+// albedo = { type = "checker", odd = [0.2, 0.3, 0.1], even = [0.9, 0.9, 0.9] }
+//
+// [[objects]]
+// type = "sphere"
+// center = [0.0, -1000.0, 0.0]
+// radius = 1000.0
+// material = "ground"
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDescription {
+    // Colour of the rays escaping the scene, the sky gradient when missing
+    background: Option<[f32; 3]>,
+    #[serde(default)]
+    render: RenderSettings,
+    camera: CameraDescription,
+    #[serde(default)]
+    materials: HashMap<String, Spanned<toml::Value>>,
+    // Objects that are only rendered through instances and volumes
+    #[serde(default)]
+    shapes: HashMap<String, Spanned<toml::Value>>,
+    #[serde(default)]
+    objects: Vec<Spanned<toml::Value>>,
+}
+
+// Where the settings of the [render] table are in the file, to locate their
+// errors once they are read
+#[derive(Deserialize, Default)]
+struct RenderLocations {
+    #[serde(default)]
+    render: HashMap<String, Spanned<toml::Value>>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            image_width: 400,
+            aspect_ratio: 3.0 / 2.0,
+            sample_per_pixel: 100,
+            max_depth: 50,
+            light_sampling: true,
+            fps: 24.0,
+            shutter: 0.5,
+            seed: 0,
+            sampler: SamplerKind::Independent,
+            adaptive_threshold: None,
+            min_samples: 16,
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDescription {
+    lookfrom: [f32; 3],
+    lookat: [f32; 3],
+    #[serde(default = "default_vup")]
+    vup: [f32; 3],
+    vfov: f32,
+    #[serde(default)]
+    aperture: f32,
+    // Defaults to the distance between lookfrom and lookat
+    focus_dist: Option<f32>,
+    #[serde(default)]
+    time0: f32,
+    #[serde(default = "default_time1")]
+    time1: f32,
+    // Moves the camera around lookfrom
+    animation: Option<AnimationDescription>,
+}
+
+// Keyframes of the position, rotation and scale of an object, such as
+//
+// [objects.animation]
+// interpolation = "spline"
+// position = [{ time = 0.0, value = [0.0, 0.0, 0.0] }, { time = 1.0, value = [2.0, 1.0, 0.0] }]
+// rotation = [{ time = 0.0, axis = [0.0, 1.0, 0.0], angle = 0.0 }, { time = 1.0, axis = [0.0, 1.0, 0.0], angle = 90.0 }]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AnimationDescription {
+    #[serde(default)]
+    interpolation: Interpolation,
+    #[serde(default)]
+    position: Vec<VectorKeyframe>,
+    // Consecutive rotations should be less than 180 degrees apart
+    #[serde(default)]
+    rotation: Vec<RotationKeyframe>,
+    #[serde(default)]
+    scale: Vec<VectorKeyframe>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VectorKeyframe {
+    time: f32,
+    value: [f32; 3],
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RotationKeyframe {
+    time: f32,
+    axis: [f32; 3],
+    // In degrees
+    angle: f32,
+}
+
+fn default_vup() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_time1() -> f32 {
+    1.0
+}
+
+// Materials and objects are tagged with their `type`. They are first read as raw
+// TOML values to keep their position in the file, which is then used to locate
+// errors found while converting them
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDescription {
+    Lambertian { albedo: toml::Value },
+    Metal { albedo: [f32; 3], fuzz: f32 },
+    Dielectric { ref_idx: f32 },
+    DiffuseLight { emit: toml::Value },
+}
+
+// Textures are either a colour or a table tagged with the kind of texture
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDescription {
+    Checker {
+        odd: toml::Value,
+        even: toml::Value,
+        #[serde(default = "default_scale")]
+        size: f32,
+    },
+    // PNG or PPM image, its path being relative to the scene file
+    Image {
+        path: String,
+    },
+    Noise {
+        #[serde(default = "default_scale")]
+        scale: f32,
+        #[serde(default)]
+        seed: u64,
+    },
+    Turbulence {
+        #[serde(default = "default_scale")]
+        scale: f32,
+        #[serde(default)]
+        seed: u64,
+    },
+    Marble {
+        #[serde(default = "default_scale")]
+        scale: f32,
+        #[serde(default)]
+        seed: u64,
+    },
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ObjectDescription {
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+        material: String,
+    },
+    MovingSphere {
+        center0: [f32; 3],
+        center1: [f32; 3],
+        time0: f32,
+        time1: f32,
+        radius: f32,
+        material: String,
+    },
+    // OBJ model, its path being relative to the scene file. Faces without
+    // a material from the MTL files use `material`
+    Mesh {
+        path: String,
+        material: String,
+    },
+    // Axis aligned rectangles, in the plane where the third coordinate is `k`
+    XyRect {
+        x0: f32,
+        x1: f32,
+        y0: f32,
+        y1: f32,
+        k: f32,
+        material: String,
+    },
+    XzRect {
+        x0: f32,
+        x1: f32,
+        z0: f32,
+        z1: f32,
+        k: f32,
+        material: String,
+    },
+    YzRect {
+        y0: f32,
+        y1: f32,
+        z0: f32,
+        z1: f32,
+        k: f32,
+        material: String,
+    },
+    // Axis aligned box between two opposite corners
+    Box {
+        p0: [f32; 3],
+        p1: [f32; 3],
+        material: String,
+    },
+    // One of the shapes, moved by transforms applied in order
+    Instance {
+        shape: String,
+        #[serde(default)]
+        transform: Vec<TransformDescription>,
+        // Applied before the transforms, so it rotates and scales the shape around
+        // its own origin
+        animation: Option<AnimationDescription>,
+    },
+    // Volume filling one of the shapes, whose material is not used. The albedo
+    // is a colour or a texture
+    ConstantMedium {
+        boundary: String,
+        density: f32,
+        albedo: toml::Value,
+    },
+    // Volume of densities from a voxel grid, stretched over the box between `min`
+    // and `max`. Files with a `resolution` are raw values, the others Mitsuba
+    // .vol files whose box is used when none is given
+    GridMedium {
+        path: String,
+        resolution: Option<[usize; 3]>,
+        #[serde(default = "default_encoding")]
+        encoding: RawEncoding,
+        min: Option<[f32; 3]>,
+        max: Option<[f32; 3]>,
+        #[serde(default = "default_scale")]
+        density_scale: f32,
+        albedo: Option<toml::Value>,
+    },
+}
+
+fn default_encoding() -> RawEncoding {
+    RawEncoding::F32
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum TransformDescription {
+    Translate([f32; 3]),
+    Scale([f32; 3]),
+    // Angles in degrees
+    RotateX(f32),
+    RotateY(f32),
+    RotateZ(f32),
+    Rotate { axis: [f32; 3], angle: f32 },
+    // Rows of an affine matrix, the last one being [0, 0, 0, 1]
+    Matrix([[f32; 4]; 4]),
+}
+
+fn to_vec3(v: [f32; 3]) -> Vec3 {
+    Vec3 {
+        x: v[0],
+        y: v[1],
+        z: v[2],
+    }
+}
+
+// Shape used by an instance or a volume
+fn referenced_shape(object: &ObjectDescription) -> Option<&str> {
+    match object {
+        ObjectDescription::Instance { shape, .. } => Some(shape),
+        ObjectDescription::ConstantMedium { boundary, .. } => Some(boundary),
+        _ => None,
+    }
+}
+
+fn to_animation(description: AnimationDescription) -> Result<Animation, String> {
+    let interpolation = description.interpolation;
+    let vectors = |keys: Vec<VectorKeyframe>| {
+        keys.into_iter()
+            .map(|key| (key.time, to_vec3(key.value)))
+            .collect::<Vec<_>>()
+    };
+    let position = vectors(description.position);
+    let scale = vectors(description.scale);
+    if scale
+        .iter()
+        .any(|(_, s)| !(s.x > 0.0 && s.y > 0.0 && s.z > 0.0))
+    {
+        return Err("the scale keyframes must be positive".to_string());
+    }
+    let rotation: Vec<_> = description
+        .rotation
+        .into_iter()
+        .map(|key| {
+            (
+                key.time,
+                Quaternion::from_axis_angle(to_vec3(key.axis), key.angle),
+            )
+        })
+        .collect();
+    if position.is_empty() && rotation.is_empty() && scale.is_empty() {
+        return Err("an animation needs keyframes".to_string());
+    }
+    let track = |keys: Vec<(f32, Vec3)>| {
+        if keys.is_empty() {
+            Ok(None)
+        } else {
+            Track::new(keys, interpolation).map(Some)
+        }
+    };
+    Ok(Animation {
+        position: track(position)?,
+        scale: track(scale)?,
+        rotation: if rotation.is_empty() {
+            None
+        } else {
+            Some(Track::rotations(rotation, interpolation)?)
+        },
+    })
+}
+
+// Composes the transforms, the first one of the list being applied first
+fn to_matrix(transforms: &[TransformDescription]) -> Matrix4 {
+    let axis = |x, y, z| Vec3 { x, y, z };
+    transforms
+        .iter()
+        .fold(Matrix4::identity(), |matrix, transform| {
+            let next = match transform {
+                TransformDescription::Translate(offset) => Matrix4::translation(to_vec3(*offset)),
+                TransformDescription::Scale(factors) => Matrix4::scaling(to_vec3(*factors)),
+                TransformDescription::RotateX(angle) => {
+                    Matrix4::rotation(axis(1.0, 0.0, 0.0), *angle)
+                }
+                TransformDescription::RotateY(angle) => {
+                    Matrix4::rotation(axis(0.0, 1.0, 0.0), *angle)
+                }
+                TransformDescription::RotateZ(angle) => {
+                    Matrix4::rotation(axis(0.0, 0.0, 1.0), *angle)
+                }
+                TransformDescription::Rotate { axis, angle } => {
+                    Matrix4::rotation(to_vec3(*axis), *angle)
+                }
+                TransformDescription::Matrix(m) => Matrix4 { m: *m },
+            };
+            next.mul(&matrix)
+        })
+}
+
+fn to_texture(value: toml::Value, directory: &Path) -> Result<Texture, String> {
+    if let toml::Value::Array(_) = value {
+        let color: [f32; 3] = value.try_into().map_err(|e| e.message().to_string())?;
+        return Ok(Texture::Solid(to_vec3(color)));
+    }
+    let noise = |pattern, scale, seed| Texture::Noise {
+        perlin: Arc::new(Perlin::new(seed)),
+        pattern,
+        scale,
+    };
+    Ok(
+        match value
+            .try_into()
+            .map_err(|e: toml::de::Error| e.message().to_string())?
+        {
+            TextureDescription::Checker { odd, even, size } => Texture::Checker {
+                odd: Box::new(to_texture(odd, directory)?),
+                even: Box::new(to_texture(even, directory)?),
+                size,
+            },
+            TextureDescription::Image { path } => {
+                Texture::Image(Arc::new(ImageTexture::load(&directory.join(path))?))
+            }
+            TextureDescription::Noise { scale, seed } => noise(NoisePattern::Smooth, scale, seed),
+            TextureDescription::Turbulence { scale, seed } => {
+                noise(NoisePattern::Turbulence, scale, seed)
+            }
+            TextureDescription::Marble { scale, seed } => noise(NoisePattern::Marble, scale, seed),
+        },
+    )
+}
+
+fn to_object(
+    object: ObjectDescription,
+    directory: &Path,
+    materials: &HashMap<String, Material>,
+    shapes: &HashMap<String, Arc<HitableObject>>,
+) -> Result<HitableObject, String> {
+    let find_material = |name: &str| {
+        materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown material {:?}", name))
+    };
+    let find_shape = |name: &str| {
+        shapes
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("unknown shape {:?}", name))
+    };
+    Ok(match object {
+        ObjectDescription::Sphere {
+            center,
+            radius,
+            material,
+        } => HitableObject::Sphere(Sphere {
+            center: to_vec3(center),
+            radius,
+            material: find_material(&material)?,
+        }),
+        ObjectDescription::MovingSphere {
+            center0,
+            center1,
+            time0,
+            time1,
+            radius,
+            material,
+        } => HitableObject::MovingSphere(MovingSphere {
+            center0: to_vec3(center0),
+            center1: to_vec3(center1),
+            time0,
+            time1,
+            radius,
+            material: find_material(&material)?,
+        }),
+        ObjectDescription::Mesh {
+            path: mesh_path,
+            material,
+        } => HitableObject::Mesh(obj::load_obj(
+            &directory.join(mesh_path),
+            find_material(&material)?,
+        )?),
+        ObjectDescription::XyRect {
+            x0,
+            x1,
+            y0,
+            y1,
+            k,
+            material,
+        } => HitableObject::XYRect(XYRect {
+            x0: x0.min(x1),
+            x1: x0.max(x1),
+            y0: y0.min(y1),
+            y1: y0.max(y1),
+            k,
+            material: find_material(&material)?,
+        }),
+        ObjectDescription::XzRect {
+            x0,
+            x1,
+            z0,
+            z1,
+            k,
+            material,
+        } => HitableObject::XZRect(XZRect {
+            x0: x0.min(x1),
+            x1: x0.max(x1),
+            z0: z0.min(z1),
+            z1: z0.max(z1),
+            k,
+            material: find_material(&material)?,
+        }),
+        ObjectDescription::YzRect {
+            y0,
+            y1,
+            z0,
+            z1,
+            k,
+            material,
+        } => HitableObject::YZRect(YZRect {
+            y0: y0.min(y1),
+            y1: y0.max(y1),
+            z0: z0.min(z1),
+            z1: z0.max(z1),
+            k,
+            material: find_material(&material)?,
+        }),
+        ObjectDescription::Box { p0, p1, material } => {
+            let (p0, p1) = (to_vec3(p0), to_vec3(p1));
+            HitableObject::Box(Cuboid {
+                min: Vec3 {
+                    x: p0.x.min(p1.x),
+                    y: p0.y.min(p1.y),
+                    z: p0.z.min(p1.z),
+                },
+                max: Vec3 {
+                    x: p0.x.max(p1.x),
+                    y: p0.y.max(p1.y),
+                    z: p0.z.max(p1.z),
+                },
+                material: find_material(&material)?,
+            })
+        }
+        ObjectDescription::Instance {
+            shape,
+            transform,
+            animation,
+        } => {
+            let mut instance = Instance::new(find_shape(&shape)?, to_matrix(&transform))?;
+            instance.animation = animation.map(to_animation).transpose()?;
+            HitableObject::Instance(instance)
+        }
+        ObjectDescription::ConstantMedium {
+            boundary,
+            density,
+            albedo,
+        } => {
+            if density <= 0.0 {
+                return Err("the density must be positive".to_string());
+            }
+            HitableObject::ConstantMedium(ConstantMedium {
+                boundary: find_shape(&boundary)?,
+                density,
+                phase_function: Material::Isotropic {
+                    albedo: to_texture(albedo, directory)?,
+                },
+            })
+        }
+        ObjectDescription::GridMedium {
+            path: grid_path,
+            resolution,
+            encoding,
+            min,
+            max,
+            density_scale,
+            albedo,
+        } => {
+            if density_scale < 0.0 {
+                return Err("the density scale cannot be negative".to_string());
+            }
+            let grid_path = directory.join(grid_path);
+            let grid = match resolution {
+                Some(resolution) => VoxelGrid::load_raw(&grid_path, resolution, encoding)?,
+                None => VoxelGrid::load_vol(&grid_path)?,
+            };
+            let bounds = match (min, max, grid.bounds) {
+                (Some(min), Some(max), _) => Aabb {
+                    min: to_vec3(min),
+                    max: to_vec3(max),
+                },
+                (None, None, Some(bounds)) => bounds,
+                _ => return Err("the box of the grid needs both min and max".to_string()),
+            };
+            let size = bounds.max - bounds.min;
+            if !(size.x > 0.0 && size.y > 0.0 && size.z > 0.0) {
+                return Err("max must be greater than min on every axis".to_string());
+            }
+            let albedo = match albedo {
+                Some(albedo) => to_texture(albedo, directory)?,
+                None => Texture::Solid(Vec3 {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                }),
+            };
+            HitableObject::GridMedium(GridMedium::new(
+                Arc::new(grid),
+                bounds,
+                density_scale,
+                Material::Isotropic { albedo },
+            ))
+        }
+    })
+}
+
+// Tables of a map in the order they appear in the file, so that the first of
+// their errors is the one reported
+fn in_file_order(
+    tables: HashMap<String, Spanned<toml::Value>>,
+) -> Vec<(String, Spanned<toml::Value>)> {
+    let mut tables: Vec<_> = tables.into_iter().collect();
+    tables.sort_by_key(|(_, value)| value.span().start);
+    tables
+}
+
+// Converts a byte offset in the file into a 1-based line and column
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, column)
+}
+
+fn located_error(path: &Path, content: &str, span: Option<Range<usize>>, message: &str) -> String {
+    match span {
+        Some(span) => {
+            let (line, column) = line_column(content, span.start);
+            format!("{}:{}:{}: {}", path.display(), line, column, message)
+        }
+        None => format!("{}: {}", path.display(), message),
+    }
+}
+
+pub fn load_scene(path: &Path) -> Result<Scene, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse_scene(&content, path)
+}
+
+// Scene of the content of a scene file, `path` locating the files it uses and
+// naming the file in the errors
+fn parse_scene(content: &str, path: &Path) -> Result<Scene, String> {
+    let description: SceneDescription =
+        toml::from_str(content).map_err(|e| located_error(path, content, e.span(), e.message()))?;
+
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut materials = HashMap::new();
+    for (name, value) in in_file_order(description.materials) {
+        let span = value.span();
+        let error = |message: &str| {
+            let message = format!("material {:?}: {}", name, message);
+            located_error(path, content, Some(span.clone()), &message)
+        };
+        let material = match value
+            .into_inner()
+            .try_into()
+            .map_err(|e: toml::de::Error| error(e.message()))?
+        {
+            MaterialDescription::Lambertian { albedo } => Material::Lambertian {
+                albedo: to_texture(albedo, directory).map_err(|e| error(&e))?,
+            },
+            MaterialDescription::Metal { albedo, fuzz } => Material::Metal {
+                albedo: to_vec3(albedo),
+                fuzz,
+            },
+            MaterialDescription::Dielectric { ref_idx } => Material::Dielectric { ref_idx },
+            MaterialDescription::DiffuseLight { emit } => Material::DiffuseLight {
+                emit: to_texture(emit, directory).map_err(|e| error(&e))?,
+            },
+        };
+        materials.insert(name, material);
+    }
+
+    let shape_error = |name: &str, span: &Range<usize>, message: &str| {
+        let message = format!("shape {:?}: {}", name, message);
+        located_error(path, content, Some(span.clone()), &message)
+    };
+    let mut pending = vec![];
+    for (name, value) in in_file_order(description.shapes) {
+        let span = value.span();
+        let object: ObjectDescription = value
+            .into_inner()
+            .try_into()
+            .map_err(|e: toml::de::Error| shape_error(&name, &span, e.message()))?;
+        pending.push((name, span, object));
+    }
+    // Shapes can be instances or volumes of other shapes, so they are built once
+    // the shapes they use are
+    let mut shapes = HashMap::new();
+    while !pending.is_empty() {
+        let waiting: Vec<String> = pending.iter().map(|(name, _, _)| name.clone()).collect();
+        let (blocked, ready): (Vec<_>, Vec<_>) = pending.into_iter().partition(|(_, _, object)| {
+            referenced_shape(object).is_some_and(|shape| waiting.iter().any(|name| name == shape))
+        });
+        if ready.is_empty() {
+            let (name, span, _) = &blocked[0];
+            return Err(shape_error(
+                name,
+                span,
+                "the shapes use each other in a cycle",
+            ));
+        }
+        for (name, span, object) in ready {
+            let shape = to_object(object, directory, &materials, &shapes)
+                .map_err(|e| shape_error(&name, &span, &e))?;
+            shapes.insert(name, Arc::new(shape));
+        }
+        pending = blocked;
+    }
+
+    let mut objects = vec![];
+    for value in description.objects {
+        let span = value.span();
+        let error = |message: &str| located_error(path, content, Some(span.clone()), message);
+        let object: ObjectDescription = value
+            .into_inner()
+            .try_into()
+            .map_err(|e: toml::de::Error| error(e.message()))?;
+        objects.push(to_object(object, directory, &materials, &shapes).map_err(|e| error(&e))?);
+    }
+    let settings = description.render;
+    let locations: RenderLocations = toml::from_str(content).unwrap_or_default();
+    let render_error = |field: &str, message: &str| {
+        let span = locations.render.get(field).map(|value| value.span());
+        located_error(
+            path,
+            content,
+            span,
+            &format!("render: {} {}", field, message),
+        )
+    };
+    let positive = [
+        ("image_width", settings.image_width > 0),
+        ("aspect_ratio", settings.aspect_ratio > 0.0),
+        ("sample_per_pixel", settings.sample_per_pixel > 0),
+        ("max_depth", settings.max_depth > 0),
+        ("tile_size", settings.tile_size > 0),
+        ("fps", settings.fps > 0.0),
+        ("min_samples", settings.min_samples > 0),
+        (
+            "adaptive_threshold",
+            settings.adaptive_threshold.is_none_or(|t| t > 0.0),
+        ),
+    ];
+    if let Some((field, _)) = positive.iter().find(|(_, valid)| !valid) {
+        return Err(render_error(field, "must be positive"));
+    }
+    if !(0.0..=1.0).contains(&settings.shutter) {
+        return Err(render_error("shutter", "must be between 0 and 1"));
+    }
+    let camera = description.camera;
+    let lookfrom = to_vec3(camera.lookfrom);
+    let lookat = to_vec3(camera.lookat);
+    Ok(Scene {
+        world: World::new(objects),
+        camera: CameraSettings {
+            lookfrom,
+            lookat,
+            vup: to_vec3(camera.vup),
+            vfov: camera.vfov,
+            aperture: camera.aperture,
+            focus_dist: camera
+                .focus_dist
+                .unwrap_or_else(|| (lookfrom - lookat).length()),
+            time0: camera.time0,
+            time1: camera.time1,
+            animation: camera
+                .animation
+                .map(to_animation)
+                .transpose()
+                .map_err(|e| format!("{}: camera: {}", path.display(), e))?,
+        },
+        background: description
+            .background
+            .map_or(Background::Sky, |color| Background::Color(to_vec3(color))),
+        settings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str =
+        "[camera]\nlookfrom = [13.0, 2.0, 3.0]\nlookat = [0.0, 0.0, 0.0]\nvfov = 20.0\n";
+
+    fn error(content: &str) -> String {
+        match parse_scene(content, Path::new("scene.toml")) {
+            Ok(_) => panic!("{:?} was accepted", content),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn syntax_errors_give_their_line_and_column() {
+        let e = error(&format!("{}aperture = 0.1.2\n", CAMERA));
+        assert!(e.starts_with("scene.toml:5:15: "), "{}", e);
+        let e = error(&format!("{}[render]\nimage_width = \"wide\"\n", CAMERA));
+        assert!(e.starts_with("scene.toml:6:15: "), "{}", e);
+    }
+
+    #[test]
+    fn bad_render_settings_give_their_line_and_column() {
+        let e = error(&format!("{}[render]\nseed = 3\nimage_width = 0\n", CAMERA));
+        assert_eq!(e, "scene.toml:7:15: render: image_width must be positive");
+        let e = error(&format!("{}[render]\nshutter = 1.5\n", CAMERA));
+        assert_eq!(
+            e,
+            "scene.toml:6:11: render: shutter must be between 0 and 1"
+        );
+    }
+
+    #[test]
+    fn the_first_bad_material_is_reported() {
+        let content = format!(
+            "{}[materials.b]\ntype = \"metal\"\n\n[materials.a]\ntype = \"glass\"\n",
+            CAMERA
+        );
+        for _ in 0..10 {
+            let e = error(&content);
+            assert!(e.starts_with("scene.toml:5:1: material \"b\": "), "{}", e);
+        }
+    }
+}
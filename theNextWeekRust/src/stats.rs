@@ -0,0 +1,208 @@
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+// Events counted while rendering, by each thread on its own
+#[derive(Debug, Copy, Clone)]
+pub enum Counter {
+    // Rays followed along the paths, the camera rays included
+    PathRays,
+    ShadowRays,
+    // Bounding boxes and objects tested by the hierarchies, the triangles of the
+    // meshes included
+    BoxTests,
+    PrimitiveTests,
+    // Paths stopped by max_depth rather than by escaping or being absorbed
+    MaxDepthPaths,
+}
+
+const COUNTERS: usize = 5;
+
+thread_local! {
+    static COUNTS: [Cell<u64>; COUNTERS] = const {
+        [Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0)]
+    };
+}
+
+// Whether the intersection tests, counted in the inner loop of the hierarchies,
+// are added to the statistics. The other counts are cheap enough to be kept
+static INTERSECTION_TESTS: AtomicBool = AtomicBool::new(false);
+
+pub fn count_intersection_tests() {
+    INTERSECTION_TESTS.store(true, Ordering::Relaxed);
+}
+
+pub fn counts_intersection_tests() -> bool {
+    INTERSECTION_TESTS.load(Ordering::Relaxed)
+}
+
+pub fn count(counter: Counter) {
+    add(counter, 1);
+}
+
+pub fn add(counter: Counter, events: u64) {
+    COUNTS.with(|counts| {
+        let count = &counts[counter as usize];
+        count.set(count.get() + events);
+    });
+}
+
+pub fn get(counter: Counter) -> u64 {
+    COUNTS.with(|counts| counts[counter as usize].get())
+}
+
+// Counts of the current thread, which start again from 0
+fn take() -> [u64; COUNTERS] {
+    COUNTS.with(|counts| {
+        let mut taken = [0; COUNTERS];
+        for (taken, count) in taken.iter_mut().zip(counts.iter()) {
+            *taken = count.replace(0);
+        }
+        taken
+    })
+}
+
+// Counts of a render, gathered from the threads
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub camera_rays: u64,
+    pub path_rays: u64,
+    pub shadow_rays: u64,
+    pub box_tests: u64,
+    pub primitive_tests: u64,
+    pub max_depth_paths: u64,
+    // Number of paths of each number of rays
+    pub path_lengths: Vec<u64>,
+}
+
+impl Stats {
+    // Forgets what the current thread counted so far, before a render
+    pub fn reset_thread() {
+        take();
+    }
+
+    pub fn add_path(&mut self, rays: u64) {
+        let rays = rays as usize;
+        if self.path_lengths.len() <= rays {
+            self.path_lengths.resize(rays + 1, 0);
+        }
+        self.path_lengths[rays] += 1;
+        self.camera_rays += 1;
+    }
+
+    // Adds what the current thread counted since the last time
+    pub fn add_thread_counts(&mut self) {
+        let [path_rays, shadow_rays, box_tests, primitive_tests, max_depth_paths] = take();
+        self.path_rays += path_rays;
+        self.shadow_rays += shadow_rays;
+        self.box_tests += box_tests;
+        self.primitive_tests += primitive_tests;
+        self.max_depth_paths += max_depth_paths;
+    }
+
+    pub fn merge(&mut self, other: &Stats) {
+        self.camera_rays += other.camera_rays;
+        self.path_rays += other.path_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.box_tests += other.box_tests;
+        self.primitive_tests += other.primitive_tests;
+        self.max_depth_paths += other.max_depth_paths;
+        if self.path_lengths.len() < other.path_lengths.len() {
+            self.path_lengths.resize(other.path_lengths.len(), 0);
+        }
+        for (count, other) in self.path_lengths.iter_mut().zip(&other.path_lengths) {
+            *count += other;
+        }
+    }
+}
+
+// Seconds taken by each part of the render of a frame
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Timings {
+    // Reading the scene and building the hierarchy of its static objects,
+    // shared by the frames of a sequence
+    pub scene: f64,
+    // Building the hierarchy of the moving objects
+    pub hierarchy: f64,
+    pub render: f64,
+    // Writing the images, sample maps and checkpoints
+    pub output: f64,
+    // Whole frame, the scene aside
+    pub total: f64,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    frame: Option<u32>,
+    width: usize,
+    height: usize,
+    rays: Rays,
+    intersection_tests: IntersectionTests,
+    paths: Paths<'a>,
+    seconds: &'a Timings,
+}
+
+#[derive(Serialize)]
+struct Rays {
+    camera: u64,
+    secondary: u64,
+    shadow: u64,
+    total: u64,
+    per_second: f64,
+}
+
+#[derive(Serialize)]
+struct IntersectionTests {
+    boxes: u64,
+    primitives: u64,
+}
+
+#[derive(Serialize)]
+struct Paths<'a> {
+    // lengths[n] paths of n rays
+    lengths: &'a [u64],
+    average_length: f64,
+    max_depth_terminations: u64,
+}
+
+// JSON report of the render of a frame of `width` by `height` pixels
+pub fn write_report(
+    output: &mut dyn Write,
+    stats: &Stats,
+    timings: &Timings,
+    frame: Option<u32>,
+    width: usize,
+    height: usize,
+) -> io::Result<()> {
+    let total = stats.path_rays + stats.shadow_rays;
+    let report = Report {
+        frame,
+        width,
+        height,
+        rays: Rays {
+            camera: stats.camera_rays,
+            secondary: stats.path_rays - stats.camera_rays,
+            shadow: stats.shadow_rays,
+            total,
+            per_second: if timings.render > 0.0 {
+                total as f64 / timings.render
+            } else {
+                0.0
+            },
+        },
+        intersection_tests: IntersectionTests {
+            boxes: stats.box_tests,
+            primitives: stats.primitive_tests,
+        },
+        paths: Paths {
+            lengths: &stats.path_lengths,
+            average_length: stats.path_rays as f64 / stats.camera_rays.max(1) as f64,
+            max_depth_terminations: stats.max_depth_paths,
+        },
+        seconds: timings,
+    };
+    serde_json::to_writer_pretty(&mut *output, &report)?;
+    writeln!(output)
+}
@@ -0,0 +1,267 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use crate::{image, Vec3};
+
+// Colour of a surface, looked up at the (u, v) coordinates and the position of a hit
+#[derive(Debug, Clone)]
+pub enum Texture {
+    Solid(Vec3),
+    // Alternates between two textures in cubes of `size` world units
+    Checker {
+        odd: Box<Texture>,
+        even: Box<Texture>,
+        size: f32,
+    },
+    Image(Arc<ImageTexture>),
+    Noise {
+        perlin: Arc<Perlin>,
+        pattern: NoisePattern,
+        scale: f32,
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum NoisePattern {
+    Smooth,
+    Turbulence,
+    // Veins following the z axis, disturbed by turbulence
+    Marble,
+}
+
+impl Texture {
+    pub fn value(&self, u: f32, v: f32, p: Vec3) -> Vec3 {
+        match self {
+            Texture::Solid(color) => *color,
+            Texture::Checker { odd, even, size } => {
+                let cell = (p.x / size).floor() + (p.y / size).floor() + (p.z / size).floor();
+                if cell as i64 % 2 == 0 {
+                    even.value(u, v, p)
+                } else {
+                    odd.value(u, v, p)
+                }
+            }
+            Texture::Image(image) => image.value(u, v),
+            Texture::Noise {
+                perlin,
+                pattern,
+                scale,
+            } => {
+                let intensity = match pattern {
+                    NoisePattern::Smooth => 0.5 * (1.0 + perlin.noise(p * *scale)),
+                    NoisePattern::Turbulence => perlin.turbulence(p * *scale, 7),
+                    NoisePattern::Marble => {
+                        0.5 * (1.0 + (*scale * p.z + 10.0 * perlin.turbulence(p, 7)).sin())
+                    }
+                };
+                Vec3 {
+                    x: intensity,
+                    y: intensity,
+                    z: intensity,
+                }
+            }
+        }
+    }
+}
+
+// Image stored as linear colours, rows from top to bottom
+#[derive(Debug)]
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl ImageTexture {
+    // Loads a PNG or PPM image. The files are gamma encoded the way the renderer
+    // writes them, so their values are squared to get back linear colours
+    pub fn load(path: &Path) -> Result<ImageTexture, String> {
+        let (width, height, rgb) = image::read(path)?;
+        let pixels = rgb
+            .chunks(3)
+            .map(|c| Vec3 {
+                x: c[0] * c[0],
+                y: c[1] * c[1],
+                z: c[2] * c[2],
+            })
+            .collect();
+        Ok(ImageTexture {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // Nearest pixel lookup, the image being repeated outside of [0, 1]
+    fn value(&self, u: f32, v: f32) -> Vec3 {
+        let u = u.rem_euclid(1.0);
+        // v goes up while the rows go down
+        let v = 1.0 - v.rem_euclid(1.0);
+        let i = ((u * self.width as f32) as usize).min(self.width - 1);
+        let j = ((v * self.height as f32) as usize).min(self.height - 1);
+        self.pixels[j * self.width + i]
+    }
+}
+
+const POINT_COUNT: usize = 256;
+
+// Gradient noise, with random unit vectors on a lattice hashed by permutation tables
+#[derive(Debug)]
+pub struct Perlin {
+    random_vectors: Vec<Vec3>,
+    permutation_x: Vec<usize>,
+    permutation_y: Vec<usize>,
+    permutation_z: Vec<usize>,
+}
+
+impl Perlin {
+    // The noise only depends on the seed
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = StdRng::from_seed(&[seed as usize][..]);
+        let random_vectors = (0..POINT_COUNT)
+            .map(|_| Vec3::random(&mut rng, -1.0, 1.0).unit_vector())
+            .collect();
+        let mut permutation = || {
+            let mut p: Vec<usize> = (0..POINT_COUNT).collect();
+            rng.shuffle(&mut p);
+            p
+        };
+        Perlin {
+            permutation_x: permutation(),
+            permutation_y: permutation(),
+            permutation_z: permutation(),
+            random_vectors,
+        }
+    }
+
+    // Value in [-1, 1], interpolated between the gradients of the 8 surrounding lattice points
+    pub fn noise(&self, p: Vec3) -> f32 {
+        let u = p.x - p.x.floor();
+        let v = p.y - p.y.floor();
+        let w = p.z - p.z.floor();
+        let i = p.x.floor() as i64;
+        let j = p.y.floor() as i64;
+        let k = p.z.floor() as i64;
+
+        // Hermite smoothing of the interpolation weights
+        let uu = u * u * (3.0 - 2.0 * u);
+        let vv = v * v * (3.0 - 2.0 * v);
+        let ww = w * w * (3.0 - 2.0 * w);
+
+        let mut accumulated = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.permutation_x[((i + di) & 255) as usize]
+                        ^ self.permutation_y[((j + dj) & 255) as usize]
+                        ^ self.permutation_z[((k + dk) & 255) as usize];
+                    let weight = Vec3 {
+                        x: u - di as f32,
+                        y: v - dj as f32,
+                        z: w - dk as f32,
+                    };
+                    let (fi, fj, fk) = (di as f32, dj as f32, dk as f32);
+                    accumulated += (fi * uu + (1.0 - fi) * (1.0 - uu))
+                        * (fj * vv + (1.0 - fj) * (1.0 - vv))
+                        * (fk * ww + (1.0 - fk) * (1.0 - ww))
+                        * self.random_vectors[index].dot(weight);
+                }
+            }
+        }
+        accumulated
+    }
+
+    // Sum of `depth` octaves of noise, each one twice as fine and half as strong
+    pub fn turbulence(&self, p: Vec3, depth: i32) -> f32 {
+        let mut accumulated = 0.0;
+        let mut p = p;
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            accumulated += weight * self.noise(p);
+            weight *= 0.5;
+            p = p * 2.0;
+        }
+        accumulated.abs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    fn xyz(v: Vec3) -> [f32; 3] {
+        [v.x, v.y, v.z]
+    }
+
+    #[test]
+    fn checker_cells_alternate_across_the_axes() {
+        let checker = Texture::Checker {
+            odd: Box::new(Texture::Solid(vec3(1.0, 1.0, 1.0))),
+            even: Box::new(Texture::Solid(vec3(0.0, 0.0, 0.0))),
+            size: 2.0,
+        };
+        let odd = |x, y, z| checker.value(0.0, 0.0, vec3(x, y, z)).x == 1.0;
+        assert!(!odd(1.0, 1.0, 1.0));
+        assert!(odd(3.0, 1.0, 1.0));
+        // The cells just below 0 are the odd neighbours of the ones above it
+        assert!(odd(-1.0, 1.0, 1.0));
+        assert!(odd(1.0, 1.0, -1.0));
+        assert!(!odd(-1.0, -1.0, 1.0));
+        assert!(odd(-1.0, -1.0, -1.0));
+        assert!(!odd(-3.0, 1.0, 1.0));
+    }
+
+    // Red and green on the top row, blue and white on the bottom one
+    fn two_by_two() -> ImageTexture {
+        ImageTexture {
+            width: 2,
+            height: 2,
+            pixels: vec![
+                vec3(1.0, 0.0, 0.0),
+                vec3(0.0, 1.0, 0.0),
+                vec3(0.0, 0.0, 1.0),
+                vec3(1.0, 1.0, 1.0),
+            ],
+        }
+    }
+
+    #[test]
+    fn images_are_flipped_and_repeated() {
+        let image = two_by_two();
+        // v goes up from the bottom row
+        assert_eq!(xyz(image.value(0.25, 0.75)), [1.0, 0.0, 0.0]);
+        assert_eq!(xyz(image.value(0.75, 0.75)), [0.0, 1.0, 0.0]);
+        assert_eq!(xyz(image.value(0.25, 0.25)), [0.0, 0.0, 1.0]);
+        assert_eq!(xyz(image.value(0.75, 0.25)), [1.0, 1.0, 1.0]);
+        // Outside of [0, 1], negative coordinates included
+        assert_eq!(xyz(image.value(1.25, 2.75)), [1.0, 0.0, 0.0]);
+        assert_eq!(xyz(image.value(-0.25, -0.75)), [1.0, 1.0, 1.0]);
+        assert_eq!(xyz(image.value(-1.75, 0.25)), [0.0, 0.0, 1.0]);
+        // The edges stay in the image
+        assert_eq!(xyz(image.value(0.0, 0.0)), [0.0, 0.0, 1.0]);
+        assert_eq!(xyz(image.value(0.999_999, 0.999_999)), [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn the_noise_only_depends_on_the_seed() {
+        let points: Vec<Vec3> = (0..50)
+            .map(|i| vec3(0.37 * i as f32, -1.3 * i as f32, 2.1 + 0.11 * i as f32))
+            .collect();
+        let noise = |seed| -> Vec<f32> {
+            let perlin = Perlin::new(seed);
+            points.iter().map(|&p| perlin.noise(p)).collect()
+        };
+        assert_eq!(noise(3), noise(3));
+        assert_ne!(noise(3), noise(4));
+        assert!(noise(3).iter().all(|n| (-1.0..=1.0).contains(n)));
+        // The gradients are 0 on the lattice points
+        let perlin = Perlin::new(3);
+        assert_eq!(perlin.noise(vec3(2.0, -5.0, 7.0)), 0.0);
+    }
+}
@@ -0,0 +1,186 @@
+use serde::Deserialize;
+
+// Rectangle of pixels rendered in one go, x0 and y0 included and x1 and y1
+// excluded, by rows from the top of the image
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        self.width() * self.height()
+    }
+}
+
+// Order in which the tiles are handed to the threads
+#[derive(Debug, Default, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TileOrder {
+    // Rows of tiles from the top, left to right
+    Scanline,
+    // Rings of tiles around the centre of the image, which is seen first
+    #[default]
+    Spiral,
+    // Hilbert curve over the tiles, each thread working next to the last tiles
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "scanline" => Some(TileOrder::Scanline),
+            "spiral" => Some(TileOrder::Spiral),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None,
+        }
+    }
+}
+
+// Tiles of `size` pixels covering a region of an image, the last ones of each
+// row and column being cut at its edges
+pub fn tiles(region: Tile, size: usize, order: TileOrder) -> Vec<Tile> {
+    let columns = region.width().div_ceil(size);
+    let rows = region.height().div_ceil(size);
+    let grid: Vec<(usize, usize)> = match order {
+        TileOrder::Scanline => (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .collect(),
+        TileOrder::Spiral => spiral(columns, rows),
+        TileOrder::Hilbert => hilbert(columns, rows),
+    };
+    grid.into_iter()
+        .map(|(column, row)| Tile {
+            x0: region.x0 + column * size,
+            y0: region.y0 + row * size,
+            x1: (region.x0 + (column + 1) * size).min(region.x1),
+            y1: (region.y0 + (row + 1) * size).min(region.y1),
+        })
+        .collect()
+}
+
+// Tiles by square rings around the central one, each ring going clockwise from
+// its top left corner
+fn spiral(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let (centre_column, centre_row) = ((columns as i64 - 1) / 2, (rows as i64 - 1) / 2);
+    let mut grid: Vec<(usize, usize)> = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .collect();
+    grid.sort_by_key(|&(column, row)| {
+        let (dx, dy) = (column as i64 - centre_column, row as i64 - centre_row);
+        let ring = dx.abs().max(dy.abs());
+        // Position along the ring : top side, right side, bottom side, left side
+        let position = if dy == -ring {
+            dx + ring
+        } else if dx == ring {
+            2 * ring + dy + ring
+        } else if dy == ring {
+            4 * ring + ring - dx
+        } else {
+            6 * ring + ring - dy
+        };
+        (ring, position)
+    });
+    grid
+}
+
+// Tiles along the Hilbert curve of the smallest power of two square holding the
+// grid, skipping the cells outside of it
+fn hilbert(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let n = columns.max(rows).next_power_of_two();
+    (0..n * n)
+        .map(|d| hilbert_cell(n, d))
+        .filter(|&(column, row)| column < columns && row < rows)
+        .collect()
+}
+
+// Cell at distance d along the Hilbert curve of an n by n square
+fn hilbert_cell(n: usize, d: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = d;
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [(usize, usize, usize); 4] =
+        [(100, 67, 16), (40, 200, 32), (7, 5, 8), (64, 64, 8)];
+
+    fn image(width: usize, height: usize) -> Tile {
+        Tile {
+            x0: 0,
+            y0: 0,
+            x1: width,
+            y1: height,
+        }
+    }
+
+    // Whatever their order, the tiles cover each pixel once
+    #[test]
+    fn tiles_cover_the_image_once() {
+        for &order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert].iter() {
+            for &(width, height, size) in SIZES.iter() {
+                let mut covered = vec![0; width * height];
+                for tile in tiles(image(width, height), size, order) {
+                    for y in tile.y0..tile.y1 {
+                        for x in tile.x0..tile.x1 {
+                            covered[y * width + x] += 1;
+                        }
+                    }
+                }
+                assert!(
+                    covered.iter().all(|&count| count == 1),
+                    "{:?} tiles of {} pixels do not cover a {}x{} image once",
+                    order,
+                    size,
+                    width,
+                    height
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_spiral_starts_at_the_centre() {
+        for &(width, height, size) in SIZES.iter() {
+            let first = tiles(image(width, height), size, TileOrder::Spiral)[0];
+            assert!(
+                first.x0 <= width / 2
+                    && first.y0 <= height / 2
+                    && first.x1 + size > width / 2
+                    && first.y1 + size > height / 2,
+                "the spiral starts at {:?}",
+                first
+            );
+        }
+    }
+}
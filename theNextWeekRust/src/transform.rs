@@ -0,0 +1,328 @@
+use std::sync::Arc;
+
+use crate::animation::Animation;
+use crate::bvh::Aabb;
+use crate::sampler::Sampler;
+use crate::{HitRecord, Hitable, HitableObject, Ray, Vec3};
+
+// Affine transform as a 4x4 matrix applied to column vectors, its last row
+// being 0 0 0 1
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Matrix4 {
+    pub m: [[f32; 4]; 4],
+}
+
+impl Matrix4 {
+    pub fn identity() -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        Matrix4 { m }
+    }
+
+    pub fn translation(offset: Vec3) -> Matrix4 {
+        let mut matrix = Matrix4::identity();
+        matrix.m[0][3] = offset.x;
+        matrix.m[1][3] = offset.y;
+        matrix.m[2][3] = offset.z;
+        matrix
+    }
+
+    pub fn scaling(factors: Vec3) -> Matrix4 {
+        let mut matrix = Matrix4::identity();
+        matrix.m[0][0] = factors.x;
+        matrix.m[1][1] = factors.y;
+        matrix.m[2][2] = factors.z;
+        matrix
+    }
+
+    // Counterclockwise rotation of `degrees` around `axis` when looking down the axis
+    // towards the origin (Rodrigues' formula)
+    pub fn rotation(axis: Vec3, degrees: f32) -> Matrix4 {
+        let a = axis.unit_vector();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let t = 1.0 - cos;
+        let mut matrix = Matrix4::identity();
+        matrix.m[0][0] = t * a.x * a.x + cos;
+        matrix.m[0][1] = t * a.x * a.y - sin * a.z;
+        matrix.m[0][2] = t * a.x * a.z + sin * a.y;
+        matrix.m[1][0] = t * a.x * a.y + sin * a.z;
+        matrix.m[1][1] = t * a.y * a.y + cos;
+        matrix.m[1][2] = t * a.y * a.z - sin * a.x;
+        matrix.m[2][0] = t * a.x * a.z - sin * a.y;
+        matrix.m[2][1] = t * a.y * a.z + sin * a.x;
+        matrix.m[2][2] = t * a.z * a.z + cos;
+        matrix
+    }
+
+    // self * other, which applies `other` first
+    pub fn mul(&self, other: &Matrix4) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[i][k] * other.m[k][j]).sum();
+            }
+        }
+        Matrix4 { m }
+    }
+
+    pub fn is_affine(&self) -> bool {
+        self.m[3] == [0.0, 0.0, 0.0, 1.0]
+    }
+
+    // Gauss-Jordan elimination with partial pivoting, in double precision so that
+    // long chains of transforms keep their accuracy. None for singular matrices
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = [[0.0f64; 8]; 4];
+        for (i, row) in a.iter_mut().enumerate() {
+            for (value, &m) in row.iter_mut().zip(self.m[i].iter()) {
+                *value = f64::from(m);
+            }
+            row[4 + i] = 1.0;
+        }
+        for column in 0..4 {
+            let pivot = (column..4)
+                .max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))
+                .unwrap();
+            if a[pivot][column].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(column, pivot);
+            let scale = 1.0 / a[column][column];
+            for value in a[column].iter_mut() {
+                *value *= scale;
+            }
+            for row in 0..4 {
+                if row != column {
+                    let factor = a[row][column];
+                    let pivot_row = a[column];
+                    for (value, pivot_value) in a[row].iter_mut().zip(pivot_row.iter()) {
+                        *value -= factor * pivot_value;
+                    }
+                }
+            }
+        }
+        let mut m = [[0.0; 4]; 4];
+        for (row, inverse_row) in m.iter_mut().zip(a.iter()) {
+            for (value, &inverse) in row.iter_mut().zip(inverse_row[4..].iter()) {
+                *value = inverse as f32;
+            }
+        }
+        Some(Matrix4 { m })
+    }
+
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        self.transform_vector(p)
+            + Vec3 {
+                x: self.m[0][3],
+                y: self.m[1][3],
+                z: self.m[2][3],
+            }
+    }
+
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3 {
+            x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        }
+    }
+
+    // Product with the transpose of the matrix, which moves normals when applied
+    // with the inverse of the transform of the points
+    fn transpose_transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3 {
+            x: m[0][0] * v.x + m[1][0] * v.y + m[2][0] * v.z,
+            y: m[0][1] * v.x + m[1][1] * v.y + m[2][1] * v.z,
+            z: m[0][2] * v.x + m[1][2] * v.y + m[2][2] * v.z,
+        }
+    }
+}
+
+// Object moved by an optional animation, then placed in the world by an affine
+// transform. The object is shared, so the same mesh or box can be instanced many
+// times without copying it
+pub struct Instance {
+    pub object: Arc<HitableObject>,
+    // Object space to world space, and back
+    pub transform: Matrix4,
+    pub inverse: Matrix4,
+    pub animation: Option<Animation>,
+}
+
+impl Instance {
+    pub fn new(object: Arc<HitableObject>, transform: Matrix4) -> Result<Instance, String> {
+        if !transform.is_affine() {
+            return Err("the last row of a transform must be [0, 0, 0, 1]".to_string());
+        }
+        let inverse = transform
+            .inverse()
+            .ok_or_else(|| "the transform is not invertible".to_string())?;
+        Ok(Instance {
+            object,
+            transform,
+            inverse,
+            animation: None,
+        })
+    }
+
+    // Object space to world space and back at `time`
+    fn matrices(&self, time: f32) -> (Matrix4, Matrix4) {
+        match &self.animation {
+            Some(animation) => (
+                self.transform.mul(&animation.matrix(time)),
+                animation.inverse_matrix(time).mul(&self.inverse),
+            ),
+            None => (self.transform, self.inverse),
+        }
+    }
+
+    // Transformed corners of the box of the object at `time`
+    fn corners(&self, time: f32) -> [Vec3; 8] {
+        let (transform, _) = self.matrices(time);
+        let Aabb { min, max } = self.object.bounding_box();
+        let mut corners = [min; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            *corner = transform.transform_point(Vec3 {
+                x: if i & 1 == 0 { min.x } else { max.x },
+                y: if i & 2 == 0 { min.y } else { max.y },
+                z: if i & 4 == 0 { min.z } else { max.z },
+            });
+        }
+        corners
+    }
+
+    // Box of the object at every time between time0 and time1
+    pub fn bounding_box_between(&self, time0: f32, time1: f32) -> Aabb {
+        let mut times = vec![time0];
+        if let Some(animation) = &self.animation {
+            times.extend(
+                animation
+                    .key_times()
+                    .into_iter()
+                    .filter(|&t| t > time0 && t < time1),
+            );
+        }
+        if time1 > time0 {
+            times.push(time1);
+        }
+        self.swept_box(&times)
+    }
+
+    // Box around the transformed corners of the object box. Animated corners are
+    // followed in small steps between the given times, and the box is padded by
+    // the longest step so that the paths between the steps stay inside it
+    fn swept_box(&self, times: &[f32]) -> Aabb {
+        let mut steps = vec![times.first().cloned().unwrap_or(0.0)];
+        for pair in times.windows(2) {
+            steps.extend(
+                (1..=BOX_STEPS)
+                    .map(|step| pair[0] + (pair[1] - pair[0]) * step as f32 / BOX_STEPS as f32),
+            );
+        }
+        let mut padding: f32 = 0.0;
+        let mut previous: Option<[Vec3; 8]> = None;
+        let mut bounds: Option<Aabb> = None;
+        for time in steps {
+            let corners = self.corners(time);
+            for (i, &corner) in corners.iter().enumerate() {
+                if let Some(previous) = &previous {
+                    padding = padding.max((corner - previous[i]).length());
+                }
+                let point = Aabb {
+                    min: corner,
+                    max: corner,
+                };
+                bounds = Some(match bounds {
+                    Some(bounds) => Aabb::surrounding_box(bounds, point),
+                    None => point,
+                });
+            }
+            previous = Some(corners);
+        }
+        let Aabb { min, max } = bounds.unwrap();
+        let pad = Vec3 {
+            x: padding,
+            y: padding,
+            z: padding,
+        };
+        Aabb {
+            min: min - pad,
+            max: max + pad,
+        }
+    }
+}
+
+// Steps between two times when looking for the box of an animated instance
+const BOX_STEPS: usize = 16;
+
+impl Hitable for Instance {
+    // The ray direction is not normalized in object space, so the distances along
+    // the ray are the same in both spaces
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32, sampler: &mut Sampler) -> Option<HitRecord<'_>> {
+        let (transform, inverse) = self.matrices(r.time);
+        let object_ray = Ray {
+            origin: inverse.transform_point(r.origin),
+            direction: inverse.transform_vector(r.direction),
+            time: r.time,
+        };
+        let mut rec = self.object.hit(&object_ray, t_min, t_max, sampler)?;
+        rec.p = transform.transform_point(rec.p);
+        // The sign of the dot product with the ray direction is kept, and so is front_face
+        rec.normal = inverse.transpose_transform_vector(rec.normal).unit_vector();
+        Some(rec)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let keys = match &self.animation {
+            Some(animation) => animation.key_times(),
+            None => vec![],
+        };
+        self.swept_box(&keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_identity(matrix: &Matrix4) {
+        for (i, row) in matrix.m.iter().enumerate() {
+            for (j, &value) in row.iter().enumerate() {
+                let expected = if i == j { 1.0 } else { 0.0 };
+                assert!((value - expected).abs() < 1e-5, "{:?}", matrix);
+            }
+        }
+    }
+
+    #[test]
+    fn matrices_times_their_inverse_give_the_identity() {
+        let vec3 = |x, y, z| Vec3 { x, y, z };
+        let matrices = [
+            Matrix4::identity(),
+            Matrix4::translation(vec3(3.0, -20.0, 0.5)),
+            Matrix4::scaling(vec3(0.01, 4.0, -2.0)),
+            Matrix4::rotation(vec3(1.0, 2.0, -0.5), 37.0),
+            Matrix4::translation(vec3(265.0, 0.0, 295.0))
+                .mul(&Matrix4::rotation(vec3(0.0, 1.0, 0.0), 15.0))
+                .mul(&Matrix4::scaling(vec3(165.0, 330.0, 165.0))),
+            Matrix4 {
+                m: [
+                    [1.0, 0.5, 0.0, 2.0],
+                    [0.0, 1.0, 0.0, 0.0],
+                    [0.3, 0.0, 2.0, -1.0],
+                    [0.0, 0.0, 0.0, 1.0],
+                ],
+            },
+        ];
+        for matrix in matrices.iter() {
+            let inverse = matrix.inverse().unwrap();
+            assert_identity(&matrix.mul(&inverse));
+            assert_identity(&inverse.mul(matrix));
+        }
+        assert_eq!(Matrix4::scaling(vec3(1.0, 0.0, 1.0)).inverse(), None);
+    }
+}
@@ -0,0 +1,295 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::bvh::Aabb;
+use crate::{checkpoint, Vec3};
+
+// Type of the values of a raw grid file
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawEncoding {
+    // Little endian 32 bit floats
+    F32,
+    // Bytes, mapped to [0, 1]
+    U8,
+}
+
+// Dense grid of densities, x varying the fastest and z the slowest
+#[derive(Debug)]
+pub struct VoxelGrid {
+    pub resolution: [usize; 3],
+    densities: Vec<f32>,
+    // Box of the grid given by the file, if any
+    pub bounds: Option<Aabb>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn read_f32(data: &[u8], offset: usize) -> f32 {
+    f32::from_bits(read_u32(data, offset))
+}
+
+fn decode(data: &[u8], encoding: RawEncoding, count: usize, stride: usize) -> Vec<f32> {
+    match encoding {
+        RawEncoding::F32 => (0..count).map(|i| read_f32(data, 4 * i * stride)).collect(),
+        RawEncoding::U8 => (0..count)
+            .map(|i| f32::from(data[i * stride]) / 255.0)
+            .collect(),
+    }
+}
+
+impl VoxelGrid {
+    // Headerless file of `resolution` values
+    pub fn load_raw(
+        path: &Path,
+        resolution: [usize; 3],
+        encoding: RawEncoding,
+    ) -> Result<VoxelGrid, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        checkpoint::record_asset(&data);
+        let count = resolution.iter().product::<usize>();
+        let size = match encoding {
+            RawEncoding::F32 => 4,
+            RawEncoding::U8 => 1,
+        };
+        if count == 0 || data.len() != count * size {
+            return Err(format!(
+                "{}: expected {} bytes for a {}x{}x{} grid, found {}",
+                path.display(),
+                count * size,
+                resolution[0],
+                resolution[1],
+                resolution[2],
+                data.len()
+            ));
+        }
+        VoxelGrid::new(resolution, decode(&data, encoding, count, 1), None)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // Mitsuba grid volume : "VOL" and version 3, then little endian the encoding
+    // (1 for floats, 3 for bytes), the x, y and z resolutions, the number of
+    // channels, the box as minimum and maximum coordinates, and the values. Only
+    // the first channel is used as the density
+    pub fn load_vol(path: &Path) -> Result<VoxelGrid, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        checkpoint::record_asset(&data);
+        VoxelGrid::parse_vol(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse_vol(data: &[u8]) -> Result<VoxelGrid, String> {
+        const HEADER_SIZE: usize = 48;
+        if data.len() < HEADER_SIZE || &data[..3] != b"VOL" {
+            return Err("not a VOL grid file".to_string());
+        }
+        if data[3] != 3 {
+            return Err(format!("unsupported VOL version {}", data[3]));
+        }
+        let encoding = match read_u32(data, 4) {
+            1 => RawEncoding::F32,
+            3 => RawEncoding::U8,
+            other => return Err(format!("unsupported VOL encoding {}", other)),
+        };
+        let resolution = [
+            read_u32(data, 8) as usize,
+            read_u32(data, 12) as usize,
+            read_u32(data, 16) as usize,
+        ];
+        let channels = read_u32(data, 20) as usize;
+        let corner = |offset| Vec3 {
+            x: read_f32(data, offset),
+            y: read_f32(data, offset + 4),
+            z: read_f32(data, offset + 8),
+        };
+        let bounds = Aabb {
+            min: corner(24),
+            max: corner(36),
+        };
+        let count = resolution.iter().product::<usize>();
+        let size = match encoding {
+            RawEncoding::F32 => 4,
+            RawEncoding::U8 => 1,
+        };
+        if count == 0 || channels == 0 || data.len() < HEADER_SIZE + count * channels * size {
+            return Err("truncated VOL data".to_string());
+        }
+        let densities = decode(&data[HEADER_SIZE..], encoding, count, channels);
+        VoxelGrid::new(resolution, densities, Some(bounds))
+    }
+
+    pub fn new(
+        resolution: [usize; 3],
+        densities: Vec<f32>,
+        bounds: Option<Aabb>,
+    ) -> Result<VoxelGrid, String> {
+        if densities.iter().any(|d| !(*d >= 0.0 && d.is_finite())) {
+            return Err("densities must be positive and finite".to_string());
+        }
+        Ok(VoxelGrid {
+            resolution,
+            densities,
+            bounds,
+        })
+    }
+
+    pub fn max_density(&self) -> f32 {
+        self.densities.iter().cloned().fold(0.0, f32::max)
+    }
+
+    fn voxel(&self, i: usize, j: usize, k: usize) -> f32 {
+        let [nx, ny, _] = self.resolution;
+        self.densities[(k * ny + j) * nx + i]
+    }
+
+    // Trilinear interpolation between the voxel centers, at a point given as
+    // fractions of the grid sides. The border voxels extend to the sides
+    pub fn density(&self, p: Vec3) -> f32 {
+        let mut index = [0; 3];
+        let mut weight = [0.0; 3];
+        for (axis, &fraction) in [p.x, p.y, p.z].iter().enumerate() {
+            let n = self.resolution[axis];
+            let x = (fraction * n as f32 - 0.5).max(0.0).min((n - 1) as f32);
+            let i = (x as usize).min(n.saturating_sub(2));
+            index[axis] = i;
+            weight[axis] = if n > 1 { x - i as f32 } else { 0.0 };
+        }
+        let mut density = 0.0;
+        for corner in 0..8 {
+            let mut w = 1.0;
+            let mut at = [0; 3];
+            for axis in 0..3 {
+                let upper = (corner >> axis) & 1;
+                w *= if upper == 1 {
+                    weight[axis]
+                } else {
+                    1.0 - weight[axis]
+                };
+                at[axis] = (index[axis] + upper).min(self.resolution[axis] - 1);
+            }
+            if w > 0.0 {
+                density += w * self.voxel(at[0], at[1], at[2]);
+            }
+        }
+        density
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_file(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ray_tracer_test_{}_{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    // Header of a VOL file of one channel over the unit box
+    fn vol_header(encoding: u32, resolution: [u32; 3], channels: u32) -> Vec<u8> {
+        let mut data = b"VOL\x03".to_vec();
+        for value in [
+            encoding,
+            resolution[0],
+            resolution[1],
+            resolution[2],
+            channels,
+        ]
+        .iter()
+        {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [0.0f32, 0.0, 0.0, 1.0, 2.0, 3.0].iter() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn raw_grids_are_read_in_both_encodings() {
+        let floats: Vec<u8> = [0.0f32, 0.5, 1.0, 2.0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes().to_vec())
+            .collect();
+        let path = temporary_file("grid.f32", &floats);
+        let grid = VoxelGrid::load_raw(&path, [2, 2, 1], RawEncoding::F32);
+        fs::remove_file(&path).unwrap();
+        let grid = grid.unwrap();
+        assert_eq!(grid.densities, vec![0.0, 0.5, 1.0, 2.0]);
+        assert!(grid.bounds.is_none());
+        assert_eq!(grid.max_density(), 2.0);
+
+        let path = temporary_file("grid.u8", &[0, 51, 255]);
+        let grid = VoxelGrid::load_raw(&path, [3, 1, 1], RawEncoding::U8);
+        let wrong_size = VoxelGrid::load_raw(&path, [2, 2, 1], RawEncoding::U8);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(grid.unwrap().densities, vec![0.0, 0.2, 1.0]);
+        assert!(wrong_size.unwrap_err().contains("expected 4 bytes"));
+    }
+
+    #[test]
+    fn vol_grids_keep_their_first_channel_and_their_box() {
+        let mut data = vol_header(3, [2, 1, 1], 2);
+        data.extend_from_slice(&[255, 7, 51, 7]);
+        let grid = VoxelGrid::parse_vol(&data).unwrap();
+        assert_eq!(grid.resolution, [2, 1, 1]);
+        assert_eq!(grid.densities, vec![1.0, 0.2]);
+        let bounds = grid.bounds.unwrap();
+        assert_eq!([bounds.max.x, bounds.max.y, bounds.max.z], [1.0, 2.0, 3.0]);
+
+        let mut data = vol_header(1, [1, 1, 1], 1);
+        data.extend_from_slice(&0.25f32.to_le_bytes());
+        assert_eq!(VoxelGrid::parse_vol(&data).unwrap().densities, vec![0.25]);
+    }
+
+    #[test]
+    fn bad_vol_grids_are_refused() {
+        let mut data = vol_header(1, [2, 2, 2], 1);
+        data.extend_from_slice(&1.0f32.to_le_bytes());
+        assert_eq!(
+            VoxelGrid::parse_vol(&data).unwrap_err(),
+            "truncated VOL data"
+        );
+        let mut data = vol_header(2, [1, 1, 1], 1);
+        data.extend_from_slice(&[0; 4]);
+        assert!(VoxelGrid::parse_vol(&data)
+            .unwrap_err()
+            .contains("encoding 2"));
+        assert!(VoxelGrid::parse_vol(b"NOT A GRID").is_err());
+        let mut data = vol_header(1, [1, 1, 1], 1);
+        data.extend_from_slice(&(-1.0f32).to_le_bytes());
+        assert!(VoxelGrid::parse_vol(&data).is_err());
+    }
+
+    fn at(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    #[test]
+    fn densities_are_interpolated_between_the_voxel_centers() {
+        // A linear function of the voxel indices is interpolated exactly
+        let densities = (0..8)
+            .map(|i| (i & 1) as f32 + 2.0 * ((i >> 1) & 1) as f32 + 4.0 * (i >> 2) as f32)
+            .collect();
+        let grid = VoxelGrid::new([2, 2, 2], densities, None).unwrap();
+        assert_eq!(grid.density(at(0.25, 0.25, 0.25)), 0.0);
+        assert_eq!(grid.density(at(0.75, 0.75, 0.75)), 7.0);
+        assert!((grid.density(at(0.5, 0.5, 0.5)) - 3.5).abs() < 1e-6);
+        assert!((grid.density(at(0.5, 0.25, 0.75)) - 4.5).abs() < 1e-6);
+        // The border voxels extend to the sides and beyond
+        assert_eq!(grid.density(at(0.0, 0.0, 0.0)), 0.0);
+        assert_eq!(grid.density(at(1.5, 1.0, 1.0)), 7.0);
+
+        let single = VoxelGrid::new([1, 1, 1], vec![0.5], None).unwrap();
+        assert_eq!(single.density(at(0.1, 0.9, 0.5)), 0.5);
+    }
+}